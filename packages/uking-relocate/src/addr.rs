@@ -0,0 +1,54 @@
+use uking_relocate::address::{parse_address, AddressMap};
+use uking_relocate::memory::perm_str;
+use uking_relocate::ModuleData;
+
+use crate::cli::AddrArgs;
+
+/// Translate the addresses and print them in all the address spaces
+pub fn run(args: &AddrArgs) -> anyhow::Result<()> {
+    if let Some(start) = args.start {
+        uking_relocate::check_start(start)?;
+    }
+    let map = match (&args.sdk, args.game_version) {
+        (Some(sdk), _) => AddressMap::from_data(&ModuleData::load(sdk, &args.module, false)?)?,
+        (None, Some(version)) => AddressMap::from_layout(version.layout()),
+        (None, None) => unreachable!("--sdk or --game-version is required"),
+    };
+    for (i, address) in args.addresses.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        let translation = map.translate(&parse_address(address)?, args.start)?;
+        println!("{address}");
+        println!("module:   {}", translation.module);
+        println!(
+            "offset:   [{}]:0x{:08x}",
+            translation.module, translation.module_offset
+        );
+        println!("program:  0x{:08x}", translation.program_offset);
+        if let Some(decomp) = translation.decomp {
+            println!("decomp:   0x{decomp:016x}");
+        }
+        if let Some(physical) = translation.physical {
+            println!("physical: 0x{physical:016x}");
+        }
+        if let Some(segment) = &translation.segment {
+            let permissions = match segment.permissions {
+                Some(permissions) => perm_str(permissions),
+                None => "??? (needs --sdk)".to_string(),
+            };
+            println!(
+                "segment:  {} 0x{:08x}-0x{:08x} {permissions}",
+                segment.module, segment.start, segment.end
+            );
+        }
+        if args.symbol {
+            match &translation.symbol {
+                Some((name, 0)) => println!("symbol:   {name}"),
+                Some((name, offset)) => println!("symbol:   {name}+0x{offset:x}"),
+                None => println!("symbol:   (none)"),
+            }
+        }
+    }
+    Ok(())
+}
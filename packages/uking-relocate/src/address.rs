@@ -0,0 +1,344 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail};
+use elf::abi::{PF_R, PF_X};
+
+use crate::decomp::{to_main_offset, DECOMP_MAIN_START};
+use crate::define::{self, DefineValue};
+use crate::memory::align_up;
+use crate::module::{ModuleData, ModuleType, Modules};
+use crate::parse_u64;
+
+/// An address to translate
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddressArg {
+    /// Address in the decomp and IDA, where main starts at 0x7100000000
+    Decomp(u64),
+    /// Offset relative to the start of a module
+    Module(ModuleType, u32),
+    /// Absolute physical address, the program is loaded at --start
+    Physical(u64),
+    /// A number without a prefix, which is a decomp or physical address
+    /// depending on which range it's in
+    Auto(u64),
+    /// A dynamic symbol, plus an offset
+    Symbol(String, u64),
+}
+
+/// Parse an address to translate, which can be:
+/// - decomp:ADDR or phys:ADDR, for a decomp or physical address
+/// - [module]:OFFSET, for an offset relative to a module
+/// - ADDR, which is detected as a decomp or physical address
+/// - SYMBOL with an optional +OFFSET
+pub fn parse_address(arg: &str) -> anyhow::Result<AddressArg> {
+    let arg = arg.trim();
+    if let Some(address) = arg.strip_prefix("decomp:") {
+        return Ok(AddressArg::Decomp(parse_u64(address.trim())?));
+    }
+    if let Some(address) = arg.strip_prefix("phys:") {
+        return Ok(AddressArg::Physical(parse_u64(address.trim())?));
+    }
+    Ok(match define::parse_value(arg)? {
+        DefineValue::Absolute(address) => AddressArg::Auto(address),
+        DefineValue::Module(module, offset) => AddressArg::Module(module, offset),
+        DefineValue::Symbol(name, offset) => AddressArg::Symbol(name, offset),
+    })
+}
+
+/// A loaded segment of a module
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentInfo {
+    pub module: ModuleType,
+    /// Start of the segment relative to the program start
+    pub start: u32,
+    /// End of the segment relative to the program start (page aligned)
+    pub end: u32,
+    /// Permission flags (PF_R, PF_W, PF_X), None if unknown
+    pub permissions: Option<u32>,
+}
+
+/// Layout of the modules, for translating addresses between the address spaces
+pub struct AddressMap {
+    pub modules: Modules,
+    /// The segments in the order they are loaded
+    pub segments: Vec<SegmentInfo>,
+    /// Dynamic symbols by offset relative to the program start,
+    /// the first name in load order is kept for each offset
+    symbols: BTreeMap<u32, String>,
+    /// Offset of the dynamic symbols by name, relative to the program start
+    symbol_offsets: BTreeMap<String, u32>,
+}
+
+/// An address in all the address spaces
+#[derive(Debug, Clone, PartialEq)]
+pub struct Translation {
+    pub module: ModuleType,
+    /// Offset relative to the module start
+    pub module_offset: u32,
+    /// Offset relative to the program start
+    pub program_offset: u32,
+    /// Address in the decomp, if the address is in main
+    pub decomp: Option<u64>,
+    /// Physical address, if the start is known
+    pub physical: Option<u64>,
+    pub segment: Option<SegmentInfo>,
+    /// The nearest dynamic symbol at or before the address in the same module, and the offset from it
+    pub symbol: Option<(String, u32)>,
+}
+
+impl AddressMap {
+    /// Use a known layout (see [`KnownVersion::layout`](crate::build_id::KnownVersion::layout)).
+    ///
+    /// Only the .text segment is known, and there are no symbols
+    pub fn from_layout(modules: Modules) -> Self {
+        let mut segments = Vec::new();
+        for (module, info) in modules.iter() {
+            segments.push(SegmentInfo {
+                module,
+                start: info.start,
+                end: info.text_end,
+                permissions: Some(PF_R | PF_X),
+            });
+            if info.text_end < info.end {
+                segments.push(SegmentInfo {
+                    module,
+                    start: info.text_end,
+                    end: info.end,
+                    permissions: None,
+                });
+            }
+        }
+        Self {
+            modules,
+            segments,
+            symbols: BTreeMap::new(),
+            symbol_offsets: BTreeMap::new(),
+        }
+    }
+
+    /// Use the layout, segments and dynamic symbols of the loaded modules
+    pub fn from_data(data: &ModuleData) -> anyhow::Result<Self> {
+        let mut segments = Vec::new();
+        let mut symbols = BTreeMap::new();
+        let mut symbol_offsets = BTreeMap::new();
+        for (module, binary) in data.iter_binaries() {
+            let info = data.info.get(module);
+            for segment in binary.segments() {
+                segments.push(SegmentInfo {
+                    module,
+                    start: info.start + segment.vaddr,
+                    end: info.start + align_up!(segment.vaddr + segment.mem_size),
+                    permissions: Some(segment.permissions),
+                });
+            }
+            let mut table = BTreeMap::new();
+            binary.load_dynamic_symbols(module, info.start as u64, &mut table)?;
+            for (name, value) in table {
+                let offset = value.address as u32;
+                symbols.entry(offset).or_insert_with(|| name.clone());
+                symbol_offsets.entry(name).or_insert(offset);
+            }
+        }
+        Ok(Self {
+            modules: data.info.clone(),
+            segments,
+            symbols,
+            symbol_offsets,
+        })
+    }
+
+    /// Translate the address. start is the physical start of the program, if known
+    pub fn translate(&self, arg: &AddressArg, start: Option<u64>) -> anyhow::Result<Translation> {
+        let program_offset = match arg {
+            AddressArg::Decomp(address) => self.decomp_offset(*address)?,
+            AddressArg::Module(module, offset) => self.module_offset(*module, *offset)?,
+            AddressArg::Physical(address) => self.physical_offset(*address, start)?,
+            AddressArg::Auto(address) => {
+                let decomp = self.decomp_offset(*address).ok();
+                let physical = self.physical_offset(*address, start).ok();
+                match (decomp, physical) {
+                    (Some(_), Some(_)) => bail!(
+                        "0x{address:x} can be a decomp or physical address, use decomp:ADDR or phys:ADDR"
+                    ),
+                    (Some(offset), None) | (None, Some(offset)) => offset,
+                    (None, None) if start.is_none() => bail!(
+                        "0x{address:x} is not a decomp address, specify --start for physical addresses"
+                    ),
+                    (None, None) => {
+                        bail!("0x{address:x} is not a decomp address or in the program")
+                    }
+                }
+            }
+            AddressArg::Symbol(name, offset) => {
+                let symbol = self.symbol_offsets.get(name).ok_or_else(|| {
+                    anyhow!("cannot find symbol {name} (symbols need the modules)")
+                })?;
+                u32::try_from(*symbol as u64 + offset)
+                    .map_err(|_| anyhow!("{name}+0x{offset:x} is outside of the program"))?
+            }
+        };
+        let (module, info) = self
+            .modules
+            .iter()
+            .find(|(_, info)| info.start <= program_offset && program_offset < info.end)
+            .ok_or_else(|| anyhow!("0x{program_offset:08x} is outside of the program"))?;
+        let segment = self
+            .segments
+            .iter()
+            .find(|s| s.start <= program_offset && program_offset < s.end)
+            .cloned();
+        let symbol = self
+            .symbols
+            .range(info.start..=program_offset)
+            .next_back()
+            .map(|(offset, name)| (name.clone(), program_offset - offset));
+        Ok(Translation {
+            module,
+            module_offset: program_offset - info.start,
+            program_offset,
+            decomp: (module == ModuleType::Main)
+                .then(|| DECOMP_MAIN_START + (program_offset - info.start) as u64),
+            physical: start.map(|start| start + program_offset as u64),
+            segment,
+            symbol,
+        })
+    }
+
+    fn decomp_offset(&self, address: u64) -> anyhow::Result<u32> {
+        let offset = to_main_offset(address)?;
+        self.module_offset(ModuleType::Main, offset)
+    }
+
+    fn module_offset(&self, module: ModuleType, offset: u32) -> anyhow::Result<u32> {
+        if !self.modules.contains(module) {
+            bail!("{module} is not loaded");
+        }
+        let info = self.modules.get(module);
+        if offset >= info.end - info.start {
+            bail!("[{module}]:0x{offset:x} is outside of {module}");
+        }
+        Ok(self.modules.to_program_offset(module, offset))
+    }
+
+    fn physical_offset(&self, address: u64, start: Option<u64>) -> anyhow::Result<u32> {
+        let start = start.ok_or_else(|| anyhow!("physical addresses need --start"))?;
+        let end = start + self.modules.sdk.end as u64;
+        if address < start || address >= end {
+            bail!("0x{address:x} is outside of the program at 0x{start:x}");
+        }
+        Ok((address - start) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use elf::abi::PF_W;
+
+    use super::*;
+    use crate::test_util::{module_data, test_modules, START};
+
+    #[test]
+    fn parse() {
+        let parse = |arg| parse_address(arg).unwrap();
+        assert_eq!(
+            parse("decomp:0x7100001000"),
+            AddressArg::Decomp(0x7100001000)
+        );
+        assert_eq!(parse("phys:1234500000"), AddressArg::Physical(0x1234500000));
+        assert_eq!(
+            parse("[main]:0x10"),
+            AddressArg::Module(ModuleType::Main, 0x10)
+        );
+        assert_eq!(parse(" 0x7100000010 "), AddressArg::Auto(0x7100000010));
+        assert_eq!(
+            parse("nnMain+0x4"),
+            AddressArg::Symbol("nnMain".to_string(), 4)
+        );
+        assert!(parse_address("[foo]:0x10").is_err());
+        assert!(parse_address("decomp:xyz").is_err());
+    }
+
+    #[test]
+    fn translate_symbol() {
+        let map = AddressMap::from_data(&module_data()).unwrap();
+        let main = map.modules.get(ModuleType::Main);
+        let offset = test_modules()[1].export_offset("main_fn") + 4;
+        let arg = AddressArg::Symbol("main_fn".to_string(), 4);
+        let translation = map.translate(&arg, Some(START)).unwrap();
+        assert_eq!(translation.module, ModuleType::Main);
+        assert_eq!(translation.module_offset, offset);
+        assert_eq!(translation.program_offset, main.start + offset);
+        assert_eq!(translation.decomp, Some(DECOMP_MAIN_START + offset as u64));
+        assert_eq!(
+            translation.physical,
+            Some(START + (main.start + offset) as u64)
+        );
+        assert_eq!(translation.symbol, Some(("main_fn".to_string(), 4)));
+        let segment = translation.segment.as_ref().unwrap();
+        assert_eq!(segment.start, main.start);
+        assert_eq!(segment.permissions, Some(PF_R | PF_X));
+
+        // the same address in the other address spaces
+        for arg in [
+            AddressArg::Decomp(DECOMP_MAIN_START + offset as u64),
+            AddressArg::Module(ModuleType::Main, offset),
+            AddressArg::Physical(START + (main.start + offset) as u64),
+            AddressArg::Auto(DECOMP_MAIN_START + offset as u64),
+            AddressArg::Auto(START + (main.start + offset) as u64),
+        ] {
+            assert_eq!(map.translate(&arg, Some(START)).unwrap(), translation);
+        }
+        // only main has decomp addresses
+        let arg = AddressArg::Symbol("nn_os_fn".to_string(), 0);
+        let translation = map.translate(&arg, None).unwrap();
+        assert_eq!(translation.module, ModuleType::Sdk);
+        assert_eq!(translation.decomp, None);
+        assert_eq!(translation.physical, None);
+    }
+
+    #[test]
+    fn translate_invalid() {
+        let map = AddressMap::from_data(&module_data()).unwrap();
+        let main = map.modules.get(ModuleType::Main);
+        let size = main.end - main.start;
+        let translate = |arg, start| map.translate(&arg, start);
+        assert!(translate(AddressArg::Module(ModuleType::Main, size), None).is_err());
+        assert!(translate(AddressArg::Module(ModuleType::Subsdk(1), 0), None).is_err());
+        assert!(translate(AddressArg::Physical(START), None).is_err());
+        assert!(translate(AddressArg::Physical(START - 1), Some(START)).is_err());
+        assert!(translate(AddressArg::Auto(START), None).is_err());
+        assert!(translate(AddressArg::Symbol("missing_fn".to_string(), 0), None).is_err());
+        // an address that is valid in both address spaces is ambiguous
+        let start = DECOMP_MAIN_START - main.start as u64;
+        assert!(translate(AddressArg::Auto(DECOMP_MAIN_START), Some(start)).is_err());
+        assert!(translate(AddressArg::Decomp(DECOMP_MAIN_START), Some(start)).is_ok());
+    }
+
+    #[test]
+    fn translate_with_layout() {
+        let data = module_data();
+        let map = AddressMap::from_layout(data.info.clone());
+        let main = map.modules.get(ModuleType::Main);
+        let arg = AddressArg::Module(ModuleType::Main, main.text_end - main.start);
+        let translation = map.translate(&arg, None).unwrap();
+        assert_eq!(translation.symbol, None);
+        // only .text is known
+        let segment = translation.segment.unwrap();
+        assert_eq!(segment.start, main.text_end);
+        assert_eq!(segment.permissions, None);
+        let arg = AddressArg::Module(ModuleType::Main, 0);
+        let segment = map.translate(&arg, None).unwrap().segment.unwrap();
+        assert_eq!(segment.permissions, Some(PF_R | PF_X));
+        assert!(map
+            .translate(&AddressArg::Symbol("nnMain".to_string(), 0), None)
+            .is_err());
+
+        let from_data = AddressMap::from_data(&data).unwrap();
+        let data_segment = from_data
+            .segments
+            .iter()
+            .find(|s| s.module == ModuleType::Main && s.permissions == Some(PF_R | PF_W))
+            .unwrap();
+        assert_eq!(data_segment.end, main.end);
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::BTreeSet;
+
+use anyhow::bail;
+
+/// Start of the region the loader picks the address from, in the 39-bit address space
+pub const ASLR_REGION_START: u64 = 0x0800_0000;
+/// End of the 39-bit address space
+pub const ASLR_REGION_END: u64 = 0x80_0000_0000;
+/// The loader aligns the randomized address to 2MB
+pub const ASLR_ALIGN: u64 = 0x20_0000;
+
+/// Picks random program starts, like the ASLR of the Horizon loader.
+///
+/// The same seed always gives the same starts. The starts are 2MB aligned
+/// and always pass [`check_start`](crate::check_start)
+#[derive(Debug, Clone)]
+pub struct RandomStart {
+    state: u64,
+}
+
+impl RandomStart {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Pick the next start for a program of size bytes, so the whole program
+    /// fits in the ASLR region
+    pub fn next_start(&mut self, size: u32) -> u64 {
+        let size = (size as u64).div_ceil(ASLR_ALIGN) * ASLR_ALIGN;
+        let slots = (ASLR_REGION_END - ASLR_REGION_START - size) / ASLR_ALIGN + 1;
+        ASLR_REGION_START + (self.next_u64() % slots) * ASLR_ALIGN
+    }
+
+    /// Pick count different starts for a program of size bytes
+    pub fn next_starts(&mut self, size: u32, count: usize) -> anyhow::Result<Vec<u64>> {
+        let mut starts = Vec::with_capacity(count);
+        let mut seen = BTreeSet::new();
+        // there are about 2^18 slots, so collisions are rare
+        let mut attempts = 0;
+        while starts.len() < count {
+            attempts += 1;
+            if attempts > count * 16 {
+                bail!("cannot pick {count} different starts");
+            }
+            let start = self.next_start(size);
+            if seen.insert(start) {
+                starts.push(start);
+            }
+        }
+        Ok(starts)
+    }
+
+    /// splitmix64
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_start_is_valid() {
+        let size = 0x0382_1000;
+        let mut random = RandomStart::new(12345);
+        for _ in 0..1000 {
+            let start = random.next_start(size);
+            assert_eq!(start % ASLR_ALIGN, 0);
+            assert!(start >= ASLR_REGION_START);
+            assert!(start + size as u64 <= ASLR_REGION_END);
+            crate::check_start(start).unwrap();
+        }
+    }
+
+    #[test]
+    fn next_start_is_deterministic() {
+        let starts = RandomStart::new(1).next_starts(0x1000, 10).unwrap();
+        assert_eq!(RandomStart::new(1).next_starts(0x1000, 10).unwrap(), starts);
+        assert_ne!(RandomStart::new(2).next_starts(0x1000, 10).unwrap(), starts);
+        let unique = starts.iter().collect::<BTreeSet<_>>();
+        assert_eq!(unique.len(), 10);
+    }
+
+    #[test]
+    fn next_start_fits_largest_program() {
+        let mut random = RandomStart::new(0);
+        for _ in 0..1000 {
+            let start = random.next_start(u32::MAX);
+            assert!(start + u32::MAX as u64 <= ASLR_REGION_END);
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use anyhow::{anyhow, bail};
+use elf::abi::{
+    PF_R, PF_W, PF_X, PT_DYNAMIC, PT_LOAD, STB_LOCAL, STB_WEAK, STV_HIDDEN, STV_INTERNAL,
+    STV_PROTECTED,
+};
+use elf::dynamic::DynamicTable;
+use elf::endian::LittleEndian;
+use elf::file::Class;
+use elf::parse::{ParsingIterator, ParsingTable};
+use elf::relocation::Rela;
+use elf::string_table::StringTable;
+use elf::symbol::SymbolTable;
+
+use crate::build_id::BuildId;
+use crate::dynamic::{decode_relr, DynamicInfo};
+use crate::elf::{ElfWrapper, SymbolValue};
+use crate::module::{ModuleFile, ModuleType};
+use crate::nso::{read_u32, Nso};
+
+pub type RelaTable<'data> = ParsingTable<'data, LittleEndian, Rela>;
+
+const SYM_SIZE: usize = 0x18;
+
+/// A module binary, parsed from either an ELF or an NSO,
+/// with the information needed to load and link it.
+///
+/// The binary owns the data of the module, and the segments and tables
+/// are located in it when parsing, so the module is only parsed once
+pub struct ModuleBinary {
+    /// The ELF file, or the decompressed NSO image
+    data: Vec<u8>,
+    /// The loadable segments, in the order of the virtual address
+    segments: Vec<SegmentRange>,
+    /// The GNU build ID of the ELF, or the module ID of the NSO, if any
+    pub build_id: Option<BuildId>,
+    /// If the module is linked with DT_SYMBOLIC (or DF_SYMBOLIC in DT_FLAGS),
+    /// meaning symbols are resolved in the module itself first
+    pub symbolic: bool,
+    /// DT_INIT, the virtual address of the function called before .init_array
+    pub init: Option<u32>,
+    /// DT_FINI, the virtual address of the function called after .fini_array
+    pub fini: Option<u32>,
+    /// Virtual address and size in bytes of .init_array (DT_INIT_ARRAY)
+    pub init_array: (u32, u32),
+    /// Virtual address and size in bytes of .fini_array (DT_FINI_ARRAY)
+    pub fini_array: (u32, u32),
+    symbols: Range<usize>,
+    strtab: Range<usize>,
+    rela_dyn: Range<usize>,
+    rela_plt: Range<usize>,
+    relr: Range<usize>,
+}
+
+/// A segment to be loaded into memory (i.e. PT_LOAD)
+pub struct LoadSegment<'data> {
+    /// Virtual address relative to the start of the module
+    pub vaddr: u32,
+    /// Initialized data of the segment
+    pub data: &'data [u8],
+    /// Size of the segment in memory, including uninitialized data
+    pub mem_size: u32,
+    /// Permission flags (PF_R, PF_W, PF_X)
+    pub permissions: u32,
+}
+
+/// A loadable segment, with the initialized data as a range in the module data
+struct SegmentRange {
+    vaddr: u32,
+    data: Range<usize>,
+    mem_size: u32,
+    permissions: u32,
+}
+
+impl ModuleBinary {
+    /// Parse the module file for loading
+    pub fn parse(file: ModuleFile) -> anyhow::Result<Self> {
+        match file {
+            ModuleFile::Elf(data) => Self::from_elf(data),
+            ModuleFile::Nso(nso) => Self::from_nso(nso),
+        }
+    }
+
+    /// Create the binary from an ELF converted from NSO
+    ///
+    /// Section headers are not used, since they might be stripped during conversion.
+    /// The dynamic section is located with PT_DYNAMIC, or the MOD0 header if
+    /// there is no PT_DYNAMIC
+    fn from_elf(data: Vec<u8>) -> anyhow::Result<Self> {
+        let elf = ElfWrapper::try_parse(&data)?;
+        let build_id = BuildId::from_elf(&elf)?;
+        let mut segments = Vec::new();
+        let mut dynamic = None;
+        for ph in elf.segments {
+            if ph.p_type == PT_DYNAMIC {
+                dynamic = Some(ph.p_vaddr as u32);
+            }
+            if ph.p_type != PT_LOAD {
+                continue;
+            }
+            if ph.p_vaddr != ph.p_paddr {
+                bail!("unexpected p_vaddr != p_paddr");
+            }
+            let start = ph.p_offset as usize;
+            let end = start + ph.p_filesz as usize;
+            if end > data.len() {
+                bail!("unexpected out of bounds segment at 0x{start:08x}");
+            }
+            segments.push(SegmentRange {
+                vaddr: ph.p_vaddr as u32,
+                data: start..end,
+                mem_size: ph.p_memsz as u32,
+                permissions: ph.p_flags,
+            });
+        }
+        let dynamic = match dynamic {
+            Some(dynamic) => dynamic,
+            None => find_dynamic_by_mod0(&data, &segments)?,
+        };
+        Self::link(data, segments, build_id, dynamic)
+    }
+
+    /// Create the binary from a decompressed NSO
+    ///
+    /// NSO does not have section headers, so the dynamic section
+    /// is located with the MOD0 header, like rtld does
+    fn from_nso(nso: Nso) -> anyhow::Result<Self> {
+        let segments = vec![
+            SegmentRange {
+                vaddr: nso.text.mem_offset,
+                data: nso.text.range(),
+                mem_size: nso.text.size,
+                permissions: PF_R | PF_X,
+            },
+            SegmentRange {
+                vaddr: nso.rodata.mem_offset,
+                data: nso.rodata.range(),
+                mem_size: nso.rodata.size,
+                permissions: PF_R,
+            },
+            SegmentRange {
+                vaddr: nso.data.mem_offset,
+                data: nso.data.range(),
+                mem_size: nso.data.size + nso.bss_size,
+                permissions: PF_R | PF_W,
+            },
+        ];
+        let build_id = Some(BuildId(nso.module_id));
+        let dynamic = find_dynamic_by_mod0(&nso.image, &segments)?;
+        Self::link(nso.image, segments, build_id, dynamic)
+    }
+
+    /// Read the dynamic section at the virtual address, and locate
+    /// the symbol and relocation tables from it
+    fn link(
+        data: Vec<u8>,
+        segments: Vec<SegmentRange>,
+        build_id: Option<BuildId>,
+        dynamic: u32,
+    ) -> anyhow::Result<Self> {
+        let dynamic_range = find_vaddr(&segments, dynamic, 0)
+            .ok_or_else(|| anyhow!("dynamic section at 0x{dynamic:08x} is not loaded"))?;
+        let dynamic = DynamicTable::new(LittleEndian, Class::ELF64, &data[dynamic_range]);
+        let info = DynamicInfo::parse(dynamic.iter())?;
+
+        let table = |name: &str, (start, size): (u32, u32)| {
+            if size == 0 {
+                return Ok(0..0);
+            }
+            find_vaddr(&segments, start, size)
+                .ok_or_else(|| anyhow!("unexpected out of bounds {name} at 0x{start:08x}"))
+        };
+        let symbol_count = symbol_count(&data, &segments, &info)?;
+        let symbols = table("DT_SYMTAB", (info.symtab, symbol_count * SYM_SIZE as u32))?;
+        let strtab = table("DT_STRTAB", info.strtab)?;
+        let rela_dyn = table("DT_RELA", info.rela)?;
+        let rela_plt = table("DT_JMPREL", info.jmprel)?;
+        let relr = table("DT_RELR", info.relr)?;
+
+        Ok(Self {
+            build_id: build_id.filter(|id| !id.is_empty()),
+            symbolic: info.symbolic,
+            init: info.init,
+            fini: info.fini,
+            init_array: info.init_array,
+            fini_array: info.fini_array,
+            symbols,
+            strtab,
+            rela_dyn,
+            rela_plt,
+            relr,
+            segments,
+            data,
+        })
+    }
+
+    /// The raw bytes of the module (the ELF file, or the decompressed NSO image)
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Iterate over the loadable segments, in the order of the virtual address
+    pub fn segments(&self) -> impl Iterator<Item = LoadSegment<'_>> {
+        self.segments.iter().map(|segment| LoadSegment {
+            vaddr: segment.vaddr,
+            data: &self.data[segment.data.clone()],
+            mem_size: segment.mem_size,
+            permissions: segment.permissions,
+        })
+    }
+
+    /// The dynamic symbol table
+    pub fn symbols(&self) -> SymbolTable<'_, LittleEndian> {
+        SymbolTable::new(LittleEndian, Class::ELF64, &self.data[self.symbols.clone()])
+    }
+
+    /// The string table for dynamic symbols
+    pub fn strtab(&self) -> StringTable<'_> {
+        StringTable::new(&self.data[self.strtab.clone()])
+    }
+
+    /// Read the initialized 64-bit value at the virtual address
+    pub fn read_u64(&self, vaddr: u32) -> anyhow::Result<u64> {
+        let range = find_vaddr(&self.segments, vaddr, 8)
+            .ok_or_else(|| anyhow!("unexpected out of bounds read at 0x{vaddr:08x}"))?;
+        Ok(u64::from_le_bytes(self.data[range].try_into()?))
+    }
+
+    /// Load the defined dynamic symbols from this module and store them by name
+    ///
+    /// start is the absolute address of this module
+    ///
+    /// Return how many symbols are loaded
+    pub fn load_dynamic_symbols(
+        &self,
+        module: ModuleType,
+        start: u64,
+        table: &mut BTreeMap<String, SymbolValue>,
+    ) -> anyhow::Result<u32> {
+        let mut count = 0;
+        let strtab = self.strtab();
+        for sym in self.symbols().iter() {
+            if sym.is_undefined() {
+                // no need to load undefined symbols - as they are defined somewhere else
+                continue;
+            }
+            if sym.st_name == 0 {
+                // symbol doesn't have a name
+                continue;
+            }
+            let name = strtab.get(sym.st_name as usize)?;
+            let bind_type = sym.st_bind();
+            let visibility = sym.st_vis();
+            match visibility {
+                STV_HIDDEN | STV_INTERNAL => {
+                    // skip hidden/internal symbols
+                    continue;
+                }
+                _ => {}
+            };
+            if bind_type == STB_LOCAL {
+                // skip local symbols
+                continue;
+            }
+            let value = SymbolValue {
+                address: sym.st_value + start,
+                weak: bind_type == STB_WEAK,
+                protected: visibility == STV_PROTECTED,
+            };
+            match table.entry(name.to_string()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(value);
+                }
+                Entry::Occupied(mut entry) => {
+                    if entry.get().weak {
+                        // linker can choose one arbitrarily if new one is also weak
+                        entry.insert(value);
+                    } else if !value.weak {
+                        // if both are strong, it's an error
+                        bail!("duplicate symbol in {}: {}", module, name);
+                    }
+                }
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Get the iterator for the relocations in .rela.dyn
+    pub fn rela_dyn(&self) -> ParsingIterator<'_, LittleEndian, Rela> {
+        RelaTable::new(
+            LittleEndian,
+            Class::ELF64,
+            &self.data[self.rela_dyn.clone()],
+        )
+        .iter()
+    }
+
+    /// Get the iterator for the relocations in .rela.plt
+    pub fn rela_plt(&self) -> ParsingIterator<'_, LittleEndian, Rela> {
+        RelaTable::new(
+            LittleEndian,
+            Class::ELF64,
+            &self.data[self.rela_plt.clone()],
+        )
+        .iter()
+    }
+
+    /// Get the targets of the packed relative relocations in .relr.dyn
+    pub fn relr(&self) -> anyhow::Result<Vec<u32>> {
+        decode_relr(&self.data[self.relr.clone()])
+    }
+}
+
+/// Find the initialized data at the virtual address, as a range in the module data.
+/// If size is 0, the rest of the segment is returned
+fn find_vaddr(segments: &[SegmentRange], vaddr: u32, size: u32) -> Option<Range<usize>> {
+    let segment = segments.iter().find(|segment| {
+        segment.vaddr <= vaddr && vaddr < segment.vaddr + segment.data.len() as u32
+    })?;
+    let start = segment.data.start + (vaddr - segment.vaddr) as usize;
+    if size == 0 {
+        return Some(start..segment.data.end);
+    }
+    let end = start + size as usize;
+    (end <= segment.data.end).then_some(start..end)
+}
+
+/// Locate the dynamic section with the MOD0 header. The second word in .text
+/// is the offset of MOD0, and the offset of the dynamic section is relative to MOD0
+fn find_dynamic_by_mod0(data: &[u8], segments: &[SegmentRange]) -> anyhow::Result<u32> {
+    let text = segments
+        .first()
+        .filter(|segment| segment.vaddr == 0)
+        .ok_or_else(|| anyhow!("module does not start with .text"))?;
+    let mod0 = read_u32(&data[text.data.clone()], 4)?;
+    let header = find_vaddr(segments, mod0, 8)
+        .map(|range| &data[range])
+        .ok_or_else(|| anyhow!("MOD0 header at 0x{mod0:08x} is not loaded"))?;
+    if &header[..4] != b"MOD0" {
+        bail!("invalid MOD0 magic");
+    }
+    let offset = read_u32(header, 4)? as i32;
+    Ok(mod0.wrapping_add_signed(offset))
+}
+
+/// Get the number of dynamic symbols, which is not stored in the dynamic section.
+/// Like other loaders, it's computed from the hash tables
+fn symbol_count(data: &[u8], segments: &[SegmentRange], info: &DynamicInfo) -> anyhow::Result<u32> {
+    let read = |vaddr: u32| {
+        find_vaddr(segments, vaddr, 4)
+            .map(|range| u32::from_le_bytes(data[range].try_into().unwrap()))
+            .ok_or_else(|| anyhow!("unexpected out of bounds hash table at 0x{vaddr:08x}"))
+    };
+    if let Some(hash) = info.hash {
+        // nbucket, nchain, where nchain is the number of symbols
+        return read(hash + 4);
+    }
+    if let Some(gnu_hash) = info.gnu_hash {
+        let nbuckets = read(gnu_hash)?;
+        let symoffset = read(gnu_hash + 4)?;
+        let bloom_size = read(gnu_hash + 8)?;
+        let buckets = gnu_hash + 16 + bloom_size * 8;
+        let mut last = 0;
+        for i in 0..nbuckets {
+            last = last.max(read(buckets + i * 4)?);
+        }
+        if last < symoffset {
+            return Ok(symoffset);
+        }
+        // walk the chain of the last bucket until the end marker
+        let chains = buckets + nbuckets * 4;
+        while read(chains + (last - symoffset) * 4)? & 1 == 0 {
+            last += 1;
+        }
+        return Ok(last + 1);
+    }
+    // .dynsym is usually followed by .dynstr
+    if info.strtab.0 > info.symtab {
+        return Ok((info.strtab.0 - info.symtab) / SYM_SIZE as u32);
+    }
+    bail!("cannot determine the number of dynamic symbols without DT_HASH or DT_GNU_HASH");
+}
@@ -0,0 +1,332 @@
+use anyhow::{anyhow, bail};
+use elf::abi::PT_NOTE;
+use elf::note::Note;
+
+use crate::elf::ElfWrapper;
+use crate::module::{ModuleInfo, ModuleType, Modules};
+
+/// Build ID of a module.
+///
+/// This is the GNU build ID note in an ELF, or the module ID in the NSO header,
+/// which is the same value padded with zeros to 0x20 bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BuildId(pub [u8; 0x20]);
+
+impl BuildId {
+    /// Create the build ID from the bytes, padding or truncating to 0x20 bytes
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        let mut id = [0; 0x20];
+        let len = bytes.len().min(0x20);
+        id[..len].copy_from_slice(&bytes[..len]);
+        Self(id)
+    }
+
+    /// Read the GNU build ID note from the PT_NOTE segments of an ELF
+    pub fn from_elf(elf: &ElfWrapper) -> anyhow::Result<Option<Self>> {
+        for ph in elf.segments {
+            if ph.p_type != PT_NOTE {
+                continue;
+            }
+            for note in elf.segment_data_as_notes(&ph)? {
+                if let Note::GnuBuildId(id) = note {
+                    return Ok(Some(Self::from_slice(id.0)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Parse a build ID from hex. Missing bytes at the end are zeros
+    pub fn from_hex(hex: &str) -> anyhow::Result<Self> {
+        let hex = hex.trim();
+        if hex.is_empty() || hex.len() > 0x40 || hex.len() % 2 != 0 {
+            bail!("invalid build ID: {hex}");
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| anyhow!("invalid build ID: {hex}"))?;
+        Ok(Self::from_slice(&bytes))
+    }
+
+    /// Check if the build ID is all zeros (i.e. the module does not have one)
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|b| *b == 0)
+    }
+}
+
+impl std::fmt::Display for BuildId {
+    /// Hex of the build ID, with the zero padding at the end removed
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let len = self.0.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+        for b in &self.0[..len] {
+            write!(f, "{b:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Versions of the game that the tool knows about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KnownVersion {
+    V1_5_0,
+    V1_6_0,
+}
+
+impl KnownVersion {
+    pub const ALL: [KnownVersion; 2] = [KnownVersion::V1_5_0, KnownVersion::V1_6_0];
+
+    pub fn is_1_6_0(self) -> bool {
+        self == KnownVersion::V1_6_0
+    }
+
+    /// Parse the version, like 1.5.0 or 150
+    pub fn parse(arg: &str) -> anyhow::Result<Self> {
+        match arg.trim() {
+            "1.5.0" | "150" => Ok(KnownVersion::V1_5_0),
+            "1.6.0" | "160" => Ok(KnownVersion::V1_6_0),
+            _ => bail!("unknown version: {arg}, expected 1.5.0 or 1.6.0"),
+        }
+    }
+
+    /// Get the known layout of the modules in this version
+    pub fn layout(self) -> Modules {
+        match self {
+            KnownVersion::V1_5_0 => Modules::new_1_5_0(),
+            KnownVersion::V1_6_0 => Modules::new_1_6_0(),
+        }
+    }
+}
+
+impl std::fmt::Display for KnownVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KnownVersion::V1_5_0 => write!(f, "1.5.0"),
+            KnownVersion::V1_6_0 => write!(f, "1.6.0"),
+        }
+    }
+}
+
+/// Build IDs of the modules from the retail game, as (version, module, build ID).
+///
+/// TODO: fill in the build IDs of 1.5.0 and 1.6.0. Until then, modules are identified
+/// by the SDK version string (sdk only) or their size instead
+pub const KNOWN_BUILDS: &[(KnownVersion, ModuleType, &str)] = &[];
+
+/// SDK version strings embedded in the sdk module of each version
+const SDK_VERSIONS: [(KnownVersion, &[u8]); 2] = [
+    (KnownVersion::V1_5_0, b"sdk_version: 4.4.0"),
+    (KnownVersion::V1_6_0, b"sdk_version: 7.3.2"),
+];
+
+/// Which versions a module belongs to, and how it is identified
+pub struct Identified {
+    pub module: ModuleType,
+    pub build_id: Option<BuildId>,
+    /// Versions the module matches. Empty if the module is unknown
+    pub versions: Vec<KnownVersion>,
+    /// How the versions are identified
+    pub source: &'static str,
+}
+
+impl Identified {
+    /// Identify the module by the build ID. If the build ID is not known,
+    /// the SDK version string is checked for the sdk module. Otherwise, the size
+    /// of the module (from the layout computed at offset 0) is compared
+    /// with the known layouts
+    pub fn new(
+        module: ModuleType,
+        build_id: Option<BuildId>,
+        bytes: &[u8],
+        info: &ModuleInfo,
+    ) -> Self {
+        if let Some(build_id) = build_id {
+            let versions = KNOWN_BUILDS
+                .iter()
+                .filter(|(_, m, id)| {
+                    *m == module && BuildId::from_hex(id).is_ok_and(|id| id == build_id)
+                })
+                .map(|(version, _, _)| *version)
+                .collect::<Vec<_>>();
+            if !versions.is_empty() {
+                return Self {
+                    module,
+                    build_id: Some(build_id),
+                    versions,
+                    source: "build id",
+                };
+            }
+        }
+        if module == ModuleType::Sdk {
+            let versions = SDK_VERSIONS
+                .into_iter()
+                .filter(|(_, needle)| memchr::memmem::find(bytes, needle).is_some())
+                .map(|(version, _)| version)
+                .collect::<Vec<_>>();
+            if !versions.is_empty() {
+                return Self {
+                    module,
+                    build_id,
+                    versions,
+                    source: "sdk ver",
+                };
+            }
+        }
+        let versions = KnownVersion::ALL
+            .into_iter()
+            .filter(|version| {
+                let layout = version.layout();
+                let known = layout.get(module);
+                known.text_end - known.start == info.text_end - info.start
+                    && known.end - known.start == info.end - info.start
+            })
+            .collect::<Vec<_>>();
+        let source = if versions.is_empty() {
+            "unknown"
+        } else {
+            "size"
+        };
+        Self {
+            module,
+            build_id,
+            versions,
+            source,
+        }
+    }
+
+    fn versions_str(&self) -> String {
+        if self.versions.is_empty() {
+            return "?".to_string();
+        }
+        self.versions
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// Print which version each module belongs to, and check they are from the same version.
+///
+/// Returns the version of the game, or None if it cannot be determined.
+/// Modules from different versions are an error unless force is true
+pub fn check_modules(modules: &[Identified], force: bool) -> anyhow::Result<Option<KnownVersion>> {
+    log::info!("");
+    log::info!("MODULE   VERSION      SOURCE   BUILD ID");
+    for identified in modules {
+        let build_id = match identified.build_id {
+            Some(id) => id.to_string(),
+            None => "-".to_string(),
+        };
+        log::info!(
+            "{:8} {:12} {:8} {}",
+            identified.module.to_string(),
+            identified.versions_str(),
+            identified.source,
+            build_id
+        );
+    }
+
+    for identified in modules {
+        if identified.versions.is_empty() {
+            log::warn!(
+                "{} does not match any known build, it might be modified",
+                identified.module
+            );
+        }
+    }
+    // versions that all identified modules agree on
+    let common = KnownVersion::ALL
+        .into_iter()
+        .filter(|version| {
+            modules
+                .iter()
+                .filter(|m| !m.versions.is_empty())
+                .all(|m| m.versions.contains(version))
+        })
+        .collect::<Vec<_>>();
+    match common.as_slice() {
+        [version] => {
+            log::info!("modules match {version}");
+            return Ok(Some(*version));
+        }
+        [] => {}
+        _ => return Ok(None),
+    }
+    if !force {
+        bail!(
+            "the modules are from different versions of the game, use --force to load them anyway"
+        );
+    }
+    log::warn!("the modules are from different versions of the game");
+    // use the version of main, since that's the game
+    let main = modules
+        .iter()
+        .find(|m| m.module == ModuleType::Main)
+        .and_then(|m| m.versions.first().copied());
+    Ok(main)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_hex() {
+        let id = BuildId::from_hex(" 0123abCD ").unwrap();
+        assert_eq!(id.0[..5], [0x01, 0x23, 0xAB, 0xCD, 0]);
+        assert_eq!(id.to_string(), "0123ABCD");
+        let full = "AB".repeat(0x20);
+        assert_eq!(BuildId::from_hex(&full).unwrap().0, [0xAB; 0x20]);
+        assert!(!BuildId::from_hex("01").unwrap().is_empty());
+        assert!(BuildId::from_hex("00").unwrap().is_empty());
+    }
+
+    #[test]
+    fn from_hex_invalid() {
+        for hex in ["", "0", "123", "0x12", "zz", &"AB".repeat(0x21)] {
+            assert!(BuildId::from_hex(hex).is_err(), "{hex}");
+        }
+    }
+
+    #[test]
+    fn identify_by_sdk_version_then_size() {
+        let info = KnownVersion::V1_6_0.layout().get(ModuleType::Sdk).clone();
+        let identified = Identified::new(ModuleType::Sdk, None, b"..sdk_version: 4.4.0..", &info);
+        assert_eq!(identified.versions, [KnownVersion::V1_5_0]);
+        assert_eq!(identified.source, "sdk ver");
+        let identified = Identified::new(ModuleType::Sdk, None, b"", &info);
+        assert_eq!(identified.versions, [KnownVersion::V1_6_0]);
+        assert_eq!(identified.source, "size");
+    }
+
+    fn identify(module: ModuleType, version: KnownVersion) -> Identified {
+        let info = version.layout().get(module).clone();
+        Identified::new(module, None, b"", &info)
+    }
+
+    #[test]
+    fn check_modules_same_version() {
+        let modules = [
+            identify(ModuleType::Main, KnownVersion::V1_6_0),
+            identify(ModuleType::Sdk, KnownVersion::V1_6_0),
+        ];
+        let version = check_modules(&modules, false).unwrap();
+        assert_eq!(version, Some(KnownVersion::V1_6_0));
+    }
+
+    #[test]
+    fn check_modules_mixed_versions() {
+        let info = KnownVersion::V1_6_0.layout().get(ModuleType::Sdk).clone();
+        let modules = [
+            identify(ModuleType::Main, KnownVersion::V1_5_0),
+            Identified::new(ModuleType::Sdk, None, b"sdk_version: 7.3.2", &info),
+        ];
+        assert_eq!(modules[1].versions, [KnownVersion::V1_6_0]);
+        assert!(check_modules(&modules, false).is_err());
+        // forced to the version of main
+        let version = check_modules(&modules, true).unwrap();
+        assert_eq!(version, Some(KnownVersion::V1_5_0));
+    }
+}
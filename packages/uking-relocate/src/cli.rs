@@ -1,136 +1,335 @@
-use anyhow::{anyhow, bail};
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 
-use crate::{
-    memory::{align_down, align_up},
-    module::ModuleType,
-};
+use uking_relocate::build_id::KnownVersion;
+use uking_relocate::define::{parse_define, SymbolDefine};
+use uking_relocate::{parse_region, parse_u64, RegionArg};
 
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub build: Option<BuildArgs>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Print a summary of an existing BlueFlame image, or compare two images
+    Inspect(InspectArgs),
+    /// Translate addresses between the decomp, module offsets and physical addresses
+    Addr(AddrArgs),
+    /// Find the program in a RAM dump, undo the relocations and report the pages modified at runtime
+    Derelocate(DerelocateArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct InspectArgs {
+    /// Path to the BlueFlame image (.bfi)
+    pub image: String,
+
+    /// Compare the image with another image page by page, and print
+    /// the module and segment of each page that is different
+    #[clap(long)]
+    pub diff: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct AddrArgs {
+    /// Addresses to translate. Each address can be:
+    /// - a decomp (or IDA) address like 0x71000007a0, which is in main
+    /// - an offset relative to a module like [sdk]:0x1000
+    /// - a physical address like 0x1234504000, with --start
+    /// - a dynamic symbol with an optional +offset, with --sdk
+    ///
+    /// Use decomp:ADDR or phys:ADDR if a number can be both
+    #[clap(required = true)]
+    pub addresses: Vec<String>,
+
+    /// Use the known layout of this version of the game (1.5.0 or 1.6.0).
+    ///
+    /// Only the .text of each module is known. The rest of the module (rodata, data and bss)
+    /// is printed as one segment with unknown permissions. Use --sdk to get all the segments
+    #[clap(
+        long,
+        value_parser(KnownVersion::parse),
+        required_unless_present = "sdk"
+    )]
+    pub game_version: Option<KnownVersion>,
+
+    /// Path to the sdk module of the dump, to use the layout, segments
+    /// and dynamic symbols from the modules instead of the known layout
+    #[clap(long, conflicts_with = "game_version")]
+    pub sdk: Option<String>,
+
+    /// Extra modules to load with --sdk, like when building the image
+    #[clap(long, requires = "sdk")]
+    pub module: Vec<String>,
+
+    /// The physical start address of the program, for translating physical addresses
+    #[clap(short, long, value_parser(parse_u64))]
+    pub start: Option<u64>,
+
+    /// Also print the nearest dynamic symbol at or before each address. Needs --sdk
+    #[clap(long, requires = "sdk")]
+    pub symbol: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct DerelocateArgs {
+    /// Path to the RAM dump, which contains the whole program
+    pub dump: String,
+
+    /// Path to the game's sdk module, the other modules are found the same way as building
+    pub sdk_elf: String,
+
+    /// Extra modules loaded in the dump, like when building the image
+    #[clap(long)]
+    pub module: Vec<String>,
+
+    /// Load the modules even if they are from different versions of the game,
+    /// or the version cannot be determined (the modules are then loaded as 1.5.0)
+    #[clap(long)]
+    pub force: bool,
+
+    /// The physical start address of the program when the dump is taken.
+    ///
+    /// If not specified, it's found from the values of the RELATIVE relocations in the dump
+    #[clap(short, long, value_parser(parse_u64))]
+    pub start: Option<u64>,
+
+    /// Write a JSON report of the program start, the relocations and the pages modified at runtime
+    #[clap(long)]
+    pub report: Option<String>,
+
+    /// Write the image of each module with the relocations undone to this directory, as MODULE.bin
+    #[clap(long)]
+    pub modules_out: Option<String>,
+
+    /// Build a BlueFlame image (.bfi) from the dump and write it to this path
+    #[clap(short, long)]
+    pub output: Option<String>,
+
+    /// The physical start address of the image built with --output. Defaults to the start of the dump
+    #[clap(long, value_parser(parse_u64), requires = "output")]
+    pub new_start: Option<u64>,
+
+    /// Path to the root of a romfs layer for the image built with --output, like when building
+    #[clap(long, requires = "output")]
+    pub romfs: Vec<String>,
+
+    /// Keep all-zero pages in the image built with --output, see --keep-zero-pages of the build
+    #[clap(long, requires = "output")]
+    pub keep_zero_pages: bool,
+}
+
+// arguments for building the image, when no subcommand is specified
+#[derive(Debug, Args)]
+pub struct BuildArgs {
     /// Path to the game's sdk module.
     ///
-    /// This must be a decompressed ELF. This tool will automatically
-    /// detect if you are using version 1.5.0 or 1.6.0, based
-    /// on embedded sdk version
+    /// This can be the NSO dumped from exefs, or an ELF converted from it.
+    /// The format is detected automatically for each module.
+    /// This tool will automatically detect if you are using version 1.5.0 or 1.6.0, based
+    /// on the build ID of the modules
     ///
     /// Once the version is detected, "sdk" in the file name
     /// will be replaced with "main", "rtld" and "subsdk0" to
     /// find the other modules.
     pub sdk_elf: String,
 
-    /// Path to the root of the romfs. Not all romfs files are required. See README
+    /// Path to the root of a romfs layer. Not all romfs files are required. See README
     ///
-    /// If not specified, the program searches:
+    /// Can be specified multiple times to stack layers like LayeredFS (i.e. mods),
+    /// where later layers have higher priority. If not specified, these are used:
     /// - "romfs" directory in the same directory as the sdk elf
-    /// - "romfs" directory in the parent directory of the sdk elf
+    /// - "romfs" directory in the parent directory of the sdk elf (lowest priority)
+    ///
+    /// The directories found next to the sdk are not used when --romfs is specified,
+    /// so the base romfs needs to be the first --romfs when stacking mods
     #[clap(long)]
-    pub romfs: Option<String>,
+    pub romfs: Vec<String>,
 
     /// Path to the output file.
-    #[clap(short, long, default_value = "program.bfi")]
-    pub output: String,
+    ///
+    /// Defaults to "program.bfi", "program.elf" or "program" (a directory) depending on --format
+    #[clap(short, long)]
+    pub output: Option<String>,
+
+    /// Format of the output
+    #[clap(long, value_enum, default_value_t = OutputFormat::Bfi)]
+    pub format: OutputFormat,
+
+    /// Also write a linker-map style listing of the modules, segments and
+    /// dynamic symbols (with absolute addresses) to this path
+    #[clap(long)]
+    pub symbols_out: Option<String>,
+
+    /// Also write a JSON report of every relocation applied, and the
+    /// relocations with unresolved symbols, to this path
+    #[clap(long)]
+    pub reloc_report: Option<String>,
+
+    /// Also write the dependency graph between the modules to this path: the symbols each module
+    /// imports from the other modules, by relocation type, and the exported symbols that are
+    /// not imported by any other module.
+    ///
+    /// The format is DOT (Graphviz) if the path ends with .dot, otherwise JSON
+    #[clap(long)]
+    pub import_graph: Option<String>,
+
+    /// Also write the entry point and static initializers (DT_INIT and .init_array) of each module,
+    /// and the finalizers (DT_FINI and .fini_array), to this path in JSON. The addresses are after
+    /// relocation, and the modules are in load order.
+    ///
+    /// DT_INIT and .init_array are listed separately: rtld only calls DT_INIT,
+    /// which then calls the functions in .init_array.
+    ///
+    /// These are not stored in the bfi and elf outputs. With `--format raw`, they are also in layout.json
+    #[clap(long)]
+    pub init_out: Option<String>,
+
+    /// Fail if any symbol cannot be resolved, unless it's in the --allowlist
+    #[clap(long)]
+    pub strict: bool,
+
+    /// Path to a file of unresolved symbols to allow with --strict, one symbol per line.
+    /// Empty lines and lines starting with # are ignored
+    #[clap(long, requires = "strict")]
+    pub allowlist: Option<String>,
+
+    /// Run sanity checks on the relocated program, and fail if any check fails:
+    /// - Every relocated pointer lands inside a mapped region of the module that defines the symbol
+    /// - Every vtable (_ZTV* in --data-symbols-csv) points into an executable segment of
+    ///   the module that defines the virtual functions
+    /// - Every function in --functions-csv starts with a valid instruction in an executable segment
+    #[clap(long)]
+    pub verify: bool,
+
+    /// Also write the JSON report of the --verify checks and failures to this path
+    #[clap(long, requires = "verify")]
+    pub verify_report: Option<String>,
+
+    /// Path to data_symbols.csv from the decomp, for the vtables checked by --verify
+    #[clap(long, requires = "verify")]
+    pub data_symbols_csv: Option<String>,
 
     /// The physical start address of the program region.
     ///
     /// This is also the start of nnrtld. Address must be in hexadecimal and the leading 0x is optional and ignored.
     /// Additionally, the upper 24 bits and lower 20 bits must be zero.
-    #[clap(short, long, value_parser(parse_u64))]
-    pub start: u64,
+    #[clap(
+        short,
+        long,
+        value_parser(parse_u64),
+        required_unless_present = "random_start"
+    )]
+    pub start: Option<u64>,
+
+    /// Pick a random start from the seed instead of --start, like the ASLR of the loader.
+    ///
+    /// The same seed always picks the same start
+    #[clap(long, conflicts_with = "start")]
+    pub random_start: Option<u64>,
+
+    /// Build this many images, each at a different random start from --random-start.
+    ///
+    /// The modules are only loaded once. The index of each image is added to the output paths,
+    /// for example "program-0.bfi" (including --symbols-out, --reloc-report, --import-graph,
+    /// --verify-report and --init-out)
+    #[clap(long, requires = "random_start", value_parser = clap::value_parser!(u32).range(1..))]
+    pub batch: Option<u32>,
 
     /// Regions of memory to keep in the program image, in the format
     /// of `[module]:start-end` (including the brackets). If empty, everything is kept.
     ///
-    /// Module can be: [rtld (alias: nnrtld), main (alias: uking, u-king), subsdk0 (alias: multimedia), sdk (alias: nnsdk)],
+    /// Module can be: [rtld (alias: nnrtld), main (alias: uking, u-king), subsdk0 (alias: multimedia), sdk (alias: nnsdk),
+    /// subsdk1 to subsdk9 (extra modules, including NRO plugins loaded with --module)],
     /// .nss postfixes are ignored. rtld is the same as not specifying a module.
     ///
-    /// The start and end offsets are specified as relative offsets
-    /// to --start or module if specified. The end offset is exclusive.
-    /// They must be in hexadecimal and the leading 0x is optional and ignored
+    /// The start and end can be:
+    /// - offsets relative to --start or module if specified, in hexadecimal with the leading 0x
+    ///   (otherwise it is a symbol name)
+    /// - decomp addresses like 0x71000007a0, which are in the main module
+    /// - symbol names, with an optional +offset, found in the dynamic symbols or --functions-csv
+    ///
+    /// The end is exclusive. `SYMBOL+SIZE` keeps SIZE bytes from the symbol,
+    /// and `SYMBOL` keeps the whole function (the size is from --functions-csv)
+    ///
+    /// Offsets and sizes also need the 0x prefix. Older versions accepted
+    /// hexadecimal without it, like `1000-2000`, which is now `0x1000-0x2000`
     ///
     /// Extra memory may be included if the inputs are not page aligned.
     #[clap(short, long, value_parser(parse_region))]
     pub regions: Vec<RegionArg>,
-}
 
-fn parse_region(arg: &str) -> anyhow::Result<RegionArg> {
-    let (module, arg) = match arg.strip_prefix("[") {
-        None => (ModuleType::None, arg),
-        Some(rest) => {
-            let rest = rest.trim_start();
-            let mut parts = rest.splitn(2, "]:");
-            let module_str = parts
-                .next()
-                .ok_or_else(|| anyhow!("invalid region syntax: cannot parse module"))?
-                .trim();
-            let rest = parts.next().ok_or_else(|| {
-                anyhow!("invalid region syntax: missing address range after module")
-            })?;
-            if parts.next().is_some() {
-                bail!("invalid region syntax: too many colons")
-            }
-            let module_str = module_str
-                .strip_suffix(".nss")
-                .unwrap_or(module_str)
-                .to_ascii_lowercase();
-            let module = match module_str.as_str() {
-                "rtld" | "nnrtld" => ModuleType::None,
-                "main" | "uking" | "u-king" => ModuleType::Main,
-                "subsdk0" | "multimedia" => ModuleType::Subsdk0,
-                "sdk" | "nnsdk" => ModuleType::Sdk,
-                _ => {
-                    bail!("invalid module: {}", module_str)
-                }
-            };
-            (module, rest)
-        }
-    };
-    let mut parts = arg.splitn(2, '-');
-    let start = parts
-        .next()
-        .ok_or_else(|| anyhow!("invalid region syntax: missing start address"))?
-        .trim();
-    let end = parts
-        .next()
-        .ok_or_else(|| anyhow!("invalid region syntax: missing end address"))?
-        .trim();
-    if parts.next().is_some() {
-        bail!("invalid region syntax: too many dashes")
-    }
-    // align to page boundary
-    let start = align_down!(parse_u32(start)?);
-    let end = align_up!(parse_u32(end)?);
+    /// Path to uking_functions.csv from the decomp, for finding function names
+    /// and sizes in --regions, and for the functions checked by --verify
+    #[clap(long)]
+    pub functions_csv: Option<String>,
 
-    if start >= end {
-        bail!("invalid region: start must be less than end")
-    }
+    /// Keep all-zero pages (like .bss) in the output. By default they are left out,
+    /// since the memory is zero-filled when loaded, which splits the segments around them
+    #[clap(long)]
+    pub keep_zero_pages: bool,
 
-    Ok(RegionArg { module, start, end })
-}
+    /// Load the modules even if they are from different versions of the game,
+    /// or the version cannot be determined (the modules are then loaded as 1.5.0)
+    #[clap(long)]
+    pub force: bool,
 
-fn parse_u32(arg: &str) -> anyhow::Result<u32> {
-    let arg = arg.trim_start_matches(['0', 'x', 'X']);
-    if arg.is_empty() {
-        return Ok(0);
-    }
-    Ok(u32::from_str_radix(arg, 16)?)
+    /// Load an extra module (NSO, NRO or ELF), like an exlaunch plugin. Can be specified multiple times.
+    ///
+    /// Extra modules are loaded as subsdk1 to subsdk9, after subsdk0 and before sdk.
+    /// The slot is from the file name if it's subsdkN, otherwise the first free slot is used.
+    /// subsdk1 to subsdk9 next to the sdk module are loaded automatically
+    #[clap(long)]
+    pub module: Vec<String>,
+
+    /// Patch the modules before relocation. Can be specified multiple times.
+    ///
+    /// This can be an IPS/IPS32 file named by the build ID of the module (like Atmosphere),
+    /// a directory of them, or a YAML file with a list of patches (module, offset and bytes). See README
+    #[clap(long)]
+    pub patch: Vec<String>,
+
+    /// Define a symbol for linking, as NAME=ADDR. Can be specified multiple times.
+    ///
+    /// This inserts the symbol, or overrides the definitions in the modules. ADDR can be
+    /// an absolute address (0x3400001000), relative to a module ([sdk]:0x1000), or another symbol
+    /// with an optional offset (nnMain+0x10). Useful for stubbing unresolved imports with trap addresses
+    #[clap(long, value_parser(parse_define))]
+    pub define: Vec<SymbolDefine>,
+
+    /// Path to a YAML file of symbols to define, as a map from NAME to ADDR. See --define.
+    /// These are defined before the ones from --define
+    #[clap(long)]
+    pub defines: Option<String>,
 }
 
-fn parse_u64(arg: &str) -> anyhow::Result<u64> {
-    let arg = arg.trim_start_matches(['0', 'x', 'X']);
-    if arg.is_empty() {
-        return Ok(0);
-    }
-    Ok(u64::from_str_radix(arg, 16)?)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// BlueFlame image
+    Bfi,
+    /// ELF with one segment per memory region at the absolute address,
+    /// with the dynamic symbols of all modules.
+    /// For use with disassemblers and emulators.
+    /// --regions and romfs data are not included
+    Elf,
+    /// Directory with each kept memory region in its own .bin file,
+    /// and the layout of the files in layout.json.
+    /// romfs data is not included
+    Raw,
 }
 
-/// Region to keep in the output
-#[derive(Debug, Clone)]
-pub struct RegionArg {
-    /// The module that the start and end are relative to
-    pub module: ModuleType,
-    /// The start offset
-    pub start: u32,
-    /// The end offset
-    pub end: u32,
+impl OutputFormat {
+    /// Get the default output path for this format
+    pub fn default_output(self) -> &'static str {
+        match self {
+            Self::Bfi => "program.bfi",
+            Self::Elf => "program.elf",
+            Self::Raw => "program",
+        }
+    }
 }
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail};
+
+/// Address of the start of the main module in the decomp (i.e. in uking_functions.csv)
+pub const DECOMP_MAIN_START: u64 = 0x7100000000;
+
+/// Convert a decomp address to an offset in the main module
+pub fn to_main_offset(address: u64) -> anyhow::Result<u32> {
+    address
+        .checked_sub(DECOMP_MAIN_START)
+        .and_then(|offset| u32::try_from(offset).ok())
+        .ok_or_else(|| anyhow!("0x{address:x} is not a decomp address"))
+}
+
+/// A function listed in uking_functions.csv
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecompFunction {
+    /// Decomp address of the function
+    pub address: u64,
+    /// Size of the function in bytes
+    pub size: u32,
+}
+
+/// Read the functions from uking_functions.csv in the decomp, by name.
+///
+/// Functions without a name or with a placeholder name (like sub_...) are ignored
+pub fn read_functions_csv(path: &str) -> anyhow::Result<BTreeMap<String, DecompFunction>> {
+    log::info!("-- reading functions from {path}");
+    let content = std::fs::read_to_string(path)?;
+    let mut functions = BTreeMap::new();
+    for (i, line) in content.lines().enumerate() {
+        // example:
+        // 0x00000071000007a0,O,000032,_ZN4ksys3act8BaseProc11hasJobType_ENS0_7JobTypeE
+        let Some(line) = line.strip_prefix("0x") else {
+            // header
+            continue;
+        };
+        let mut parts = line.split(',');
+        let (Some(address), Some(_status), Some(size)) = (parts.next(), parts.next(), parts.next())
+        else {
+            bail!("invalid line {} in {path}", i + 1);
+        };
+        let name = parts.next().unwrap_or_default();
+        if name.is_empty()
+            || name.starts_with("sub_")
+            || name.starts_with("nullsub_")
+            || name.starts_with("j_")
+        {
+            continue;
+        }
+        let function = DecompFunction {
+            address: u64::from_str_radix(address, 16)
+                .map_err(|_| anyhow!("invalid address on line {} in {path}", i + 1))?,
+            size: size
+                .parse()
+                .map_err(|_| anyhow!("invalid size on line {} in {path}", i + 1))?,
+        };
+        functions.insert(name.to_string(), function);
+    }
+    log::info!("-- loaded {} functions", functions.len());
+    Ok(functions)
+}
+
+/// Read the data symbols from data_symbols.csv in the decomp, as (decomp address, name).
+///
+/// Symbols without a name are ignored
+pub fn read_data_symbols_csv(path: &str) -> anyhow::Result<Vec<(u64, String)>> {
+    log::info!("-- reading data symbols from {path}");
+    let content = std::fs::read_to_string(path)?;
+    let mut symbols = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        // example:
+        // 0x00000071024d8d60,_ZTVN4sead14SafeStringBaseIcEE
+        let Some(line) = line.strip_prefix("0x") else {
+            // header
+            continue;
+        };
+        let Some((address, name)) = line.split_once(',') else {
+            bail!("invalid line {} in {path}", i + 1);
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            continue;
+        }
+        let address = u64::from_str_radix(address, 16)
+            .map_err(|_| anyhow!("invalid address on line {} in {path}", i + 1))?;
+        symbols.push((address, name.to_string()));
+    }
+    log::info!("-- loaded {} data symbols", symbols.len());
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    /// Write the content to a file in the directory, and return the path
+    fn write_temp(dir: &TempDir, name: &str, content: &str) -> String {
+        dir.write(name, content).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn functions_csv() {
+        let dir = TempDir::new();
+        let path = write_temp(
+            &dir,
+            "functions.csv",
+            "Address,Quality,Size,Name\n\
+             0x00000071000007a0,O,000032,_ZN4ksys3act8BaseProc11hasJobType_ENS0_7JobTypeE\n\
+             0x00000071000007c0,U,000016,\n\
+             0x00000071000007d0,U,000016,sub_71000007D0\n\
+             0x00000071000007e0,U,000004,nullsub_1\n\
+             0x00000071000007f0,U,000004,j__ZdlPv\n\
+             0x0000007100000800,m,000100,nnMain\n",
+        );
+        let functions = read_functions_csv(&path).unwrap();
+        assert_eq!(functions.len(), 2);
+        assert_eq!(
+            functions["_ZN4ksys3act8BaseProc11hasJobType_ENS0_7JobTypeE"],
+            DecompFunction {
+                address: 0x71000007a0,
+                size: 32
+            }
+        );
+        assert_eq!(
+            functions["nnMain"],
+            DecompFunction {
+                address: 0x7100000800,
+                size: 100
+            }
+        );
+    }
+
+    #[test]
+    fn functions_csv_invalid() {
+        let dir = TempDir::new();
+        let path = write_temp(&dir, "functions-invalid.csv", "0x00000071000007a0,O\n");
+        assert!(read_functions_csv(&path).is_err());
+        let path = write_temp(
+            &dir,
+            "functions-invalid-size.csv",
+            "0x00000071000007a0,O,abc,foo\n",
+        );
+        assert!(read_functions_csv(&path).is_err());
+    }
+
+    #[test]
+    fn data_symbols_csv() {
+        let dir = TempDir::new();
+        let path = write_temp(
+            &dir,
+            "data_symbols.csv",
+            "Address,Name\n\
+             0x00000071024d8d60,_ZTVN4sead14SafeStringBaseIcEE\n\
+             0x00000071024d8d80, \n\
+             0x00000071024d8d90,_ZTIN4sead14SafeStringBaseIcEE\n",
+        );
+        let symbols = read_data_symbols_csv(&path).unwrap();
+        assert_eq!(
+            symbols,
+            [
+                (0x71024d8d60, "_ZTVN4sead14SafeStringBaseIcEE".to_string()),
+                (0x71024d8d90, "_ZTIN4sead14SafeStringBaseIcEE".to_string()),
+            ]
+        );
+        let path = write_temp(&dir, "data_symbols-invalid.csv", "0x00000071024d8d60\n");
+        assert!(read_data_symbols_csv(&path).is_err());
+        let path = write_temp(&dir, "data_symbols-invalid-address.csv", "0xzz,foo\n");
+        assert!(read_data_symbols_csv(&path).is_err());
+    }
+}
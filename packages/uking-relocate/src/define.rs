@@ -0,0 +1,251 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+
+use crate::elf::{DynamicSymbolTables, SymbolValue};
+use crate::module::{ModuleType, Modules};
+use crate::{is_hex_number, parse_hex_number, parse_u64};
+
+/// A symbol defined by the user, which is inserted into the dynamic symbols,
+/// or overrides the definitions in the modules
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolDefine {
+    pub name: String,
+    pub value: DefineValue,
+}
+
+/// Address of a user-defined symbol
+#[derive(Debug, Clone, PartialEq)]
+pub enum DefineValue {
+    /// Absolute physical address
+    Absolute(u64),
+    /// Offset relative to the start of a module
+    Module(ModuleType, u32),
+    /// Address of another symbol, plus an offset
+    Symbol(String, u64),
+}
+
+/// Parse NAME=ADDR
+pub fn parse_define(arg: &str) -> anyhow::Result<SymbolDefine> {
+    let (name, value) = arg
+        .split_once('=')
+        .ok_or_else(|| anyhow!("invalid define syntax: expected NAME=ADDR"))?;
+    let name = name.trim();
+    if name.is_empty() {
+        bail!("invalid define syntax: missing name");
+    }
+    Ok(SymbolDefine {
+        name: name.to_string(),
+        value: parse_value(value)?,
+    })
+}
+
+/// Parse the address of a define, which can be:
+/// - an absolute address in hexadecimal, like 0x3400001000
+/// - an offset relative to a module, like [sdk]:0x1000
+/// - a symbol with an optional +offset, like nnMain+0x10
+///
+/// Like --regions, numbers need the 0x prefix
+pub fn parse_value(value: &str) -> anyhow::Result<DefineValue> {
+    let value = value.trim();
+    if value.is_empty() {
+        bail!("invalid define syntax: missing address");
+    }
+    if let Some(rest) = value.strip_prefix('[') {
+        let (module, offset) = rest
+            .split_once("]:")
+            .ok_or_else(|| anyhow!("invalid define syntax: missing offset after module"))?;
+        let module = ModuleType::from_name(module)
+            .ok_or_else(|| anyhow!("invalid module: {}", module.trim()))?;
+        let offset = u32::try_from(parse_offset(offset)?)
+            .map_err(|_| anyhow!("invalid define: offset is too big"))?;
+        return Ok(DefineValue::Module(module, offset));
+    }
+    if is_hex_number(value) {
+        return Ok(DefineValue::Absolute(parse_u64(value)?));
+    }
+    let (symbol, offset) = match value.rsplit_once('+') {
+        Some((symbol, offset)) => (symbol.trim(), parse_offset(offset)?),
+        None => (value, 0),
+    };
+    if symbol.is_empty() {
+        bail!("invalid define syntax: missing symbol");
+    }
+    if symbol.starts_with(|c: char| c.is_ascii_digit()) {
+        bail!("invalid define: {symbol} is not a symbol, numbers need the 0x prefix");
+    }
+    Ok(DefineValue::Symbol(symbol.to_string(), offset))
+}
+
+fn parse_offset(arg: &str) -> anyhow::Result<u64> {
+    parse_hex_number(arg.trim()).map_err(|e| anyhow!("invalid define: {e}"))
+}
+
+/// Read defines from a YAML file, which is a map from the name to the address.
+/// Numbers are absolute addresses, strings are parsed like --define
+/// ```yaml
+/// nnsdkSomeFunction: "[sdk]:0x1000"
+/// trap: 0x3400000000
+/// nnsdkOtherFunction: trap+0x10
+/// ```
+pub fn read_defines_file(path: impl AsRef<Path>) -> anyhow::Result<Vec<SymbolDefine>> {
+    let path = path.as_ref();
+    let map: serde_yaml::Mapping = serde_yaml::from_str(&std::fs::read_to_string(path)?)
+        .map_err(|e| anyhow!("failed to parse {}: {e}", path.display()))?;
+    let mut defines = Vec::with_capacity(map.len());
+    for (name, value) in map {
+        let name = name
+            .as_str()
+            .ok_or_else(|| anyhow!("{}: names must be strings", path.display()))?
+            .to_string();
+        let value = match &value {
+            serde_yaml::Value::Number(n) => n
+                .as_u64()
+                .map(DefineValue::Absolute)
+                .ok_or_else(|| anyhow!("{}: invalid address for {name}", path.display()))?,
+            serde_yaml::Value::String(s) => {
+                parse_value(s).map_err(|e| anyhow!("{}: {name}: {e}", path.display()))?
+            }
+            _ => bail!("{}: invalid address for {name}", path.display()),
+        };
+        defines.push(SymbolDefine { name, value });
+    }
+    Ok(defines)
+}
+
+impl SymbolDefine {
+    /// Get the absolute address of the define, when the program is loaded at start
+    ///
+    /// Symbols are looked up in the magic symbols (which include the
+    /// defines before this one), then the modules in load order
+    pub fn resolve(
+        &self,
+        start: u64,
+        modules: &Modules,
+        symbols: &DynamicSymbolTables,
+    ) -> anyhow::Result<u64> {
+        match &self.value {
+            DefineValue::Absolute(address) => Ok(*address),
+            DefineValue::Module(module, offset) => {
+                if !modules.contains(*module) {
+                    bail!("cannot define {}: {module} is not loaded", self.name);
+                }
+                Ok(start + modules.get(*module).start as u64 + *offset as u64)
+            }
+            DefineValue::Symbol(name, offset) => {
+                let address = symbols
+                    .find(name)
+                    .map(|symbol| symbol.address)
+                    .ok_or_else(|| {
+                        anyhow!("cannot define {}: cannot find symbol {name}", self.name)
+                    })?;
+                Ok(address + offset)
+            }
+        }
+    }
+}
+
+/// Resolve the defines in order and insert them into the magic symbols,
+/// overriding the definitions in the modules
+pub fn apply_defines(
+    defines: &[SymbolDefine],
+    start: u64,
+    modules: &Modules,
+    symbols: &mut DynamicSymbolTables,
+) -> anyhow::Result<()> {
+    log::info!("");
+    log::info!("ADDRESS            NAME");
+    for define in defines {
+        let address = define.resolve(start, modules, symbols)?;
+        let overridden = symbols
+            .modules()
+            .filter(|module| symbols.get(*module).contains_key(&define.name))
+            .map(|module| module.to_string())
+            .collect::<Vec<_>>();
+        if overridden.is_empty() {
+            log::info!("0x{address:016x} {}", define.name);
+        } else {
+            log::info!(
+                "0x{address:016x} {} (overrides {})",
+                define.name,
+                overridden.join(", ")
+            );
+        }
+        symbols.magic.insert(
+            define.name.clone(),
+            SymbolValue {
+                address,
+                weak: false,
+                protected: false,
+            },
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str, offset: u64) -> DefineValue {
+        DefineValue::Symbol(name.to_string(), offset)
+    }
+
+    #[test]
+    fn value_absolute_and_module() {
+        assert_eq!(
+            parse_value("0x3400001000").unwrap(),
+            DefineValue::Absolute(0x3400001000)
+        );
+        assert_eq!(
+            parse_value(" [sdk]:0x1000 ").unwrap(),
+            DefineValue::Module(ModuleType::Sdk, 0x1000)
+        );
+        assert_eq!(
+            parse_value("[main]: 0x10").unwrap(),
+            DefineValue::Module(ModuleType::Main, 0x10)
+        );
+    }
+
+    #[test]
+    fn value_symbol() {
+        // hex digits without 0x are symbols
+        for name in ["add", "cafe", "beef", "nnMain"] {
+            assert_eq!(parse_value(name).unwrap(), symbol(name, 0));
+        }
+        assert_eq!(
+            parse_value("nnMain + 0x10").unwrap(),
+            symbol("nnMain", 0x10)
+        );
+        assert_eq!(parse_value("trap+0x10").unwrap(), symbol("trap", 0x10));
+    }
+
+    #[test]
+    fn value_invalid() {
+        for value in [
+            "",
+            "+0x10",
+            "[foo]:0x10",
+            "[sdk]0x10",
+            "[sdk]:0x100000000",
+            "nnMain+xyz",
+            // numbers need the 0x prefix
+            "1000",
+            "3400001000",
+            "1000+0x10",
+            "[sdk]:1000",
+            "nnMain+10",
+        ] {
+            assert!(parse_value(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn define() {
+        let define = parse_define("nnsdkSomeFunction = [sdk]:0x1000").unwrap();
+        assert_eq!(define.name, "nnsdkSomeFunction");
+        assert_eq!(define.value, DefineValue::Module(ModuleType::Sdk, 0x1000));
+        assert!(parse_define("nnMain").is_err());
+        assert!(parse_define("=0x1000").is_err());
+    }
+}
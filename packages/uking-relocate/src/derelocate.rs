@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use uking_relocate::{ModuleData, Romfs};
+
+use crate::cli::DerelocateArgs;
+
+/// Find the program in the dump, undo the relocations, and write the outputs
+pub fn run(args: &DerelocateArgs) -> anyhow::Result<()> {
+    let data = ModuleData::load(&args.sdk_elf, &args.module, args.force)?;
+    println!("-- reading dump: {}", args.dump);
+    let dump = std::fs::read(&args.dump)?;
+    let derelocated = uking_relocate::ram_dump::derelocate(&data, &dump, args.start)?;
+    let report = &derelocated.report;
+
+    println!();
+    println!("dump offset:   0x{:08x}", report.dump_offset);
+    println!("program start: 0x{:016x}", report.start);
+    println!("restored:      {} relocations", report.restored);
+    if !report.mismatched.is_empty() {
+        println!();
+        println!("relocation targets modified at runtime:");
+        println!("MODULE   OFFSET     EXPECTED           ACTUAL             SYMBOL");
+        for r in &report.mismatched {
+            println!(
+                "{:8} 0x{:08x} 0x{:016x} 0x{:016x} {}",
+                r.module.to_string(),
+                r.offset,
+                r.expected,
+                r.actual,
+                r.symbol.as_deref().unwrap_or(r.r_type)
+            );
+        }
+    }
+    if !report.modified_pages.is_empty() {
+        println!();
+        println!("pages modified at runtime:");
+        println!("MODULE   START      PERM BYTES");
+        for page in &report.modified_pages {
+            println!(
+                "{:8} 0x{:08x} {}  {}",
+                page.module.to_string(),
+                page.rel_start,
+                page.permissions,
+                page.bytes
+            );
+        }
+    }
+    println!();
+
+    if let Some(path) = &args.report {
+        println!("-- writing report: {path}");
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(writer, report)?;
+    }
+    if let Some(dir) = &args.modules_out {
+        println!("-- writing module images: {dir}");
+        std::fs::create_dir_all(dir)?;
+        for module in data.info.modules() {
+            let path = Path::new(dir).join(format!("{module}.bin"));
+            std::fs::write(path, derelocated.module_image(&data, module))?;
+        }
+    }
+    if let Some(output_path) = &args.output {
+        let new_start = args.new_start.unwrap_or(report.start);
+        println!("-- loading the dump at 0x{new_start:016x}...");
+        let memory = derelocated.rebase(&data, new_start)?;
+        let romfs = Romfs::find_layers(&args.sdk_elf, &args.romfs)?;
+        let romfs_data = romfs.load_manifest()?;
+        let program =
+            uking_relocate::build_program(&data, &memory, &[], !args.keep_zero_pages, romfs_data)?;
+        let packed = uking_relocate::pack_program(&program)?;
+        println!("-- writing output file: {output_path}");
+        std::fs::write(output_path, packed)?;
+    }
+
+    println!("done!");
+    Ok(())
+}
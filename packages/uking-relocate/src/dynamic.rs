@@ -0,0 +1,240 @@
+use anyhow::bail;
+use elf::abi::{
+    DF_SYMBOLIC, DF_TEXTREL, DT_FINI, DT_FINI_ARRAY, DT_FINI_ARRAYSZ, DT_FLAGS, DT_GNU_HASH,
+    DT_HASH, DT_INIT, DT_INIT_ARRAY, DT_INIT_ARRAYSZ, DT_JMPREL, DT_NULL, DT_PLTREL, DT_PLTRELSZ,
+    DT_REL, DT_RELA, DT_RELAENT, DT_RELASZ, DT_STRSZ, DT_STRTAB, DT_SYMBOLIC, DT_SYMENT, DT_SYMTAB,
+    DT_TEXTREL,
+};
+use elf::dynamic::Dyn;
+
+// not in the elf crate yet
+const DT_RELRSZ: i64 = 35;
+const DT_RELR: i64 = 36;
+const DT_RELRENT: i64 = 37;
+
+const SYMENT: u64 = 0x18;
+const RELAENT: u64 = 0x18;
+const RELRENT: u64 = 0x8;
+
+/// Information needed for linking a module, read from the dynamic section.
+///
+/// Addresses are virtual addresses relative to the module start,
+/// and tables are (address, size in bytes)
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DynamicInfo {
+    /// DT_SYMTAB
+    pub symtab: u32,
+    /// DT_STRTAB and DT_STRSZ
+    pub strtab: (u32, u32),
+    /// DT_HASH
+    pub hash: Option<u32>,
+    /// DT_GNU_HASH
+    pub gnu_hash: Option<u32>,
+    /// DT_RELA and DT_RELASZ
+    pub rela: (u32, u32),
+    /// DT_JMPREL and DT_PLTRELSZ
+    pub jmprel: (u32, u32),
+    /// DT_RELR and DT_RELRSZ
+    pub relr: (u32, u32),
+    /// DT_SYMBOLIC, or DF_SYMBOLIC in DT_FLAGS
+    pub symbolic: bool,
+    /// DT_INIT
+    pub init: Option<u32>,
+    /// DT_FINI
+    pub fini: Option<u32>,
+    /// DT_INIT_ARRAY and DT_INIT_ARRAYSZ
+    pub init_array: (u32, u32),
+    /// DT_FINI_ARRAY and DT_FINI_ARRAYSZ
+    pub fini_array: (u32, u32),
+}
+
+impl DynamicInfo {
+    /// Parse the entries of the dynamic section, up to DT_NULL
+    pub fn parse(entries: impl IntoIterator<Item = Dyn>) -> anyhow::Result<Self> {
+        let mut info = Self::default();
+        let mut has_symtab = false;
+        let mut has_strtab = false;
+        for entry in entries {
+            let tag = entry.d_tag;
+            let value = entry.d_val();
+            match tag {
+                DT_NULL => break,
+                DT_SYMTAB => {
+                    info.symtab = value as u32;
+                    has_symtab = true;
+                }
+                DT_STRTAB => {
+                    info.strtab.0 = value as u32;
+                    has_strtab = true;
+                }
+                DT_STRSZ => info.strtab.1 = value as u32,
+                DT_HASH => info.hash = Some(value as u32),
+                DT_GNU_HASH => info.gnu_hash = Some(value as u32),
+                DT_RELA => info.rela.0 = value as u32,
+                DT_RELASZ => info.rela.1 = value as u32,
+                DT_JMPREL => info.jmprel.0 = value as u32,
+                DT_PLTRELSZ => info.jmprel.1 = value as u32,
+                DT_RELR => info.relr.0 = value as u32,
+                DT_RELRSZ => info.relr.1 = value as u32,
+                DT_SYMBOLIC => info.symbolic = true,
+                DT_INIT => info.init = Some(value as u32),
+                DT_FINI => info.fini = Some(value as u32),
+                DT_INIT_ARRAY => info.init_array.0 = value as u32,
+                DT_INIT_ARRAYSZ => info.init_array.1 = value as u32,
+                DT_FINI_ARRAY => info.fini_array.0 = value as u32,
+                DT_FINI_ARRAYSZ => info.fini_array.1 = value as u32,
+                DT_FLAGS => {
+                    if value & DF_SYMBOLIC as u64 != 0 {
+                        info.symbolic = true;
+                    }
+                    if value & DF_TEXTREL as u64 != 0 {
+                        bail!("text relocations (DF_TEXTREL) are not supported");
+                    }
+                }
+                DT_SYMENT if value != SYMENT => {
+                    bail!("unexpected DT_SYMENT: 0x{value:x}");
+                }
+                DT_RELAENT if value != RELAENT => {
+                    bail!("unexpected DT_RELAENT: 0x{value:x}");
+                }
+                DT_RELRENT if value != RELRENT => {
+                    bail!("unexpected DT_RELRENT: 0x{value:x}");
+                }
+                DT_PLTREL if value != DT_RELA as u64 => {
+                    bail!("unexpected DT_PLTREL: only DT_RELA is supported for .rela.plt");
+                }
+                DT_REL => {
+                    bail!("DT_REL relocations are not supported, only DT_RELA and DT_RELR");
+                }
+                DT_TEXTREL => {
+                    bail!("text relocations (DT_TEXTREL) are not supported");
+                }
+                _ => {}
+            }
+        }
+        if !has_symtab || !has_strtab {
+            bail!("missing DT_SYMTAB or DT_STRTAB in dynamic section");
+        }
+        Ok(info)
+    }
+}
+
+/// Decode the packed relative relocations in a DT_RELR table
+///
+/// Return the offsets of the relocation targets, relative to the module start
+pub fn decode_relr(data: &[u8]) -> anyhow::Result<Vec<u32>> {
+    if data.len() % RELRENT as usize != 0 {
+        bail!("unexpected DT_RELRSZ: 0x{:x}", data.len());
+    }
+    let mut offsets = Vec::new();
+    let mut next = None;
+    for entry in data.chunks_exact(RELRENT as usize) {
+        let entry = u64::from_le_bytes(entry.try_into()?);
+        if entry & 1 == 0 {
+            // an address entry, which relocates the address itself
+            offsets.push(entry as u32);
+            next = Some(entry + 8);
+            continue;
+        }
+        // a bitmap entry, the remaining 63 bits relocate the next 63 words
+        let Some(base) = next else {
+            bail!("unexpected DT_RELR bitmap without a preceding address");
+        };
+        for i in 0..63 {
+            if entry & (1 << (i + 1)) != 0 {
+                offsets.push((base + i * 8) as u32);
+            }
+        }
+        next = Some(base + 63 * 8);
+    }
+    Ok(offsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use elf::dynamic::DynamicTable;
+    use elf::endian::LittleEndian;
+    use elf::file::Class;
+
+    use super::*;
+
+    /// Parse the (tag, value) entries as a dynamic section
+    fn parse(entries: &[(i64, u64)]) -> anyhow::Result<DynamicInfo> {
+        let data = entries
+            .iter()
+            .flat_map(|(tag, value)| [tag.to_le_bytes(), value.to_le_bytes()])
+            .flatten()
+            .collect::<Vec<_>>();
+        DynamicInfo::parse(DynamicTable::new(LittleEndian, Class::ELF64, &data).iter())
+    }
+
+    fn relr(entries: &[u64]) -> Vec<u8> {
+        entries.iter().flat_map(|e| e.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn parse_reads_tables() {
+        let info = parse(&[
+            (DT_SYMTAB, 0x1000),
+            (DT_STRTAB, 0x2000),
+            (DT_STRSZ, 0x300),
+            (DT_SYMENT, SYMENT),
+            (DT_RELA, 0x4000),
+            (DT_RELASZ, 0x60),
+            (DT_RELAENT, RELAENT),
+            (DT_JMPREL, 0x5000),
+            (DT_PLTRELSZ, 0x30),
+            (DT_PLTREL, DT_RELA as u64),
+            (DT_RELR, 0x6000),
+            (DT_RELRSZ, 0x10),
+            (DT_INIT, 0x100),
+            (DT_INIT_ARRAY, 0x7000),
+            (DT_INIT_ARRAYSZ, 0x18),
+            (DT_FLAGS, DF_SYMBOLIC as u64),
+            (DT_NULL, 0),
+            // ignored after DT_NULL
+            (DT_TEXTREL, 0),
+        ])
+        .unwrap();
+        assert_eq!(info.symtab, 0x1000);
+        assert_eq!(info.strtab, (0x2000, 0x300));
+        assert_eq!(info.rela, (0x4000, 0x60));
+        assert_eq!(info.jmprel, (0x5000, 0x30));
+        assert_eq!(info.relr, (0x6000, 0x10));
+        assert_eq!(info.init, Some(0x100));
+        assert_eq!(info.fini, None);
+        assert_eq!(info.init_array, (0x7000, 0x18));
+        assert!(info.symbolic);
+    }
+
+    #[test]
+    fn parse_rejects_unsupported() {
+        let base = [(DT_SYMTAB, 0x1000), (DT_STRTAB, 0x2000)];
+        assert!(parse(&base).is_ok());
+        assert!(parse(&base[..1]).is_err());
+        for entry in [
+            (DT_REL, 0x3000),
+            (DT_TEXTREL, 0),
+            (DT_FLAGS, DF_TEXTREL as u64),
+            (DT_SYMENT, 0x10),
+            (DT_PLTREL, DT_REL as u64),
+        ] {
+            assert!(parse(&[base[0], base[1], entry]).is_err(), "{entry:?}");
+        }
+    }
+
+    #[test]
+    fn decode_relr_address_and_bitmap() {
+        // 0x1000, then a bitmap (bit 0 is the marker) for 0x1008, 0x1010 and
+        // the last word (0x1008 + 62 * 8), then a second bitmap continuing at 0x1008 + 63 * 8
+        let offsets = decode_relr(&relr(&[0x1000, 0b111 | 1 << 63, 0b11])).unwrap();
+        assert_eq!(offsets, [0x1000, 0x1008, 0x1010, 0x11f8, 0x1200]);
+    }
+
+    #[test]
+    fn decode_relr_rejects_bad_input() {
+        assert!(decode_relr(&relr(&[1 << 1 | 1])).is_err());
+        assert!(decode_relr(&[0; 4]).is_err());
+        assert!(decode_relr(&[]).unwrap().is_empty());
+    }
+}
@@ -1,15 +1,13 @@
-use std::collections::btree_map::Entry;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-use anyhow::{anyhow, bail};
+use anyhow::anyhow;
 use derive_more::Deref;
 
-use elf::abi::{STB_LOCAL, STB_WEAK, STV_HIDDEN, STV_INTERNAL, STV_PROTECTED};
 use elf::endian::LittleEndian;
-use elf::parse::{ParsingIterator, ParsingTable};
-use elf::relocation::Rela;
+use elf::parse::ParsingTable;
 use elf::segment::ProgramHeader;
 use elf::ElfBytes;
+use serde::Serialize;
 
 use crate::module::ModuleType;
 
@@ -29,106 +27,27 @@ impl<'data> ElfWrapper<'data> {
             .ok_or_else(|| anyhow!("unexpected empty program header table"))?;
         Ok(Self { elf, segments })
     }
-
-    /// Load the defined dynamic symbols from this ELF file and store them by name
-    ///
-    /// start is the absolute address of this module
-    ///
-    /// Return how many symbols are loaded
-    pub fn load_dynamic_symbols(
-        &self,
-        module: ModuleType,
-        start: u64,
-        table: &mut BTreeMap<String, SymbolValue>,
-    ) -> anyhow::Result<u32> {
-        let (dynsyms, strtab) = self
-            .dynamic_symbol_table()?
-            .ok_or_else(|| anyhow!("missing dynamic symbol table"))?;
-        let mut count = 0;
-        for sym in dynsyms {
-            if sym.is_undefined() {
-                // no need to load undefined symbols - as they are defined somewhere else
-                continue;
-            }
-            if sym.st_name == 0 {
-                // symbol doesn't have a name
-                continue;
-            }
-            let name = strtab.get(sym.st_name as usize)?;
-            let bind_type = sym.st_bind();
-            let visibility = sym.st_vis();
-            match visibility {
-                STV_HIDDEN | STV_INTERNAL => {
-                    // skip hidden/internal symbols
-                    continue;
-                }
-                _ => {}
-            };
-            if bind_type == STB_LOCAL {
-                // skip local symbols
-                continue;
-            }
-            let value = SymbolValue {
-                address: sym.st_value + start,
-                weak: bind_type == STB_WEAK,
-                protected: visibility == STV_PROTECTED,
-            };
-            match table.entry(name.to_string()) {
-                Entry::Vacant(entry) => {
-                    entry.insert(value);
-                }
-                Entry::Occupied(mut entry) => {
-                    if entry.get().weak {
-                        // linker can choose one arbitrarily if new one is also weak
-                        entry.insert(value);
-                    } else if !value.weak {
-                        // if both are strong, it's an error
-                        bail!("duplicate symbol in {}: {}", module, name);
-                    }
-                }
-            }
-            count += 1;
-        }
-        Ok(count)
-    }
-
-    // BOTW only has .rela.dyn and .rela.plt sections, not .rel
-
-    /// Get the iterator for the .rela.dyn section
-    pub fn rela_dyn(&self) -> anyhow::Result<ParsingIterator<'data, LittleEndian, Rela>> {
-        let rela_dyn = self
-            .section_header_by_name(".rela.dyn")?
-            .ok_or_else(|| anyhow!("missing .rela.dyn section"))?;
-        let rela_dyn = self.section_data_as_relas(&rela_dyn)?;
-        Ok(rela_dyn)
-    }
-
-    /// Get the iterator for the .rela.plt section
-    pub fn rela_plt(&self) -> anyhow::Result<ParsingIterator<'data, LittleEndian, Rela>> {
-        let rela_plt = self
-            .section_header_by_name(".rela.plt")?
-            .ok_or_else(|| anyhow!("missing .rela.plt section"))?;
-        let rela_plt = self.section_data_as_relas(&rela_plt)?;
-        Ok(rela_plt)
-    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct DynamicSymbolTables {
-    pub rtld: BTreeMap<String, SymbolValue>,
-    pub main: BTreeMap<String, SymbolValue>,
-    pub subsdk0: BTreeMap<String, SymbolValue>,
-    pub sdk: BTreeMap<String, SymbolValue>,
+    /// Symbols exported by each module. The map is in load order
+    pub tables: BTreeMap<ModuleType, BTreeMap<String, SymbolValue>>,
 
-    // these are provided by magic
+    // these are provided by magic, and the symbols defined with --define,
+    // which override the definitions in the modules
     pub magic: HashMap<String, SymbolValue>,
+
+    /// Modules linked with DT_SYMBOLIC, which resolve symbols
+    /// in themselves before the global search
+    pub symbolic: BTreeSet<ModuleType>,
 }
 
 impl DynamicSymbolTables {
     pub fn new(start: u64, size: u32) -> Self {
         let mut magic = HashMap::new();
         // these are guesses... we can probably verify
-        // but it's not really important
+        // but it's not really important. They can be overridden with --define
         magic.insert(
             "__EX_start".to_string(),
             SymbolValue {
@@ -146,62 +65,163 @@ impl DynamicSymbolTables {
             },
         );
         Self {
-            rtld: BTreeMap::new(),
-            main: BTreeMap::new(),
-            subsdk0: BTreeMap::new(),
-            sdk: BTreeMap::new(),
+            tables: BTreeMap::new(),
             magic,
+            symbolic: BTreeSet::new(),
         }
     }
-    /// Get the absolute physical address of a dynamic symbol
+    /// Get the symbol table of a module, which is empty if the module is not loaded
+    pub fn get(&self, module: ModuleType) -> &BTreeMap<String, SymbolValue> {
+        static EMPTY: BTreeMap<String, SymbolValue> = BTreeMap::new();
+        self.tables.get(&module).unwrap_or(&EMPTY)
+    }
+
+    /// Get the symbol table of a module for loading symbols into
+    pub fn get_mut(&mut self, module: ModuleType) -> &mut BTreeMap<String, SymbolValue> {
+        self.tables.entry(module).or_default()
+    }
+
+    /// Iterate over the modules with symbol tables, in load order
+    pub fn modules(&self) -> impl Iterator<Item = ModuleType> + '_ {
+        self.tables.keys().copied()
+    }
+
+    /// Iterate over the symbols exported by all modules, in module load order.
+    /// Magic symbols are not included
+    pub fn iter(&self) -> impl Iterator<Item = (ModuleType, &str, &SymbolValue)> {
+        self.tables.iter().flat_map(|(module, table)| {
+            table
+                .iter()
+                .map(move |(name, value)| (*module, name.as_str(), value))
+        })
+    }
+
+    /// Find a symbol by name in the magic symbols, then the modules in load order
+    pub fn find(&self, name: &str) -> Option<&SymbolValue> {
+        self.magic
+            .get(name)
+            .or_else(|| self.tables.values().find_map(|table| table.get(name)))
+    }
+
+    /// Get the absolute physical address of a dynamic symbol, following the rtld lookup rules
+    ///
+    /// module is the module that is trying to resolve the symbol.
     ///
-    /// module is the module that is trying to resolve the symbol
-    pub fn resolve(&self, _module: ModuleType, name: &str) -> anyhow::Result<u64> {
+    /// - A protected definition in the requesting module cannot be interposed, so it's
+    ///   used directly. All references from a module linked with DT_SYMBOLIC also look
+    ///   in the requesting module first
+    /// - Otherwise, the modules are searched in load order and the first definition wins.
+    ///   rtld does not prefer strong definitions over weak ones, so a weak definition
+    ///   in an earlier module interposes a strong definition in a later module
+    ///
+    /// Returns None if the symbol cannot be resolved. The caller decides what to do
+    /// with it (for example, weak references are resolved to 0)
+    pub fn resolve(&self, module: ModuleType, name: &str) -> Option<ResolvedSymbol> {
         if let Some(symbol) = self.magic.get(name) {
-            return Ok(symbol.address);
-        }
-        let mut results = vec![];
-        if let Some(symbol) = self.rtld.get(name) {
-            results.push((ModuleType::None, symbol));
-        }
-        if let Some(symbol) = self.main.get(name) {
-            results.push((ModuleType::Main, symbol));
+            return Some(ResolvedSymbol::new(symbol.address, None));
         }
-        if let Some(symbol) = self.subsdk0.get(name) {
-            results.push((ModuleType::Subsdk0, symbol));
-        }
-        if let Some(symbol) = self.sdk.get(name) {
-            results.push((ModuleType::Sdk, symbol));
-        }
-        if results.is_empty() {
-            bail!("cannot resolve dynamic symbol: {}", name);
-        }
-        if results.len() == 1 {
-            return Ok(results[0].1.address);
-        }
-        // if all of the symbols are weak, choose one arbitrarily
-        if results.iter().all(|(_, symbol)| symbol.weak) {
-            return Ok(results[0].1.address);
-        }
-        // if one is strong, pick that one
-        let mut strong_sym = None;
-        for (_, symbol) in &results {
-            if !symbol.weak {
-                if strong_sym.is_some() {
-                    bail!("conflicting strong symbol: {}", name);
-                }
-                strong_sym = Some(symbol);
+        if let Some(symbol) = self.get(module).get(name) {
+            if symbol.protected || self.symbolic.contains(&module) {
+                return Some(ResolvedSymbol::new(symbol.address, Some(module)));
             }
         }
-        if let Some(symbol) = strong_sym {
-            return Ok(symbol.address);
+        self.search_global(name)
+    }
+
+    /// Search the modules in load order, without the rules for the requesting module
+    fn search_global(&self, name: &str) -> Option<ResolvedSymbol> {
+        self.tables.iter().find_map(|(provider, table)| {
+            let symbol = table.get(name)?;
+            Some(ResolvedSymbol::new(symbol.address, Some(*provider)))
+        })
+    }
+
+    /// Find the symbols that are defined in more than one module
+    pub fn find_conflicts(&self) -> Vec<SymbolConflict> {
+        let mut definitions = BTreeMap::<&str, Vec<SymbolDefinition>>::new();
+        for (module, name, value) in self.iter() {
+            definitions.entry(name).or_default().push(SymbolDefinition {
+                module,
+                address: value.address,
+                weak: value.weak,
+                protected: value.protected,
+            });
         }
-        println!("{results:?}");
-        // found more than one symbol, does it even happen?
-        bail!("ambiguous symbol: {name}");
+        definitions
+            .into_iter()
+            .filter(|(_, definitions)| definitions.len() > 1)
+            .map(|(name, definitions)| {
+                let chosen = if self.magic.contains_key(name) {
+                    None
+                } else {
+                    self.search_global(name).and_then(|s| s.provider)
+                };
+                let chosen_weak = definitions
+                    .iter()
+                    .find(|d| Some(d.module) == chosen)
+                    .is_some_and(|d| d.weak);
+                let interposes_strong = chosen_weak && definitions.iter().any(|d| !d.weak);
+                let self_bound = definitions
+                    .iter()
+                    .map(|d| d.module)
+                    .filter(|module| Some(*module) != chosen)
+                    .filter(|module| {
+                        self.resolve(*module, name)
+                            .is_some_and(|s| s.provider == Some(*module))
+                    })
+                    .collect();
+                SymbolConflict {
+                    name: name.to_string(),
+                    chosen,
+                    self_bound,
+                    interposes_strong,
+                    definitions,
+                }
+            })
+            .collect()
+    }
+}
+
+/// A symbol reference resolved by [`DynamicSymbolTables::resolve`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResolvedSymbol {
+    /// The absolute physical address of the definition
+    pub address: u64,
+    /// The module with the definition. None for magic symbols (including --define)
+    pub provider: Option<ModuleType>,
+}
+
+impl ResolvedSymbol {
+    fn new(address: u64, provider: Option<ModuleType>) -> Self {
+        Self { address, provider }
     }
 }
 
+/// A symbol that is defined in more than one module
+#[derive(Debug, Clone, Serialize)]
+pub struct SymbolConflict {
+    pub name: String,
+    /// The module whose definition is chosen by the global search, which is what
+    /// the modules without their own definition bind to. None if a magic symbol
+    /// overrides the definitions
+    pub chosen: Option<ModuleType>,
+    /// Other modules that bind to their own definition instead (protected or DT_SYMBOLIC),
+    /// see [`DynamicSymbolTables::resolve`]
+    pub self_bound: Vec<ModuleType>,
+    /// If the chosen definition is weak, but a later module has a strong definition
+    pub interposes_strong: bool,
+    /// The definitions, in module load order
+    pub definitions: Vec<SymbolDefinition>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SymbolDefinition {
+    pub module: ModuleType,
+    pub address: u64,
+    pub weak: bool,
+    pub protected: bool,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct SymbolValue {
     /// The absolute physical address of the symbol
@@ -212,3 +232,234 @@ pub struct SymbolValue {
     /// the definition in the same module
     pub protected: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: u64 = 0x3400000000;
+
+    fn define(
+        symbols: &mut DynamicSymbolTables,
+        module: ModuleType,
+        name: &str,
+        address: u64,
+        weak: bool,
+        protected: bool,
+    ) {
+        let value = SymbolValue {
+            address,
+            weak,
+            protected,
+        };
+        symbols.get_mut(module).insert(name.to_string(), value);
+    }
+
+    fn provider(
+        symbols: &DynamicSymbolTables,
+        module: ModuleType,
+        name: &str,
+    ) -> Option<ModuleType> {
+        symbols.resolve(module, name).unwrap().provider
+    }
+
+    #[test]
+    fn resolve_magic_first() {
+        let mut symbols = DynamicSymbolTables::new(START, 0x1000);
+        define(
+            &mut symbols,
+            ModuleType::Main,
+            "__EX_start",
+            START + 0x10,
+            false,
+            true,
+        );
+        let resolved = symbols.resolve(ModuleType::Main, "__EX_start").unwrap();
+        assert_eq!(resolved, ResolvedSymbol::new(START, None));
+        assert_eq!(
+            symbols
+                .resolve(ModuleType::Sdk, "__EX_end")
+                .unwrap()
+                .address,
+            START + 0x1000
+        );
+    }
+
+    #[test]
+    fn resolve_first_in_load_order() {
+        let mut symbols = DynamicSymbolTables::new(START, 0x1000);
+        define(
+            &mut symbols,
+            ModuleType::Sdk,
+            "f",
+            START + 0x30,
+            false,
+            false,
+        );
+        define(
+            &mut symbols,
+            ModuleType::Main,
+            "f",
+            START + 0x10,
+            false,
+            false,
+        );
+        define(
+            &mut symbols,
+            ModuleType::Subsdk0,
+            "f",
+            START + 0x20,
+            false,
+            false,
+        );
+        for module in [ModuleType::None, ModuleType::Subsdk0, ModuleType::Sdk] {
+            let resolved = symbols.resolve(module, "f").unwrap();
+            assert_eq!(
+                resolved,
+                ResolvedSymbol::new(START + 0x10, Some(ModuleType::Main))
+            );
+        }
+        assert!(symbols.resolve(ModuleType::Main, "g").is_none());
+    }
+
+    #[test]
+    fn resolve_protected_and_symbolic_in_self() {
+        let mut symbols = DynamicSymbolTables::new(START, 0x1000);
+        define(
+            &mut symbols,
+            ModuleType::Main,
+            "f",
+            START + 0x10,
+            false,
+            false,
+        );
+        define(
+            &mut symbols,
+            ModuleType::Subsdk0,
+            "f",
+            START + 0x20,
+            false,
+            true,
+        );
+        define(
+            &mut symbols,
+            ModuleType::Sdk,
+            "f",
+            START + 0x30,
+            false,
+            false,
+        );
+        // protected binds in its own module only
+        assert_eq!(
+            provider(&symbols, ModuleType::Subsdk0, "f"),
+            Some(ModuleType::Subsdk0)
+        );
+        assert_eq!(
+            provider(&symbols, ModuleType::Sdk, "f"),
+            Some(ModuleType::Main)
+        );
+
+        symbols.symbolic.insert(ModuleType::Sdk);
+        assert_eq!(
+            provider(&symbols, ModuleType::Sdk, "f"),
+            Some(ModuleType::Sdk)
+        );
+        assert_eq!(
+            provider(&symbols, ModuleType::None, "f"),
+            Some(ModuleType::Main)
+        );
+    }
+
+    #[test]
+    fn resolve_weak_interposes_strong() {
+        let mut symbols = DynamicSymbolTables::new(START, 0x1000);
+        define(
+            &mut symbols,
+            ModuleType::Main,
+            "f",
+            START + 0x10,
+            true,
+            false,
+        );
+        define(
+            &mut symbols,
+            ModuleType::Sdk,
+            "f",
+            START + 0x30,
+            false,
+            false,
+        );
+        // rtld does not prefer strong definitions
+        assert_eq!(
+            provider(&symbols, ModuleType::Sdk, "f"),
+            Some(ModuleType::Main)
+        );
+
+        let conflicts = symbols.find_conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].chosen, Some(ModuleType::Main));
+        assert!(conflicts[0].interposes_strong);
+        assert!(conflicts[0].self_bound.is_empty());
+    }
+
+    #[test]
+    fn find_conflicts_follows_resolve() {
+        let mut symbols = DynamicSymbolTables::new(START, 0x1000);
+        define(
+            &mut symbols,
+            ModuleType::Main,
+            "f",
+            START + 0x10,
+            false,
+            false,
+        );
+        define(
+            &mut symbols,
+            ModuleType::Subsdk0,
+            "f",
+            START + 0x20,
+            false,
+            true,
+        );
+        define(
+            &mut symbols,
+            ModuleType::Sdk,
+            "f",
+            START + 0x30,
+            false,
+            false,
+        );
+        define(
+            &mut symbols,
+            ModuleType::Main,
+            "__EX_end",
+            START + 0x10,
+            false,
+            false,
+        );
+        define(
+            &mut symbols,
+            ModuleType::Sdk,
+            "__EX_end",
+            START + 0x30,
+            false,
+            false,
+        );
+        symbols.symbolic.insert(ModuleType::Sdk);
+
+        let conflicts = symbols.find_conflicts();
+        assert_eq!(conflicts.len(), 2);
+        // overridden by the magic symbol
+        assert_eq!(conflicts[0].name, "__EX_end");
+        assert_eq!(conflicts[0].chosen, None);
+        assert!(conflicts[0].self_bound.is_empty());
+
+        assert_eq!(conflicts[1].name, "f");
+        assert_eq!(conflicts[1].chosen, Some(ModuleType::Main));
+        assert_eq!(
+            conflicts[1].self_bound,
+            [ModuleType::Subsdk0, ModuleType::Sdk]
+        );
+        assert!(!conflicts[1].interposes_strong);
+    }
+}
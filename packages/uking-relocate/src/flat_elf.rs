@@ -0,0 +1,355 @@
+use elf::abi::{
+    ELFCLASS64, ELFDATA2LSB, ELFOSABI_NONE, EM_AARCH64, ET_EXEC, EV_CURRENT, PF_W, PF_X, PT_LOAD,
+    SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHN_ABS, SHT_DYNSYM, SHT_PROGBITS, SHT_STRTAB, STB_GLOBAL,
+    STB_WEAK, STT_FUNC, STT_NOTYPE, STT_OBJECT, STV_DEFAULT, STV_PROTECTED,
+};
+
+use crate::memory::{align_up, Memory, Region};
+
+const EHDR_SIZE: usize = 0x40;
+const PHDR_SIZE: usize = 0x38;
+const SHDR_SIZE: usize = 0x40;
+const SYM_SIZE: usize = 0x18;
+
+/// Build a relocated ELF image of the program memory.
+///
+/// Each region is a PT_LOAD segment at its absolute physical address,
+/// and the dynamic symbols exported by all modules are merged into one .dynsym.
+/// Magic symbols (including --define) are SHN_ABS symbols, and replace the definitions
+/// with the same name in the modules, like when linking
+pub fn build(memory: &Memory) -> Vec<u8> {
+    let start = memory.get_program_start();
+    let regions = &memory.regions;
+
+    let mut out = Writer::default();
+    let data_offset = align_up!(EHDR_SIZE + PHDR_SIZE * regions.len());
+    out.0.resize(data_offset, 0);
+
+    // region data, page aligned in the file
+    let mut region_offsets = Vec::with_capacity(regions.len());
+    for region in regions {
+        region_offsets.push(out.0.len());
+        for page in region.iter_pages() {
+            out.0.extend_from_slice(page);
+        }
+    }
+
+    // merged dynamic symbols, sorted by address
+    let magic = &memory.dynamic_symbols.magic;
+    let mut symbols = memory
+        .dynamic_symbols
+        .iter()
+        .filter(|(_, name, _)| !magic.contains_key(*name))
+        .map(|(_, name, value)| (name, value, false))
+        .chain(
+            magic
+                .iter()
+                .map(|(name, value)| (name.as_str(), value, true)),
+        )
+        .collect::<Vec<_>>();
+    symbols.sort_by_key(|(name, value, _)| (value.address, *name));
+    let mut dynstr = StringTable::default();
+    let mut dynsym = Writer::default();
+    dynsym.0.resize(SYM_SIZE, 0); // null symbol
+    for (name, value, is_magic) in symbols {
+        let region_idx = regions.iter().position(|region| {
+            let region_start = start + region.rel_start as u64;
+            let region_end = region_start + region.get_byte_len() as u64;
+            (region_start..region_end).contains(&value.address)
+        });
+        let (shndx, sym_type) = match region_idx {
+            // magic symbols are not defined by any module
+            _ if is_magic => (SHN_ABS, STT_NOTYPE),
+            // section 0 is the null section
+            Some(i) if regions[i].permissions & PF_X != 0 => (i as u16 + 1, STT_FUNC),
+            Some(i) => (i as u16 + 1, STT_OBJECT),
+            None => (SHN_ABS, STT_NOTYPE),
+        };
+        let bind = if value.weak { STB_WEAK } else { STB_GLOBAL };
+        let visibility = if value.protected {
+            STV_PROTECTED
+        } else {
+            STV_DEFAULT
+        };
+        dynsym.u32(dynstr.add(name));
+        dynsym.u8((bind << 4) | sym_type);
+        dynsym.u8(visibility);
+        dynsym.u16(shndx);
+        dynsym.u64(value.address);
+        dynsym.u64(0); // size is unknown
+    }
+
+    out.align(8);
+    let dynsym_offset = out.0.len();
+    out.0.extend_from_slice(&dynsym.0);
+    let dynstr_offset = out.0.len();
+    out.0.extend_from_slice(&dynstr.0);
+
+    let mut shstrtab = StringTable::default();
+    let region_names = regions
+        .iter()
+        .map(|region| shstrtab.add(&section_name(region)))
+        .collect::<Vec<_>>();
+    let dynsym_name = shstrtab.add(".dynsym");
+    let dynstr_name = shstrtab.add(".dynstr");
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    let shstrtab_offset = out.0.len();
+    out.0.extend_from_slice(&shstrtab.0);
+
+    // section headers: null, regions, .dynsym, .dynstr, .shstrtab
+    out.align(8);
+    let shoff = out.0.len();
+    let dynsym_idx = regions.len() + 1;
+    let dynstr_idx = dynsym_idx + 1;
+    let shstrtab_idx = dynstr_idx + 1;
+    out.0.resize(shoff + SHDR_SIZE, 0);
+    for ((region, name), offset) in regions.iter().zip(region_names).zip(&region_offsets) {
+        let mut flags = SHF_ALLOC;
+        if region.permissions & PF_W != 0 {
+            flags |= SHF_WRITE;
+        }
+        if region.permissions & PF_X != 0 {
+            flags |= SHF_EXECINSTR;
+        }
+        out.section_header(SectionHeader {
+            name,
+            sh_type: SHT_PROGBITS,
+            flags: flags as u64,
+            addr: start + region.rel_start as u64,
+            offset: *offset as u64,
+            size: region.get_byte_len() as u64,
+            link: 0,
+            info: 0,
+            align: 0x1000,
+            entsize: 0,
+        });
+    }
+    out.section_header(SectionHeader {
+        name: dynsym_name,
+        sh_type: SHT_DYNSYM,
+        // not mapped, the symbols are only for tools like IDA and Ghidra
+        flags: 0,
+        addr: 0,
+        offset: dynsym_offset as u64,
+        size: dynsym.0.len() as u64,
+        link: dynstr_idx as u32,
+        // index of the first non-local symbol
+        info: 1,
+        align: 8,
+        entsize: SYM_SIZE as u64,
+    });
+    out.section_header(SectionHeader {
+        name: dynstr_name,
+        sh_type: SHT_STRTAB,
+        flags: 0,
+        addr: 0,
+        offset: dynstr_offset as u64,
+        size: dynstr.0.len() as u64,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    });
+    out.section_header(SectionHeader {
+        name: shstrtab_name,
+        sh_type: SHT_STRTAB,
+        flags: 0,
+        addr: 0,
+        offset: shstrtab_offset as u64,
+        size: shstrtab.0.len() as u64,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    });
+
+    // file header and program headers
+    let mut header = Writer::default();
+    header.0.extend_from_slice(b"\x7fELF");
+    header.u8(ELFCLASS64);
+    header.u8(ELFDATA2LSB);
+    header.u8(EV_CURRENT);
+    header.u8(ELFOSABI_NONE);
+    header.0.resize(16, 0);
+    header.u16(ET_EXEC);
+    header.u16(EM_AARCH64);
+    header.u32(EV_CURRENT as u32);
+    header.u64(start); // entry is the start of rtld
+    header.u64(EHDR_SIZE as u64);
+    header.u64(shoff as u64);
+    header.u32(0); // flags
+    header.u16(EHDR_SIZE as u16);
+    header.u16(PHDR_SIZE as u16);
+    header.u16(regions.len() as u16);
+    header.u16(SHDR_SIZE as u16);
+    header.u16(shstrtab_idx as u16 + 1);
+    header.u16(shstrtab_idx as u16);
+    for (region, offset) in regions.iter().zip(&region_offsets) {
+        let addr = start + region.rel_start as u64;
+        let size = region.get_byte_len() as u64;
+        header.u32(PT_LOAD);
+        header.u32(region.permissions);
+        header.u64(*offset as u64);
+        header.u64(addr); // vaddr
+        header.u64(addr); // paddr
+        header.u64(size); // filesz
+        header.u64(size); // memsz
+        header.u64(0x1000);
+    }
+    out.0[..header.0.len()].copy_from_slice(&header.0);
+
+    out.0
+}
+
+/// Name the section of a region by module and permission, like `main.text`
+fn section_name(region: &Region) -> String {
+    let kind = if region.permissions & PF_X != 0 {
+        "text"
+    } else if region.permissions & PF_W != 0 {
+        "data"
+    } else {
+        "rodata"
+    };
+    format!("{}.{}", region.module, kind)
+}
+
+struct SectionHeader {
+    name: u32,
+    sh_type: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+    fn align(&mut self, align: usize) {
+        let len = self.0.len().next_multiple_of(align);
+        self.0.resize(len, 0);
+    }
+    fn section_header(&mut self, header: SectionHeader) {
+        self.u32(header.name);
+        self.u32(header.sh_type);
+        self.u64(header.flags);
+        self.u64(header.addr);
+        self.u64(header.offset);
+        self.u64(header.size);
+        self.u32(header.link);
+        self.u32(header.info);
+        self.u64(header.align);
+        self.u64(header.entsize);
+    }
+}
+
+/// Builder for a string table, starting with the empty string
+struct StringTable(Vec<u8>);
+
+impl Default for StringTable {
+    fn default() -> Self {
+        Self(vec![0])
+    }
+}
+
+impl StringTable {
+    /// Add a string and return its offset
+    fn add(&mut self, s: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        self.0.extend_from_slice(s.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use elf::endian::LittleEndian;
+    use elf::ElfBytes;
+
+    use super::*;
+    use crate::elf::SymbolValue;
+    use crate::module::ModuleType;
+    use crate::test_util::{module_data, START};
+
+    #[test]
+    fn segments_at_physical_addresses() {
+        let memory = Memory::load(START, &module_data()).unwrap();
+        let data = build(&memory);
+        let elf = ElfBytes::<LittleEndian>::minimal_parse(&data).unwrap();
+        assert_eq!(elf.ehdr.e_type, ET_EXEC);
+        assert_eq!(elf.ehdr.e_entry, START);
+        let segments = elf.segments().unwrap().iter().collect::<Vec<_>>();
+        assert_eq!(segments.len(), memory.regions.len());
+        for (segment, region) in segments.iter().zip(&memory.regions) {
+            assert_eq!(segment.p_type, PT_LOAD);
+            assert_eq!(segment.p_vaddr, START + region.rel_start as u64);
+            assert_eq!(segment.p_flags, region.permissions);
+            let bytes = elf.segment_data(segment).unwrap();
+            assert_eq!(bytes.len(), region.get_byte_len() as usize);
+            assert!(bytes.chunks(0x1000).eq(region.iter_pages()));
+        }
+    }
+
+    #[test]
+    fn merged_dynsym() {
+        let module_data = module_data();
+        let mut memory = Memory::load(START, &module_data).unwrap();
+        let magic = SymbolValue {
+            address: START + 0x10,
+            weak: false,
+            protected: false,
+        };
+        memory
+            .dynamic_symbols
+            .magic
+            .insert("mm_fn".to_string(), magic);
+        let data = build(&memory);
+        let elf = ElfBytes::<LittleEndian>::minimal_parse(&data).unwrap();
+        let (symbols, strings) = elf.dynamic_symbol_table().unwrap().unwrap();
+        let find = |name: &str| {
+            let mut found = symbols
+                .iter()
+                .filter(|s| strings.get(s.st_name as usize).unwrap() == name);
+            let symbol = found.next().unwrap();
+            assert!(found.next().is_none(), "{name} is not unique");
+            symbol
+        };
+
+        let nn_main = find("nnMain");
+        let main_text = memory
+            .regions
+            .iter()
+            .position(|r| r.module == ModuleType::Main && r.permissions & PF_X != 0)
+            .unwrap();
+        assert_eq!(nn_main.st_shndx, main_text as u16 + 1);
+        assert_eq!(nn_main.st_symtype(), STT_FUNC);
+        assert_eq!(nn_main.st_value, START + module_data.info.main.start as u64);
+
+        // magic symbols are absolute, and replace the module definitions
+        for name in ["__EX_start", "mm_fn"] {
+            let symbol = find(name);
+            assert_eq!(symbol.st_shndx, SHN_ABS);
+            assert_eq!(symbol.st_symtype(), STT_NOTYPE);
+        }
+        assert_eq!(find("__EX_start").st_value, START);
+        assert_eq!(find("mm_fn").st_value, START + 0x10);
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs::File;
+use std::io::BufWriter;
+
+use serde::Serialize;
+
+use crate::memory::Memory;
+use crate::module::ModuleType;
+
+/// Dependencies between the modules, from the symbols resolved when linking
+#[derive(Debug, Default, Serialize)]
+pub struct ImportGraph {
+    /// Modules that import symbols from another module, in load order
+    pub edges: Vec<ImportEdge>,
+    /// Symbols exported by each module that are not imported by any other module
+    pub unused_exports: BTreeMap<ModuleType, BTreeSet<String>>,
+}
+
+/// The symbols a module imports from another module
+#[derive(Debug, Serialize)]
+pub struct ImportEdge {
+    pub importer: ModuleType,
+    pub exporter: ModuleType,
+    /// The symbols, by the type of relocation that references them
+    pub symbols: BTreeMap<&'static str, BTreeSet<String>>,
+}
+
+impl ImportEdge {
+    /// Number of different symbols imported, of any relocation type
+    pub fn symbol_count(&self) -> usize {
+        self.symbols
+            .values()
+            .flatten()
+            .collect::<BTreeSet<_>>()
+            .len()
+    }
+}
+
+impl ImportGraph {
+    /// Build the graph from the relocations applied to the memory.
+    ///
+    /// Symbols resolved in the same module, magic symbols (including --define),
+    /// and unresolved symbols are not imports
+    pub fn new(memory: &Memory) -> Self {
+        let mut imports = BTreeMap::<(ModuleType, ModuleType), ImportEdge>::new();
+        let mut imported = BTreeSet::new();
+        for relocation in &memory.relocations.applied {
+            let (Some(exporter), Some(symbol)) = (relocation.provider, &relocation.symbol) else {
+                continue;
+            };
+            let importer = relocation.module;
+            if importer == exporter {
+                continue;
+            }
+            imports
+                .entry((importer, exporter))
+                .or_insert_with(|| ImportEdge {
+                    importer,
+                    exporter,
+                    symbols: BTreeMap::new(),
+                })
+                .symbols
+                .entry(relocation.r_type)
+                .or_default()
+                .insert(symbol.clone());
+            imported.insert((exporter, symbol.as_str()));
+        }
+
+        let mut unused_exports = BTreeMap::<ModuleType, BTreeSet<String>>::new();
+        for (module, name, _) in memory.dynamic_symbols.iter() {
+            if !imported.contains(&(module, name)) {
+                unused_exports
+                    .entry(module)
+                    .or_default()
+                    .insert(name.to_string());
+            }
+        }
+
+        Self {
+            edges: imports.into_values().collect(),
+            unused_exports,
+        }
+    }
+
+    /// Print the number of symbols imported between the modules
+    pub fn log_summary(&self) {
+        log::info!("");
+        log::info!("IMPORTER EXPORTER SYMBOLS");
+        for edge in &self.edges {
+            log::info!(
+                "{:8} {:8} {}",
+                edge.importer.to_string(),
+                edge.exporter.to_string(),
+                edge.symbol_count()
+            );
+        }
+        let unused = self.unused_exports.values().map(|s| s.len()).sum::<usize>();
+        log::info!("-- {unused} exported symbols are not imported by another module");
+    }
+
+    /// Render the graph in the DOT format (Graphviz). Each edge is labeled with
+    /// the number of symbols of each relocation type
+    pub fn to_dot(&self) -> anyhow::Result<String> {
+        let mut dot = String::new();
+        writeln!(dot, "digraph imports {{")?;
+        writeln!(dot, "    rankdir=LR;")?;
+        let modules = self
+            .edges
+            .iter()
+            .flat_map(|edge| [edge.importer, edge.exporter])
+            .chain(self.unused_exports.keys().copied())
+            .collect::<BTreeSet<_>>();
+        for module in modules {
+            let unused = self.unused_exports.get(&module).map_or(0, |s| s.len());
+            writeln!(
+                dot,
+                "    \"{module}\" [label=\"{module}\\n{unused} unused exports\"];"
+            )?;
+        }
+        for edge in &self.edges {
+            let label = edge
+                .symbols
+                .iter()
+                .map(|(r_type, symbols)| {
+                    let r_type = r_type.strip_prefix("R_AARCH64_").unwrap_or(r_type);
+                    format!("{r_type}: {}", symbols.len())
+                })
+                .collect::<Vec<_>>()
+                .join("\\n");
+            writeln!(
+                dot,
+                "    \"{}\" -> \"{}\" [label=\"{label}\"];",
+                edge.importer, edge.exporter
+            )?;
+        }
+        writeln!(dot, "}}")?;
+        Ok(dot)
+    }
+
+    /// Write the graph to path, in DOT if the extension is .dot, otherwise in JSON
+    pub fn write(&self, path: &str) -> anyhow::Result<()> {
+        if path.ends_with(".dot") {
+            std::fs::write(path, self.to_dot()?)?;
+        } else {
+            let writer = BufWriter::new(File::create(path)?);
+            serde_json::to_writer(writer, self)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{module_data, START};
+
+    fn graph() -> ImportGraph {
+        ImportGraph::new(&Memory::load(START, &module_data()).unwrap())
+    }
+
+    fn set(symbols: &[&str]) -> BTreeSet<String> {
+        symbols.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn edges_and_unused_exports() {
+        let graph = graph();
+        let edges = graph
+            .edges
+            .iter()
+            .map(|e| (e.importer, e.exporter, e.symbol_count()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            edges,
+            [
+                (ModuleType::Main, ModuleType::Subsdk0, 1),
+                (ModuleType::Main, ModuleType::Sdk, 2),
+                (ModuleType::Subsdk0, ModuleType::Sdk, 1),
+                (ModuleType::Sdk, ModuleType::Main, 1),
+            ]
+        );
+        let main_sdk = &graph.edges[1];
+        assert_eq!(
+            main_sdk.symbols.keys().copied().collect::<Vec<_>>(),
+            [
+                "R_AARCH64_ABS64",
+                "R_AARCH64_GLOB_DAT",
+                "R_AARCH64_JUMP_SLOT"
+            ]
+        );
+        assert_eq!(
+            main_sdk.symbols["R_AARCH64_JUMP_SLOT"],
+            set(&["nn_fs_fn", "nn_os_fn"])
+        );
+
+        // the unresolved weak reference in subsdk0 is not an import
+        assert_eq!(
+            graph.unused_exports,
+            BTreeMap::from([
+                (ModuleType::None, set(&["__rtld_start", "rtld_fn"])),
+                (ModuleType::Main, set(&["main_fn", "main_vt"])),
+                (ModuleType::Subsdk0, set(&["mm_unused"])),
+                (ModuleType::Sdk, set(&["nnsdkInit", "sdk_unused"])),
+            ])
+        );
+    }
+
+    #[test]
+    fn dot() {
+        let dot = graph().to_dot().unwrap();
+        let lines = dot.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "digraph imports {");
+        assert_eq!(lines.last(), Some(&"}"));
+        assert!(lines.contains(&r#"    "sdk" [label="sdk\n2 unused exports"];"#));
+        assert!(lines
+            .contains(&r#"    "main" -> "sdk" [label="ABS64: 2\nGLOB_DAT: 2\nJUMP_SLOT: 2"];"#));
+        assert!(lines
+            .contains(&r#"    "sdk" -> "main" [label="ABS64: 1\nGLOB_DAT: 1\nJUMP_SLOT: 1"];"#));
+    }
+}
@@ -0,0 +1,177 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use anyhow::bail;
+use serde::Serialize;
+
+use crate::binary::ModuleBinary;
+use crate::memory::Memory;
+use crate::module::ModuleType;
+
+/// Entry point and static initializers of a module, as physical addresses after relocation.
+///
+/// DT_INIT and the .init_array are kept separate, since they are called by different code.
+/// Based on oss-rtld (<https://github.com/Atmosphere-NX/oss-rtld>), rtld only calls DT_INIT
+/// of each module after all modules are relocated, and DT_INIT (`_init` from the SDK runtime)
+/// calls the functions in the .init_array. This is not verified against the retail rtld,
+/// including the order the modules are initialized in
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleInit {
+    pub module: ModuleType,
+    /// The entry point, which is the start of the module (.text starts with
+    /// a branch to the module's startup code)
+    pub entry: u64,
+    /// DT_INIT, which is called by rtld
+    pub init: Option<u64>,
+    /// The functions in .init_array, in array order. These are called by DT_INIT, not rtld.
+    /// Entries of 0 or -1 are skipped
+    pub init_array: Vec<u64>,
+    /// DT_FINI
+    pub fini: Option<u64>,
+    /// The functions in .fini_array, from the end of the array (the order
+    /// they are called in by DT_FINI)
+    pub fini_array: Vec<u64>,
+}
+
+impl ModuleInit {
+    /// Read the entry point and initializers of the module from the relocated memory
+    pub fn read(
+        memory: &Memory,
+        module: ModuleType,
+        binary: &ModuleBinary,
+    ) -> anyhow::Result<Self> {
+        let module_start =
+            memory.get_program_start() + memory.get_modules().get(module).start as u64;
+        let mut fini_array = read_array(memory, module, binary.fini_array)?;
+        fini_array.reverse();
+        Ok(Self {
+            module,
+            entry: module_start,
+            init: binary.init.map(|vaddr| module_start + vaddr as u64),
+            init_array: read_array(memory, module, binary.init_array)?,
+            fini: binary.fini.map(|vaddr| module_start + vaddr as u64),
+            fini_array,
+        })
+    }
+}
+
+/// The entry points and static initializers of all modules, for running global
+/// construction in a simulator. The blueflame image has no place for these,
+/// so they are written to a separate file
+#[derive(Debug, Serialize)]
+pub struct ProgramInit<'a> {
+    /// Physical address of the program start
+    pub program_start: u64,
+    /// The modules in load order
+    pub modules: &'a [ModuleInit],
+}
+
+impl<'a> ProgramInit<'a> {
+    pub fn new(memory: &'a Memory) -> Self {
+        Self {
+            program_start: memory.get_program_start(),
+            modules: &memory.init,
+        }
+    }
+
+    /// Write to path in JSON
+    pub fn write(&self, path: &str) -> anyhow::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+}
+
+/// Read the relocated function pointers in an array of the module,
+/// at (virtual address, size in bytes)
+fn read_array(
+    memory: &Memory,
+    module: ModuleType,
+    (vaddr, size): (u32, u32),
+) -> anyhow::Result<Vec<u64>> {
+    if size % 8 != 0 {
+        bail!("unexpected size of function pointer array in {module}: 0x{size:x}");
+    }
+    let start = memory.get_modules().get(module).start + vaddr;
+    let mut functions = Vec::with_capacity(size as usize / 8);
+    for offset in (start..start + size).step_by(8) {
+        let function = memory.read_u64(offset)?;
+        if function != 0 && function != u64::MAX {
+            functions.push(function);
+        }
+    }
+    Ok(functions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{module_data, test_modules, TempDir, TestModule, START};
+
+    #[test]
+    fn read_initializers() {
+        let memory = Memory::load(START, &module_data()).unwrap();
+        let modules = memory.init.iter().map(|m| m.module).collect::<Vec<_>>();
+        assert_eq!(
+            modules,
+            [
+                ModuleType::None,
+                ModuleType::Main,
+                ModuleType::Subsdk0,
+                ModuleType::Sdk
+            ]
+        );
+        let [rtld, main, _, sdk] = test_modules();
+        let address = |module, test_module: &TestModule, name| {
+            START
+                + memory.get_modules().get(module).start as u64
+                + test_module.export_offset(name) as u64
+        };
+
+        let init = &memory.init[0];
+        assert_eq!(init.entry, address(ModuleType::None, &rtld, "__rtld_start"));
+        assert_eq!(init.init, None);
+        assert!(init.init_array.is_empty());
+
+        let init = &memory.init[1];
+        let main_address = |name| address(ModuleType::Main, &main, name);
+        assert_eq!(init.entry, main_address("nnMain"));
+        assert_eq!(init.init, Some(main_address("main_fn")));
+        // 0 and -1 are skipped
+        assert_eq!(
+            init.init_array,
+            [main_address("main_fn"), main_address("nnMain")]
+        );
+        assert_eq!(init.fini, Some(main_address("main_vt")));
+        // in reverse order
+        assert_eq!(
+            init.fini_array,
+            [main_address("main_vt"), main_address("main_fn")]
+        );
+
+        let init = &memory.init[3];
+        let nnsdk_init = address(ModuleType::Sdk, &sdk, "nnsdkInit");
+        assert_eq!(init.init, Some(nnsdk_init));
+        assert_eq!(init.init_array, [nnsdk_init]);
+        assert_eq!(init.fini, None);
+        assert!(init.fini_array.is_empty());
+    }
+
+    #[test]
+    fn write_program_init() {
+        let memory = Memory::load(START, &module_data()).unwrap();
+        let dir = TempDir::new();
+        let path = dir.path().join("init.json");
+        ProgramInit::new(&memory)
+            .write(path.to_str().unwrap())
+            .unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(json["program_start"], START);
+        let modules = json["modules"].as_array().unwrap();
+        assert_eq!(modules.len(), 4);
+        assert_eq!(modules[1]["module"], "main");
+        assert_eq!(modules[1]["init_array"].as_array().unwrap().len(), 2);
+        assert_eq!(modules[2]["init"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+
+use blueflame::program::{self, Program, Section};
+
+use uking_relocate::memory::perm_str;
+
+use crate::cli::InspectArgs;
+
+const PAGE_SIZE: u32 = 0x1000;
+
+/// Print the summary of an image, and the differences with another image if --diff is specified
+pub fn run(args: &InspectArgs) -> anyhow::Result<()> {
+    let program = read_program(&args.image)?;
+    print_summary(&program);
+
+    let Some(other_path) = &args.diff else {
+        return Ok(());
+    };
+    let other = read_program(other_path)?;
+    println!();
+    println!("-- comparing {} with {}", args.image, other_path);
+    print_diff(&program, &other);
+    Ok(())
+}
+
+fn read_program(path: &str) -> anyhow::Result<Program> {
+    println!("-- reading {path}");
+    let data = std::fs::read(path)?;
+    Ok(program::unpack(&data)?)
+}
+
+fn print_summary(program: &Program) {
+    let start = program.program_start;
+    println!();
+    println!("game version:  {:?}", program.ver);
+    println!("program start: 0x{start:016x}");
+    println!("program size:  0x{:08x}", program.program_size);
+    println!();
+    println!("MODULE   START      ADDRESS");
+    for module in &program.modules {
+        println!(
+            "{:8} 0x{:08x} 0x{:016x}",
+            module.name,
+            module.rel_start,
+            start + module.rel_start as u64
+        );
+    }
+    println!();
+    println!("SECTION  START      END        PERM");
+    for (i, section) in program.sections.iter().enumerate() {
+        // sections are contiguous, each one ends where the next one starts
+        let end = program
+            .sections
+            .get(i + 1)
+            .map(|next| next.rel_start)
+            .unwrap_or(program.program_size);
+        println!(
+            "{:8} 0x{:08x} 0x{:08x} {}",
+            module_name(program, section.rel_start),
+            section.rel_start,
+            end,
+            perm_str(section.permissions)
+        );
+    }
+    println!();
+    println!("SEGMENT  START      END        SIZE       PERM");
+    let mut total = 0;
+    for segment in &program.segments {
+        let size = segment.data.len() as u32;
+        total += size;
+        println!(
+            "{:8} 0x{:08x} 0x{:08x} 0x{:08x} {}",
+            module_name(program, segment.rel_start),
+            segment.rel_start,
+            segment.rel_start + size,
+            size,
+            section_perm(program, segment.rel_start)
+        );
+    }
+    println!("total segment size: 0x{total:08x}");
+    println!();
+    println!("DATA                 SIZE");
+    for data in &program.data {
+        println!("{:20} 0x{:08x}", format!("{:?}", data.id), data.bytes.len());
+    }
+}
+
+/// A run of consecutive pages that are different in the same way
+#[derive(Debug, PartialEq)]
+struct DiffRange {
+    start: u32,
+    end: u32,
+    kind: &'static str,
+}
+
+fn print_diff(a: &Program, b: &Program) {
+    let mut same = true;
+    if a.ver != b.ver {
+        println!("game version: {:?} != {:?}", a.ver, b.ver);
+        same = false;
+    }
+    if a.program_start != b.program_start {
+        // relocated pointers will also be different
+        println!(
+            "program start: 0x{:016x} != 0x{:016x}",
+            a.program_start, b.program_start
+        );
+        same = false;
+    }
+    if a.program_size != b.program_size {
+        println!(
+            "program size: 0x{:08x} != 0x{:08x}",
+            a.program_size, b.program_size
+        );
+        same = false;
+    }
+    if a.modules != b.modules {
+        println!("modules are different");
+        same = false;
+    }
+    if a.sections != b.sections {
+        println!("sections are different");
+        same = false;
+    }
+    for data in &a.data {
+        match b.data.iter().find(|other| other.id == data.id) {
+            None => println!("data {:?}: only in the first image", data.id),
+            Some(other) if other.bytes != data.bytes => println!(
+                "data {:?}: different (0x{:x} bytes != 0x{:x} bytes)",
+                data.id,
+                data.bytes.len(),
+                other.bytes.len()
+            ),
+            Some(_) => continue,
+        }
+        same = false;
+    }
+    for data in &b.data {
+        if !a.data.iter().any(|other| other.id == data.id) {
+            println!("data {:?}: only in the second image", data.id);
+            same = false;
+        }
+    }
+
+    let ranges = diff_pages(a, b);
+    if !ranges.is_empty() {
+        same = false;
+        println!();
+        println!("MODULE   START      END        PERM PAGES  DIFFERENCE");
+        for range in &ranges {
+            println!(
+                "{:8} 0x{:08x} 0x{:08x} {}  {:<6} {}",
+                module_name(a, range.start),
+                range.start,
+                range.end,
+                section_perm(a, range.start),
+                (range.end - range.start) / PAGE_SIZE,
+                range.kind
+            );
+        }
+        let count = ranges
+            .iter()
+            .map(|range| (range.end - range.start) / PAGE_SIZE)
+            .sum::<u32>();
+        println!("{count} pages are different");
+    }
+
+    if same {
+        println!("the images are the same");
+    }
+}
+
+/// Compare the pages of the segments, merging consecutive pages that are
+/// different in the same way
+fn diff_pages(a: &Program, b: &Program) -> Vec<DiffRange> {
+    let pages_a = collect_pages(a);
+    let pages_b = collect_pages(b);
+    let mut page_starts = pages_a.keys().chain(pages_b.keys()).collect::<Vec<_>>();
+    page_starts.sort();
+    page_starts.dedup();
+
+    let mut ranges: Vec<DiffRange> = Vec::new();
+    for &page in page_starts {
+        let kind = match (pages_a.get(&page), pages_b.get(&page)) {
+            (Some(x), Some(y)) if x == y => continue,
+            (Some(_), Some(_)) => "different",
+            (Some(_), None) => "only in the first image",
+            _ => "only in the second image",
+        };
+        // merge with the previous range if it's the same kind in the same module and section
+        if let Some(last) = ranges.last_mut() {
+            if last.end == page
+                && last.kind == kind
+                && module_name(a, last.start) == module_name(a, page)
+                && find_section(a, last.start) == find_section(a, page)
+            {
+                last.end += PAGE_SIZE;
+                continue;
+            }
+        }
+        ranges.push(DiffRange {
+            start: page,
+            end: page + PAGE_SIZE,
+            kind,
+        });
+    }
+    ranges
+}
+
+/// Split the segments into pages, keyed by the start of the page relative to the program start
+fn collect_pages(program: &Program) -> BTreeMap<u32, Vec<u8>> {
+    let mut pages = BTreeMap::new();
+    for segment in &program.segments {
+        let mut offset = 0;
+        while offset < segment.data.len() as u32 {
+            let address = segment.rel_start + offset;
+            let page_start = address / PAGE_SIZE * PAGE_SIZE;
+            let len = (page_start + PAGE_SIZE - address).min(segment.data.len() as u32 - offset);
+            let page = pages
+                .entry(page_start)
+                .or_insert_with(|| vec![0; PAGE_SIZE as usize]);
+            let page_offset = (address - page_start) as usize;
+            let data = &segment.data[offset as usize..(offset + len) as usize];
+            page[page_offset..page_offset + len as usize].copy_from_slice(data);
+            offset += len;
+        }
+    }
+    pages
+}
+
+/// Get the name of the module that contains the address (relative to program start)
+fn module_name(program: &Program, rel_address: u32) -> &str {
+    program
+        .modules
+        .iter()
+        .rev()
+        .find(|module| module.rel_start <= rel_address)
+        .map(|module| module.name.as_str())
+        .unwrap_or("?")
+}
+
+/// Get the section that contains the address (relative to program start)
+fn find_section(program: &Program, rel_address: u32) -> Option<&Section> {
+    program
+        .sections
+        .iter()
+        .rev()
+        .find(|section| section.rel_start <= rel_address)
+}
+
+/// Get the permissions of the section that contains the address (relative to program start)
+fn section_perm(program: &Program, rel_address: u32) -> String {
+    find_section(program, rel_address)
+        .map(|section| perm_str(section.permissions))
+        .unwrap_or_else(|| "???".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use blueflame::env::GameVer;
+
+    use super::*;
+
+    const R: u32 = 4;
+    const RW: u32 = 6;
+
+    /// Two modules at 0x0 and 0x4000, each with a read-only and a read-write section
+    fn program(segments: &[(u32, Vec<u8>)]) -> Program {
+        let mut builder = program::builder(GameVer::X150, 0x1234500000, 0x8000)
+            .add_module("main", 0)
+            .add_module("sdk", 0x4000)
+            .done_with_modules()
+            .add_section(0, R)
+            .add_section(0x2000, RW)
+            .add_section(0x4000, R)
+            .add_section(0x6000, RW)
+            .done_with_sections();
+        for (rel_start, data) in segments {
+            builder = builder.add_segment(*rel_start, data.clone());
+        }
+        builder.done()
+    }
+
+    fn range(start: u32, end: u32, kind: &'static str) -> DiffRange {
+        DiffRange { start, end, kind }
+    }
+
+    #[test]
+    fn collect_pages_splits_segments() {
+        let program = program(&[(0x800, vec![1; 0x1000]), (0x2000, vec![2; 0x10])]);
+        let pages = collect_pages(&program);
+        assert_eq!(
+            pages.keys().copied().collect::<Vec<_>>(),
+            [0, 0x1000, 0x2000]
+        );
+        // the parts not in a segment are zero
+        assert_eq!(pages[&0][..0x800], [0; 0x800]);
+        assert_eq!(pages[&0][0x800..], [1; 0x800]);
+        assert_eq!(pages[&0x1000][..0x800], [1; 0x800]);
+        assert_eq!(pages[&0x1000][0x800..], [0; 0x800]);
+        assert_eq!(pages[&0x2000][..0x10], [2; 0x10]);
+    }
+
+    #[test]
+    fn module_and_section_of_address() {
+        let program = program(&[]);
+        assert_eq!(module_name(&program, 0), "main");
+        assert_eq!(module_name(&program, 0x3fff), "main");
+        assert_eq!(module_name(&program, 0x4000), "sdk");
+        assert_eq!(section_perm(&program, 0x1fff), "r--");
+        assert_eq!(section_perm(&program, 0x2000), "rw-");
+    }
+
+    #[test]
+    fn diff_pages_merges_ranges() {
+        let a = program(&[(0, vec![1; 0x8000])]);
+        assert!(diff_pages(&a, &a).is_empty());
+
+        let mut data = vec![1; 0x7000];
+        // different pages across the section and module boundaries
+        data[0x1000..0x5000].fill(2);
+        let b = program(&[(0, data)]);
+        assert_eq!(
+            diff_pages(&a, &b),
+            [
+                range(0x1000, 0x2000, "different"),
+                range(0x2000, 0x4000, "different"),
+                range(0x4000, 0x5000, "different"),
+                range(0x7000, 0x8000, "only in the first image"),
+            ]
+        );
+        assert_eq!(
+            diff_pages(&b, &a)[3],
+            range(0x7000, 0x8000, "only in the second image")
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! Simulates loading the BOTW executable and some data into memory,
+//! producing a program image for BlueFlame.
+//!
+//! The typical flow is:
+//! 1. Load the modules with [`ModuleData::load`] (from a dump), or
+//!    [`ModuleData::from_files`] with [`ModuleFile::from_bytes`] (from memory).
+//!    This only needs to be done once.
+//! 2. Relocate the modules at a start address with [`relocate`].
+//! 3. Build the [`Program`] with [`build_program`], and pack it with [`pack_program`].
+//!
+//! [`build`] does steps 2 and 3 with [`ProgramOptions`]. To build images at
+//! different starts, repeat steps 2 and 3 with the same [`ModuleData`] (see [`random_starts`]).
+//!
+//! Progress is logged through the `log` facade.
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use anyhow::bail;
+use blueflame::env::{DataId, GameVer};
+use blueflame::program::{self, Program};
+
+pub mod address;
+pub mod aslr;
+pub mod binary;
+pub mod build_id;
+pub mod decomp;
+pub mod define;
+pub mod dynamic;
+pub mod elf;
+pub mod flat_elf;
+pub mod import_graph;
+pub mod init;
+pub mod memory;
+pub mod module;
+pub mod nso;
+pub mod patch;
+pub mod ram_dump;
+pub mod raw_dump;
+pub mod region;
+pub mod reloc_report;
+pub mod romfs;
+pub mod symbol_map;
+#[cfg(test)]
+mod test_util;
+pub mod verify;
+
+pub use aslr::RandomStart;
+pub use decomp::DecompFunction;
+pub use memory::Memory;
+pub use module::{ModuleData, ModuleFile};
+pub use region::{parse_region, RegionArg};
+pub use romfs::Romfs;
+
+/// Options for building a program image with [`build`]
+#[derive(Debug, Clone, Default)]
+pub struct ProgramOptions {
+    /// The physical start address of the program, see [`check_start`]
+    pub start: u64,
+    /// Regions of memory to keep. If empty, everything is kept
+    pub regions: Vec<RegionArg>,
+    /// Functions from uking_functions.csv, for resolving symbols in the regions
+    pub functions: Option<BTreeMap<String, DecompFunction>>,
+    /// Keep all-zero pages in the image, which are left out by default
+    pub keep_zero_pages: bool,
+}
+
+/// Relocate the modules and build the program image.
+///
+/// romfs_data is the data to include in the image, usually from [`Romfs::load_manifest`]
+pub fn build(
+    data: &ModuleData,
+    options: &ProgramOptions,
+    romfs_data: Vec<(DataId, Vec<u8>)>,
+) -> anyhow::Result<Program> {
+    let memory = relocate(data, options.start)?;
+    let regions = resolve_regions(&memory, &options.regions, options.functions.as_ref())?;
+    build_program(
+        data,
+        &memory,
+        &regions,
+        !options.keep_zero_pages,
+        romfs_data,
+    )
+}
+
+/// Check the program start is valid.
+///
+/// The upper 24 bits and lower 20 bits must be zero
+pub fn check_start(start: u64) -> anyhow::Result<()> {
+    if start & 0xFFFFFF00000FFFFF != 0 {
+        bail!("invalid program start (see readme)");
+    }
+    Ok(())
+}
+
+/// Pick count different random starts for the modules from the seed, like ASLR.
+///
+/// See [`RandomStart`]
+pub fn random_starts(data: &ModuleData, seed: u64, count: usize) -> anyhow::Result<Vec<u64>> {
+    // sdk is the last module loaded
+    RandomStart::new(seed).next_starts(data.info.sdk.end, count)
+}
+
+/// Load the modules into memory at start, and apply the relocations
+pub fn relocate(data: &ModuleData, start: u64) -> anyhow::Result<Memory> {
+    check_start(start)?;
+    Memory::load(start, data)
+}
+
+/// Resolve the regions to ranges relative to the program start
+pub fn resolve_regions(
+    memory: &Memory,
+    regions: &[RegionArg],
+    functions: Option<&BTreeMap<String, DecompFunction>>,
+) -> anyhow::Result<Vec<Range<u32>>> {
+    if regions.is_empty() {
+        return Ok(Vec::new());
+    }
+    log::info!("-- resolving regions...");
+    let mut ranges = Vec::with_capacity(regions.len());
+    for region in regions {
+        let range = region.resolve(memory, functions)?;
+        log::info!("0x{:08x}-0x{:08x} {}", range.start, range.end, region.text);
+        ranges.push(range);
+    }
+    Ok(ranges)
+}
+
+/// Build the BlueFlame program image from the relocated memory.
+///
+/// regions are relative to the program start (see [`resolve_regions`]).
+/// All-zero pages are left out if drop_zero_pages is true.
+/// ActorInfo in romfs_data is validated before it is added
+pub fn build_program(
+    data: &ModuleData,
+    memory: &Memory,
+    regions: &[Range<u32>],
+    drop_zero_pages: bool,
+    romfs_data: Vec<(DataId, Vec<u8>)>,
+) -> anyhow::Result<Program> {
+    let game_ver = if data.info.is_1_6_0 {
+        GameVer::X160
+    } else {
+        GameVer::X150
+    };
+
+    let mut builder = program::builder(
+        game_ver,
+        memory.get_program_start(),
+        memory.get_program_size(),
+    );
+    for (module, info) in data.info.iter() {
+        builder = builder.add_module(module.to_string(), info.start);
+    }
+    let mut builder = builder.done_with_modules();
+    for section in &memory.regions {
+        builder = builder.add_section(section.rel_start, section.permissions);
+    }
+    let mut builder = builder.done_with_sections();
+    builder = memory.add_program_segments(regions, drop_zero_pages, builder);
+    for (id, data) in romfs_data {
+        if matches!(id, DataId::ActorInfoByml) {
+            romfs::validate_actor_info(&data)?;
+        }
+        builder = builder.add_data(id, data);
+    }
+    Ok(builder.done())
+}
+
+/// Pack the program image, and check that it unpacks to the same program
+pub fn pack_program(program: &Program) -> anyhow::Result<Vec<u8>> {
+    log::info!("-- packing the program...");
+    let data = program::pack(program)?;
+    log::info!("packed size: {} bytes", data.len());
+    log::info!("-- verifying the pack...");
+    let program2 = program::unpack(&data)?;
+    if *program != program2 {
+        bail!("the unpacked program does not match the original program");
+    }
+    Ok(data)
+}
+
+/// Check if the string is a hexadecimal number, with 0x. Without the prefix,
+/// symbols like `add` could be taken as numbers
+pub fn is_hex_number(arg: &str) -> bool {
+    let Some(digits) = arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) else {
+        return false;
+    };
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Parse a hexadecimal u64 that must have the leading 0x, like the offsets
+/// in --regions and --define
+pub fn parse_hex_number(arg: &str) -> anyhow::Result<u64> {
+    if !is_hex_number(arg) {
+        bail!("{arg} is not a number, numbers need the 0x prefix");
+    }
+    parse_u64(arg)
+}
+
+/// Parse a hexadecimal u64, the leading 0x is optional
+pub fn parse_u64(arg: &str) -> anyhow::Result<u64> {
+    let arg = arg.trim_start_matches(['0', 'x', 'X']);
+    if arg.is_empty() {
+        return Ok(0);
+    }
+    Ok(u64::from_str_radix(arg, 16)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{module_data, START};
+
+    fn segment_size(program: &Program) -> usize {
+        program.segments.iter().map(|s| s.data.len()).sum()
+    }
+
+    #[test]
+    fn pack_without_zero_pages() {
+        let data = module_data();
+        let memory = relocate(&data, START).unwrap();
+        let all = build_program(&data, &memory, &[], false, Vec::new()).unwrap();
+        let program = build_program(&data, &memory, &[], true, Vec::new()).unwrap();
+        assert!(segment_size(&program) < segment_size(&all));
+        for segment in &program.segments {
+            assert!(segment
+                .data
+                .chunks(0x1000)
+                .all(|p| p.iter().any(|b| *b != 0)));
+        }
+
+        let packed = pack_program(&program).unwrap();
+        assert_eq!(program::unpack(&packed).unwrap(), program);
+    }
+
+    #[test]
+    fn build_drops_zero_pages_by_default() {
+        let data = module_data();
+        let options = ProgramOptions {
+            start: START,
+            ..Default::default()
+        };
+        let program = build(&data, &options, Vec::new()).unwrap();
+        let memory = relocate(&data, START).unwrap();
+        assert_eq!(
+            program,
+            build_program(&data, &memory, &[], true, Vec::new()).unwrap()
+        );
+        let options = ProgramOptions {
+            keep_zero_pages: true,
+            ..options
+        };
+        let program = build(&data, &options, Vec::new()).unwrap();
+        assert_eq!(segment_size(&program), memory.get_program_size() as usize);
+    }
+}
@@ -1,22 +1,24 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
 use std::process::ExitCode;
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
+use blueflame::env::DataId;
 use clap::Parser;
 
-use blueflame::env::{DataId, GameVer};
-use blueflame::program;
-//use blueflame::program::{self, ProgramBuilder};
+use uking_relocate::import_graph::ImportGraph;
+use uking_relocate::init::ProgramInit;
+use uking_relocate::{decomp, define, flat_elf, patch, raw_dump, reloc_report, symbol_map, verify};
+use uking_relocate::{DecompFunction, ModuleData, Romfs};
 
+mod addr;
 mod cli;
-mod elf;
-mod memory;
-mod module;
-mod romfs;
+mod derelocate;
+mod inspect;
 
-use cli::Cli;
-use memory::Memory;
-use module::ModuleData;
-use romfs::Romfs;
+use cli::{BuildArgs, Cli, Command, OutputFormat};
 
 fn main() -> ExitCode {
     if let Err(e) = main_internal() {
@@ -27,56 +29,251 @@ fn main() -> ExitCode {
     }
 }
 
+/// Print the logs from the library to stdout
+struct StdoutLogger {
+    /// The most verbose level to print
+    level: log::LevelFilter,
+}
+
+impl StdoutLogger {
+    /// Set the logger for the process, printing logs up to level
+    fn init(level: log::LevelFilter) {
+        let logger = Box::leak(Box::new(Self { level }));
+        log::set_logger(logger).expect("failed to set logger");
+        log::set_max_level(level);
+    }
+}
+
+impl log::Log for StdoutLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= log::Level::Warn {
+            println!("WARNING - {}", record.args());
+        } else {
+            println!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
 fn main_internal() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    let level = match &cli.command {
+        // only print warnings when loading, so the output is just the addresses
+        Some(Command::Addr(_)) => log::LevelFilter::Warn,
+        _ => log::LevelFilter::Info,
+    };
+    StdoutLogger::init(level);
+    match &cli.command {
+        Some(Command::Inspect(args)) => return inspect::run(args),
+        Some(Command::Addr(args)) => return addr::run(args),
+        Some(Command::Derelocate(args)) => return derelocate::run(args),
+        None => {}
+    }
+    let cli = cli
+        .build
+        .ok_or_else(|| anyhow!("missing arguments, see --help"))?;
 
-    if cli.start & 0xFFFFFF00000FFFFF != 0 {
-        bail!("invalid program start (see readme)");
+    if let Some(start) = cli.start {
+        uking_relocate::check_start(start)?;
     }
 
     // load the files
-    let data = ModuleData::load(&cli.sdk_elf)?;
-    let romfs_path = cli.romfs.as_ref().map(|s| s.as_ref());
-    let romfs = Romfs::find_paths(&cli.sdk_elf, romfs_path)?;
-    let game_ver = if data.info.is_1_6_0 {
-        GameVer::X160
-    } else {
-        GameVer::X150
+    let mut data = ModuleData::load(&cli.sdk_elf, &cli.module, cli.force)?;
+    for path in &cli.patch {
+        println!("-- reading patches: {path}");
+        let patches = patch::read_patches(path, &data)?;
+        data.add_patches(patches)?;
+    }
+    if let Some(path) = &cli.defines {
+        println!("-- reading defines: {path}");
+        data.defines = define::read_defines_file(path)?;
+    }
+    data.defines.extend(cli.define.iter().cloned());
+
+    let functions = match &cli.functions_csv {
+        Some(path) => Some(decomp::read_functions_csv(path)?),
+        None => None,
+    };
+    let data_symbols = match &cli.data_symbols_csv {
+        Some(path) => Some(decomp::read_data_symbols_csv(path)?),
+        None => None,
+    };
+    let romfs_data = match cli.format {
+        OutputFormat::Bfi => {
+            let romfs = Romfs::find_layers(&cli.sdk_elf, &cli.romfs)?;
+            Some(romfs.load_manifest()?)
+        }
+        _ => None,
     };
 
-    // make the memory
-    let memory = Memory::load(cli.start, &data)?;
-    // build the program image
-    let info = &data.info;
-    let mut builder = program::builder(game_ver, cli.start, memory.get_program_size())
-        .add_module("rtld", info.rtld.start)
-        .add_module("main", info.main.start)
-        .add_module("subsdk0", info.subsdk0.start)
-        .add_module("sdk", info.sdk.start)
-        .done_with_modules();
-    for section in &memory.regions {
-        builder = builder.add_section(section.rel_start, section.permissions);
-    }
-    let mut builder = builder.done_with_sections();
-    builder = memory.add_program_segments(&cli.regions, builder);
-
-    let program = builder
-        .add_data(DataId::ActorInfoByml, romfs.load_actor_info_data()?)
-        .done();
-
-    println!("-- packing the program...");
-    let data = program::pack(&program)?;
-    println!("packed size: {} bytes", data.len());
-    println!("-- verifying the pack...");
-    let program2 = program::unpack(&data)?;
-    if program != program2 {
-        bail!("the unpacked program does not match the original program");
-    }
-    println!("-- writing output file: {}", cli.output);
-
-    std::fs::write(cli.output, data)?;
+    let starts = match (cli.start, cli.random_start) {
+        (Some(start), _) => vec![start],
+        (None, Some(seed)) => {
+            let count = cli.batch.unwrap_or(1) as usize;
+            let starts = uking_relocate::random_starts(&data, seed, count)?;
+            println!("-- picked {count} random starts from seed {seed}");
+            starts
+        }
+        (None, None) => unreachable!("--start or --random-start is required"),
+    };
+
+    for (i, start) in starts.into_iter().enumerate() {
+        let index = cli.batch.map(|_| i);
+        if index.is_some() {
+            println!("-- [batch {i}] building the image at 0x{start:016x}...");
+        } else {
+            println!("-- building the image at 0x{start:016x}...");
+        }
+        build_image(
+            &cli,
+            &data,
+            start,
+            index,
+            functions.as_ref(),
+            data_symbols.as_deref(),
+            romfs_data.as_ref(),
+        )?;
+    }
 
     println!("done!");
 
     Ok(())
 }
+
+/// Relocate the modules at start and write the outputs. index is added
+/// to the output paths in batch mode
+fn build_image(
+    cli: &BuildArgs,
+    data: &ModuleData,
+    start: u64,
+    index: Option<usize>,
+    functions: Option<&BTreeMap<String, DecompFunction>>,
+    data_symbols: Option<&[(u64, String)]>,
+    romfs_data: Option<&Vec<(DataId, Vec<u8>)>>,
+) -> anyhow::Result<()> {
+    // make the memory
+    let memory = uking_relocate::relocate(data, start)?;
+    if let Some(path) = &cli.symbols_out {
+        let path = indexed_path(path, index);
+        println!("-- writing symbol map: {path}");
+        symbol_map::write(&path, &memory)?;
+    }
+    if let Some(path) = &cli.reloc_report {
+        let path = indexed_path(path, index);
+        println!("-- writing relocation report: {path}");
+        let writer = BufWriter::new(File::create(&path)?);
+        serde_json::to_writer(writer, &memory.relocations)?;
+    }
+    if let Some(path) = &cli.import_graph {
+        let path = indexed_path(path, index);
+        let graph = ImportGraph::new(&memory);
+        graph.log_summary();
+        println!("-- writing import graph: {path}");
+        graph.write(&path)?;
+    }
+    if let Some(path) = &cli.init_out {
+        let path = indexed_path(path, index);
+        println!("-- writing entry points and initializers: {path}");
+        ProgramInit::new(&memory).write(&path)?;
+    }
+    if cli.strict {
+        println!("-- checking unresolved symbols...");
+        let allowlist = match &cli.allowlist {
+            Some(path) => reloc_report::read_allowlist(path)?,
+            None => Default::default(),
+        };
+        memory.relocations.check_allowlist(&allowlist)?;
+    }
+    if cli.verify {
+        println!("-- verifying the relocated program...");
+        let report = verify::verify(&memory, functions, data_symbols);
+        report.log_summary();
+        if let Some(path) = &cli.verify_report {
+            let path = indexed_path(path, index);
+            println!("-- writing verify report: {path}");
+            report.write(&path)?;
+        }
+        if !report.failures.is_empty() {
+            bail!("{} verify checks failed", report.failures.len());
+        }
+    }
+
+    let regions = uking_relocate::resolve_regions(&memory, &cli.regions, functions)?;
+    let output_path = indexed_path(
+        cli.output.as_deref().unwrap_or(cli.format.default_output()),
+        index,
+    );
+
+    match cli.format {
+        OutputFormat::Bfi => {
+            let romfs_data = romfs_data.cloned().unwrap_or_default();
+            let program = uking_relocate::build_program(
+                data,
+                &memory,
+                &regions,
+                !cli.keep_zero_pages,
+                romfs_data,
+            )?;
+            let data = uking_relocate::pack_program(&program)?;
+            println!("-- writing output file: {output_path}");
+            std::fs::write(output_path, data)?;
+        }
+        OutputFormat::Elf => {
+            println!("-- building the ELF image...");
+            let data = flat_elf::build(&memory);
+            println!("ELF size: {} bytes", data.len());
+            println!("-- writing output file: {output_path}");
+            std::fs::write(output_path, data)?;
+        }
+        OutputFormat::Raw => {
+            println!("-- writing output directory: {output_path}");
+            raw_dump::write(&output_path, &memory, &regions, !cli.keep_zero_pages)?;
+        }
+    }
+    Ok(())
+}
+
+/// Add the index to the path before the extension, like program-0.bfi
+fn indexed_path(path: &str, index: Option<usize>) -> String {
+    let Some(index) = index else {
+        return path.to_string();
+    };
+    let path = Path::new(path);
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let file_name = match path.extension() {
+        Some(ext) => format!("{stem}-{index}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-{index}"),
+    };
+    path.with_file_name(file_name).to_string_lossy().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indexed_path_adds_index() {
+        assert_eq!(indexed_path("program.bfi", None), "program.bfi");
+        assert_eq!(indexed_path("program.bfi", Some(0)), "program-0.bfi");
+        assert_eq!(
+            indexed_path("out/program.bfi", Some(12)),
+            "out/program-12.bfi"
+        );
+        assert_eq!(indexed_path("out/program", Some(1)), "out/program-1");
+        assert_eq!(
+            indexed_path("out.d/layout.json", Some(2)),
+            "out.d/layout-2.json"
+        );
+    }
+}
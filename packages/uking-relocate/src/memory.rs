@@ -1,16 +1,24 @@
 use std::collections::BTreeSet;
+use std::ops::Range;
 
 use anyhow::{anyhow, bail};
 use elf::abi::{
-    PT_LOAD, R_AARCH64_ABS64, R_AARCH64_GLOB_DAT, R_AARCH64_JUMP_SLOT, R_AARCH64_RELATIVE,
+    R_AARCH64_ABS64, R_AARCH64_GLOB_DAT, R_AARCH64_IRELATIVE, R_AARCH64_JUMP_SLOT, R_AARCH64_NONE,
+    R_AARCH64_RELATIVE, R_AARCH64_TLSDESC, R_AARCH64_TLS_DTPMOD, R_AARCH64_TLS_DTPREL,
+    R_AARCH64_TLS_TPREL, STB_WEAK,
 };
+use elf::relocation::Rela;
 
 use blueflame::program;
 
 use crate::{
-    cli::RegionArg,
-    elf::{DynamicSymbolTables, ElfWrapper},
+    binary::ModuleBinary,
+    define,
+    elf::DynamicSymbolTables,
+    init::ModuleInit,
     module::{ModuleData, ModuleInfo, ModuleType, Modules},
+    patch::Patch,
+    reloc_report::{relocation_type_name, AppliedRelocation, RelocationReport},
 };
 
 /// The loaded program memory layout
@@ -19,6 +27,12 @@ pub struct Memory {
     start: u64,
     pub regions: Vec<Region>,
     loaded_size: u32,
+    /// Dynamic symbols exported by the modules, used for linking
+    pub dynamic_symbols: DynamicSymbolTables,
+    /// Relocations applied when linking
+    pub relocations: RelocationReport,
+    /// Entry points and static initializers of the modules, in load order
+    pub init: Vec<ModuleInit>,
 }
 
 impl Memory {
@@ -29,82 +43,103 @@ impl Memory {
             start,
             regions: Vec::new(),
             loaded_size: 0,
+            dynamic_symbols: DynamicSymbolTables::new(start, module_data.info.sdk.end),
+            relocations: RelocationReport::default(),
+            init: Vec::new(),
         };
 
-        println!("-- [exefs] parsing ELF files...");
+        let binaries = module_data.iter_binaries().collect::<Vec<_>>();
 
-        let rtld_elf = ElfWrapper::try_parse(&module_data.rtld)?;
-        let main_elf = ElfWrapper::try_parse(&module_data.main)?;
-        let subsdk0_elf = ElfWrapper::try_parse(&module_data.subsdk0)?;
-        let sdk_elf = ElfWrapper::try_parse(&module_data.sdk)?;
+        log::info!("-- [exefs] loading modules into memory...");
 
-        println!("-- [exefs] loading modules into memory...");
+        log::info!("");
+        log::info!("SEGMENT START      FILE_SIZE  MEM_SIZE");
 
-        println!();
-        println!("SEGMENT START      FILE_SIZE  MEM_SIZE");
-
-        mem.load_module(ModuleType::None, &rtld_elf, &module_data.info.rtld)?;
-        mem.load_module(ModuleType::Main, &main_elf, &module_data.info.main)?;
-        mem.load_module(ModuleType::Subsdk0, &subsdk0_elf, &module_data.info.subsdk0)?;
-        mem.load_module(ModuleType::Sdk, &sdk_elf, &module_data.info.sdk)?;
+        for (module, binary) in &binaries {
+            mem.load_module(*module, binary, module_data.info.get(*module))?;
+        }
 
         mem.loaded_size = module_data.info.sdk.end;
 
-        println!("-- [exefs] loading dynamic symbols...");
-        let mut dynamic_symbols = DynamicSymbolTables::new(start, mem.loaded_size);
-        let count = rtld_elf.load_dynamic_symbols(
-            ModuleType::None,
-            start + module_data.info.rtld.start as u64,
-            &mut dynamic_symbols.rtld,
-        )?;
-        println!();
-        println!("MODULE   DYNAMIC SYMBOLS");
-        println!("rtld     {count}");
-        let count = main_elf.load_dynamic_symbols(
-            ModuleType::Main,
-            start + module_data.info.main.start as u64,
-            &mut dynamic_symbols.main,
-        )?;
-        println!("main     {count}");
-        let count = subsdk0_elf.load_dynamic_symbols(
-            ModuleType::Subsdk0,
-            start + module_data.info.subsdk0.start as u64,
-            &mut dynamic_symbols.subsdk0,
-        )?;
-        println!("subsdk0  {count}");
-        let count = sdk_elf.load_dynamic_symbols(
-            ModuleType::Sdk,
-            start + module_data.info.sdk.start as u64,
-            &mut dynamic_symbols.sdk,
-        )?;
-        println!("sdk      {count}");
+        if !module_data.patches.is_empty() {
+            log::info!("-- [exefs] applying patches...");
+            log::info!("");
+            log::info!("MODULE   OFFSET     SIZE       SOURCE");
+            for patch in &module_data.patches {
+                mem.apply_patch(patch)?;
+                log::info!(
+                    "{:8} 0x{:08x} 0x{:08x} {}",
+                    patch.module.to_string(),
+                    patch.offset,
+                    patch.bytes.len(),
+                    patch.source
+                );
+            }
+            log::info!("-- [exefs] applied {} patches", module_data.patches.len());
+        }
+
+        log::info!("-- [exefs] loading dynamic symbols...");
+        let dynamic_symbols = &mut mem.dynamic_symbols;
+        log::info!("");
+        log::info!("MODULE   DYNAMIC SYMBOLS");
+        for (module, binary) in &binaries {
+            let count = binary.load_dynamic_symbols(
+                *module,
+                start + module_data.info.get(*module).start as u64,
+                dynamic_symbols.get_mut(*module),
+            )?;
+            log::info!("{:8} {count}", module.to_string());
+        }
+
+        for (module, binary) in &binaries {
+            if binary.symbolic {
+                log::info!("-- [exefs] {module} is linked with DT_SYMBOLIC");
+                dynamic_symbols.symbolic.insert(*module);
+            }
+        }
+        if !module_data.defines.is_empty() {
+            log::info!(
+                "-- [exefs] defining {} symbols...",
+                module_data.defines.len()
+            );
+            define::apply_defines(
+                &module_data.defines,
+                start,
+                &module_data.info,
+                dynamic_symbols,
+            )?;
+        }
+        let conflicts = dynamic_symbols.find_conflicts();
+        let interposed = conflicts.iter().filter(|c| c.interposes_strong).count();
+        log::info!(
+            "-- [exefs] {} symbols are defined in more than one module, {} weak definitions interpose a strong one",
+            conflicts.len(),
+            interposed
+        );
+        mem.relocations.conflicts = conflicts;
 
         let mut count = 0;
-        count += mem.relocate(
-            ModuleType::None,
-            &rtld_elf,
-            &module_data.info.rtld,
-            &dynamic_symbols,
-        )?;
-        count += mem.relocate(
-            ModuleType::Main,
-            &main_elf,
-            &module_data.info.main,
-            &dynamic_symbols,
-        )?;
-        count += mem.relocate(
-            ModuleType::Subsdk0,
-            &subsdk0_elf,
-            &module_data.info.subsdk0,
-            &dynamic_symbols,
-        )?;
-        count += mem.relocate(
-            ModuleType::Sdk,
-            &sdk_elf,
-            &module_data.info.sdk,
-            &dynamic_symbols,
-        )?;
-        println!("-- [exefs] applied {count} relocations across all modules",);
+        for (module, binary) in &binaries {
+            count += mem.relocate(*module, binary, module_data.info.get(*module))?;
+        }
+        log::info!("-- [exefs] applied {count} relocations across all modules",);
+
+        log::info!("-- [exefs] reading entry points and static initializers...");
+        log::info!("");
+        log::info!("MODULE   ENTRY              DT_INIT DT_FINI INIT_ARRAY FINI_ARRAY");
+        for (module, binary) in &binaries {
+            let init = ModuleInit::read(&mem, *module, binary)?;
+            log::info!(
+                "{:8} 0x{:016x} {:7} {:7} {:<10} {}",
+                module.to_string(),
+                init.entry,
+                if init.init.is_some() { "yes" } else { "-" },
+                if init.fini.is_some() { "yes" } else { "-" },
+                init.init_array.len(),
+                init.fini_array.len()
+            );
+            mem.init.push(init);
+        }
 
         Ok(mem)
     }
@@ -113,51 +148,45 @@ impl Memory {
     fn load_module(
         &mut self,
         module: ModuleType,
-        elf: &ElfWrapper,
+        binary: &ModuleBinary,
         info: &ModuleInfo,
     ) -> anyhow::Result<()> {
         if self.loaded_size != info.start {
             bail!("unexpected loaded size mismatch for {}", module);
         }
         let mut segment_start = info.start;
-        for ph in elf.segments {
-            if ph.p_type == PT_LOAD {
-                if ph.p_vaddr != ph.p_paddr {
-                    bail!("unexpected p_vaddr != p_paddr");
-                }
-                if ph.p_vaddr != (segment_start - info.start) as u64 {
-                    bail!(
-                        "unexpected p_vaddr != start ({} != {})",
-                        ph.p_vaddr,
-                        segment_start - info.start
-                    );
-                }
-                let segment_data = elf.segment_data(&ph)?;
-                let permission = ph.p_flags;
-                let region = Region::allocate(
-                    module,
-                    segment_start,
-                    permission,
-                    segment_data,
-                    ph.p_memsz as u32,
-                );
-                let size = region.get_byte_len();
-                println!(
-                    "{:8}0x{:08x} 0x{:08x} 0x{:08x}  {}",
-                    module.to_string(),
-                    segment_start,
-                    ph.p_memsz,
-                    size,
-                    perm_str(permission)
+        for segment in binary.segments() {
+            if segment.vaddr != segment_start - info.start {
+                bail!(
+                    "unexpected p_vaddr != start ({} != {})",
+                    segment.vaddr,
+                    segment_start - info.start
                 );
-                self.regions.push(region);
-                segment_start += size;
-                self.loaded_size = segment_start;
-                if permission == 5 {
-                    // RX
-                    if segment_start != info.text_end {
-                        bail!("unexpected text end mismatch for {module}");
-                    }
+            }
+            let permission = segment.permissions;
+            let region = Region::allocate(
+                module,
+                segment_start,
+                permission,
+                segment.data,
+                segment.mem_size,
+            );
+            let size = region.get_byte_len();
+            log::info!(
+                "{:8}0x{:08x} 0x{:08x} 0x{:08x}  {}",
+                module.to_string(),
+                segment_start,
+                segment.mem_size,
+                size,
+                perm_str(permission)
+            );
+            self.regions.push(region);
+            segment_start += size;
+            self.loaded_size = segment_start;
+            if permission == 5 {
+                // RX
+                if segment_start != info.text_end {
+                    bail!("unexpected text end mismatch for {module}");
                 }
             }
         }
@@ -173,31 +202,44 @@ impl Memory {
         Ok(())
     }
 
+    /// Write the bytes of the patch to memory
+    fn apply_patch(&mut self, patch: &Patch) -> anyhow::Result<()> {
+        let start = self.info.get(patch.module).start + patch.offset;
+        for (i, byte) in patch.bytes.iter().enumerate() {
+            let offset = start + i as u32;
+            let region = self
+                .find_region_mut(offset)
+                .ok_or_else(|| anyhow!("unexpected offset 0x{:08x} not in any region", offset))?;
+            region.write_u8(offset, *byte);
+        }
+        Ok(())
+    }
+
     /// Apply relocation to the module in memory, return how many relocations were applied
     fn relocate(
         &mut self,
         module: ModuleType,
-        elf: &ElfWrapper,
+        binary: &ModuleBinary,
         info: &ModuleInfo,
-        dynamic: &DynamicSymbolTables,
     ) -> anyhow::Result<u32> {
-        println!("-- [exefs] applying relocation to {module}");
+        log::info!("-- [exefs] applying relocation to {module}");
 
+        let dynamic = &self.dynamic_symbols;
+        let report = &mut self.relocations;
         let mut module_regions = self
             .regions
             .iter_mut()
             .filter(|r| r.module == module)
             .collect::<Vec<_>>();
-        let (symbols, strtab) = elf
-            .dynamic_symbol_table()?
-            .ok_or_else(|| anyhow!("missing dynamic symbol table"))?;
+        let symbols = binary.symbols();
+        let strtab = binary.strtab();
         let mut unresolved_global_data = BTreeSet::new();
         let mut unresolved_global_plt = BTreeSet::new();
 
         let mut count = 0;
 
         // dyn doesn't have JUMP_SLOT
-        for rela in elf.rela_dyn()? {
+        for rela in binary.rela_dyn() {
             match rela.r_type {
                 R_AARCH64_ABS64 => {
                     // maybe external functions in vtable?
@@ -216,8 +258,21 @@ impl Memory {
                     }
                     let symbol = symbols.get(rela.r_sym as usize)?;
                     let symbol_name = strtab.get(symbol.st_name as usize)?;
-                    let address = dynamic.resolve(module, symbol_name)? + rela.r_addend as u64;
+                    let resolved = dynamic.resolve(module, symbol_name);
+                    if resolved.is_none() && symbol.st_bind() != STB_WEAK {
+                        bail!("cannot resolve dynamic symbol: {}", symbol_name);
+                    }
+                    // weak references that cannot be resolved are resolved to 0
+                    let address = resolved.map_or(0, |r| r.address) + rela.r_addend as u64;
                     Self::write_relocation(&mut module_regions, rela.r_offset as u32, address)?;
+                    let provider = resolved.and_then(|resolved| resolved.provider);
+                    report.add(
+                        module,
+                        &rela,
+                        symbol_name,
+                        resolved.map(|_| address),
+                        provider,
+                    );
                     count += 1;
                 }
                 R_AARCH64_GLOB_DAT => {
@@ -236,16 +291,23 @@ impl Memory {
                     }
                     let symbol = symbols.get(rela.r_sym as usize)?;
                     let symbol_name = strtab.get(symbol.st_name as usize)?;
-                    let address = match dynamic.resolve(module, symbol_name) {
-                        Ok(address) => address,
-                        Err(_) => {
+                    let resolved = dynamic.resolve(module, symbol_name);
+                    let address = match resolved {
+                        Some(resolved) => Some(resolved.address),
+                        None => {
                             // given it's global variable, it might be OK, if we are not touching
                             // it
                             unresolved_global_data.insert(symbol_name.to_string());
-                            0
+                            None
                         }
                     };
-                    Self::write_relocation(&mut module_regions, rela.r_offset as u32, address)?;
+                    Self::write_relocation(
+                        &mut module_regions,
+                        rela.r_offset as u32,
+                        address.unwrap_or_default(),
+                    )?;
+                    let provider = resolved.and_then(|resolved| resolved.provider);
+                    report.add(module, &rela, symbol_name, address, provider);
                     count += 1;
                 }
                 R_AARCH64_RELATIVE => {
@@ -268,16 +330,43 @@ impl Memory {
                     let value = info.start as u64 + rela.r_addend as u64 + self.start;
 
                     Self::write_relocation(&mut module_regions, offset, value)?;
+                    report.applied.push(AppliedRelocation {
+                        module,
+                        offset,
+                        r_type: relocation_type_name(rela.r_type),
+                        symbol: None,
+                        addend: rela.r_addend,
+                        value,
+                        provider: None,
+                    });
                     count += 1;
                 }
+                R_AARCH64_NONE => {}
                 _ => {
-                    bail!("unexpected relocation type in .rela.dyn: {}", rela.r_type);
+                    return Err(unsupported_relocation(module, binary, &rela));
                 }
             }
         }
 
+        // packed relative relocations, the addend is the value at the target
+        for offset in binary.relr()? {
+            let addend = binary.read_u64(offset)?;
+            let value = info.start as u64 + addend + self.start;
+            Self::write_relocation(&mut module_regions, offset, value)?;
+            report.applied.push(AppliedRelocation {
+                module,
+                offset,
+                r_type: relocation_type_name(R_AARCH64_RELATIVE),
+                symbol: None,
+                addend: addend as i64,
+                value,
+                provider: None,
+            });
+            count += 1;
+        }
+
         // plt only has JUMP_SLOT
-        for rela in elf.rela_plt()? {
+        for rela in binary.rela_plt() {
             if rela.r_addend != 0 {
                 bail!("unexpected r_addend in .rela.plt: {}", rela.r_addend);
             }
@@ -294,13 +383,14 @@ impl Memory {
                     }
                     let symbol = symbols.get(rela.r_sym as usize)?;
                     let symbol_name = strtab.get(symbol.st_name as usize)?;
-                    let address = match dynamic.resolve(module, symbol_name) {
-                        Ok(address) => address,
-                        Err(_) => {
+                    let resolved = dynamic.resolve(module, symbol_name);
+                    let address = match resolved {
+                        Some(resolved) => Some(resolved.address),
+                        None => {
                             // only happens for SDK, which aren't called
                             // so it's probably fine?
                             unresolved_global_plt.insert(symbol_name.to_string());
-                            0
+                            None
                         }
                     };
 
@@ -308,24 +398,26 @@ impl Memory {
                     // but in BOTW this seems to be creating .got.plt entry.
                     // These are just 1 pointer to the actual function
                     // the PLT entry is created statically to load GOT
-                    Self::write_relocation(&mut module_regions, rela.r_offset as u32, address)?;
+                    Self::write_relocation(
+                        &mut module_regions,
+                        rela.r_offset as u32,
+                        address.unwrap_or_default(),
+                    )?;
+                    let provider = resolved.and_then(|resolved| resolved.provider);
+                    report.add(module, &rela, symbol_name, address, provider);
                     count += 1;
                 }
                 _ => {
-                    bail!("unexpected relocation type in .rela.plt: {}", rela.r_type);
+                    return Err(unsupported_relocation(module, binary, &rela));
                 }
             }
         }
 
         if !unresolved_global_data.is_empty() {
-            println!(
-                "WARNING - the following global variables are unresolved: {unresolved_global_data:?}",
-            );
+            log::warn!("the following global variables are unresolved: {unresolved_global_data:?}",);
         }
         if !unresolved_global_plt.is_empty() {
-            println!(
-                "WARNING - the following GOT PLT entries are unresolved: {unresolved_global_data:?}",
-            );
+            log::warn!("the following GOT PLT entries are unresolved: {unresolved_global_plt:?}",);
         }
         Ok(count)
     }
@@ -356,15 +448,36 @@ impl Memory {
 
     pub fn add_program_segments(
         &self,
-        regions: &[RegionArg],
+        regions: &[Range<u32>],
+        drop_zero_pages: bool,
         mut builder: program::BuilderPhase3,
     ) -> program::BuilderPhase3 {
-        println!("-- [exefs] copying program memory...");
+        log::info!("-- [exefs] copying program memory...");
+        let segments = self.get_kept_segments(regions, drop_zero_pages);
+        let count = segments.len();
+        let size = segments.iter().map(|s| s.data.len()).sum::<usize>();
+        for segment in segments {
+            builder = builder.add_segment(segment.rel_start, segment.data);
+        }
+        log::info!("-- [exefs] copied {count} segments (0x{size:08x} bytes)");
+
+        builder
+    }
+
+    /// Copy the memory to keep in the output, specified by the regions
+    /// (relative to the program start).
+    ///
+    /// If no regions are specified, everything is kept. All-zero pages are left out
+    /// if drop_zero_pages is true, since memory is zero-filled when loaded
+    pub fn get_kept_segments(
+        &self,
+        regions: &[Range<u32>],
+        drop_zero_pages: bool,
+    ) -> Vec<KeptSegment<'_>> {
         let mut page_starts = BTreeSet::new();
         for region in regions {
-            let region_start =
-                align_down!(self.info.to_program_offset(region.module, region.start));
-            let region_end = align_up!(self.info.to_program_offset(region.module, region.end));
+            let region_start = align_down!(region.start);
+            let region_end = align_up!(region.end);
             for page in (region_start / 0x1000)..(region_end / 0x1000) {
                 page_starts.insert(page * 0x1000);
             }
@@ -392,42 +505,108 @@ impl Memory {
             }
         }
 
-        let mut count = 0;
+        let mut segments = Vec::new();
         for (rel_start, num_pages) in page_regions {
-            builder = self.add_segments_in(rel_start, num_pages, &mut count, builder);
+            self.get_segments_in(rel_start, num_pages, drop_zero_pages, &mut segments);
         }
-        println!("-- [exefs] copied {count} segments");
-
-        builder
+        segments
     }
 
-    /// Copy the region of memory into a vector of ProgramRegion
+    /// Copy the range of memory from each region
     ///
-    /// Note that one input region may result into multiple output regions,
+    /// Note that one input range may result into multiple output segments,
     /// since there are gaps between the regions in the memory.
-    pub fn add_segments_in(
-        &self,
+    fn get_segments_in<'a>(
+        &'a self,
         rel_start: u32,
         num_pages: u32,
-        count: &mut u32,
-        mut builder: program::BuilderPhase3,
-    ) -> program::BuilderPhase3 {
+        drop_zero_pages: bool,
+        out: &mut Vec<KeptSegment<'a>>,
+    ) {
         for region in &self.regions {
-            if let Some((rel_start, data)) = region.get_overlapped(rel_start, num_pages) {
-                builder = builder.add_segment(rel_start, data);
-                *count += 1;
+            for (rel_start, data) in region.get_overlapped(rel_start, num_pages, drop_zero_pages) {
+                out.push(KeptSegment {
+                    region,
+                    rel_start,
+                    data,
+                });
             }
         }
-        builder
     }
 
     pub fn get_program_size(&self) -> u32 {
         self.loaded_size
     }
+
+    pub fn get_program_start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn get_modules(&self) -> &Modules {
+        &self.info
+    }
+
+    /// Find the region that contains offset (relative to program start)
+    pub fn find_region(&self, offset: u32) -> Option<&Region> {
+        self.regions
+            .iter()
+            .find(|r| r.rel_start <= offset && offset < r.rel_start + r.get_byte_len())
+    }
+
+    /// Find the region that contains offset (relative to program start), for writing
+    fn find_region_mut(&mut self, offset: u32) -> Option<&mut Region> {
+        self.regions
+            .iter_mut()
+            .find(|r| r.rel_start <= offset && offset < r.rel_start + r.get_byte_len())
+    }
+
+    /// Read 8 bytes at offset (relative to program start)
+    pub fn read_u64(&self, offset: u32) -> anyhow::Result<u64> {
+        let region = self
+            .find_region(offset)
+            .ok_or_else(|| anyhow!("unexpected offset 0x{:08x} not in any region", offset))?;
+        Ok(region.read(offset))
+    }
+
+    /// Read 4 bytes at offset (relative to program start)
+    pub fn read_u32(&self, offset: u32) -> anyhow::Result<u32> {
+        let region = self
+            .find_region(offset)
+            .ok_or_else(|| anyhow!("unexpected offset 0x{:08x} not in any region", offset))?;
+        Ok(region.read_u32(offset))
+    }
+
+    /// Write 8 bytes at offset (relative to program start)
+    pub fn write_u64(&mut self, offset: u32, value: u64) -> anyhow::Result<()> {
+        let region = self
+            .find_region_mut(offset)
+            .ok_or_else(|| anyhow!("unexpected offset 0x{:08x} not in any region", offset))?;
+        region.write(offset, value);
+        Ok(())
+    }
+
+    /// Replace the content of the whole program with data, which starts at the program start.
+    /// Pages not covered by data are zeroed
+    pub fn replace_pages(&mut self, data: &[u8]) {
+        for region in &mut self.regions {
+            for (i, page) in region.pages.iter_mut().enumerate() {
+                *page = Page::copy(data, region.rel_start as usize + i * 0x1000);
+            }
+        }
+    }
+}
+
+/// Memory copied from a region to be kept in the output
+pub struct KeptSegment<'a> {
+    /// The region this memory is in
+    pub region: &'a Region,
+    /// Start of the memory relative to the program start
+    pub rel_start: u32,
+    pub data: Vec<u8>,
 }
 
 pub struct Region {
-    module: ModuleType,
+    pub module: ModuleType,
     /// relative start compared to the start of the memory
     pub rel_start: u32,
     pub permissions: u32,
@@ -438,6 +617,7 @@ impl Region {
     /// Allocate a new region of memory.
     ///
     /// Just enough pages will be allocated for the data.
+    /// Pages that are all zero (like .bss) are not backed by memory until written to
     pub fn allocate(
         module: ModuleType,
         start: u32,
@@ -473,31 +653,70 @@ impl Region {
         self.get_num_pages() * 0x1000
     }
 
+    /// Iterate over the data of each page in this region
+    pub fn iter_pages(&self) -> impl Iterator<Item = &[u8]> {
+        self.pages.iter().map(|page| page.data())
+    }
+
+    /// Read a value at offset in program memory
+    pub fn read(&self, offset: u32) -> u64 {
+        let rel_offset = offset - self.rel_start;
+        let page_idx = (rel_offset / 0x1000) as usize;
+        let page_offset = (rel_offset % 0x1000) as usize;
+        let data = &self.pages[page_idx].data()[page_offset..page_offset + 8];
+        u64::from_le_bytes(data.try_into().unwrap())
+    }
+
+    /// Read a 4-byte value at offset in program memory
+    pub fn read_u32(&self, offset: u32) -> u32 {
+        let rel_offset = offset - self.rel_start;
+        let page_idx = (rel_offset / 0x1000) as usize;
+        let page_offset = (rel_offset % 0x1000) as usize;
+        let data = &self.pages[page_idx].data()[page_offset..page_offset + 4];
+        u32::from_le_bytes(data.try_into().unwrap())
+    }
+
     /// Write a value to offset to program memory
     pub fn write(&mut self, offset: u32, value: u64) {
         let rel_offset = offset - self.rel_start;
         let page_idx = (rel_offset / 0x1000) as usize;
         let page_offset = (rel_offset % 0x1000) as usize;
-        self.pages[page_idx].data[page_offset..page_offset + 8]
+        self.pages[page_idx].data_mut()[page_offset..page_offset + 8]
             .copy_from_slice(&value.to_le_bytes());
     }
 
-    /// Get memory in this region that overlaps with the given range
-    /// Returns None if there is no overlap
-    pub fn get_overlapped(&self, rel_start: u32, num_pages: u32) -> Option<(u32, Vec<u8>)> {
+    /// Write a byte to offset to program memory
+    pub fn write_u8(&mut self, offset: u32, value: u8) {
+        let rel_offset = offset - self.rel_start;
+        let page_idx = (rel_offset / 0x1000) as usize;
+        let page_offset = (rel_offset % 0x1000) as usize;
+        self.pages[page_idx].data_mut()[page_offset] = value;
+    }
+
+    /// Get memory in this region that overlaps with the given range,
+    /// as (rel_start, data) of each run of consecutive pages.
+    ///
+    /// If drop_zero_pages is true, all-zero pages are left out,
+    /// which may split the memory into multiple runs
+    pub fn get_overlapped(
+        &self,
+        rel_start: u32,
+        num_pages: u32,
+        drop_zero_pages: bool,
+    ) -> Vec<(u32, Vec<u8>)> {
         let rel_end = rel_start + num_pages * 0x1000;
         if rel_end <= self.rel_start {
             // input range is before this region
-            return None;
+            return Vec::new();
         }
         let self_rel_end = self.rel_start + self.get_num_pages() * 0x1000;
         if rel_start >= self_rel_end {
             // input range is after this region
-            return None;
+            return Vec::new();
         }
         let rel_start = rel_start.max(self.rel_start);
         let rel_end = rel_end.min(self_rel_end);
-        println!(
+        log::info!(
             "loading 0x{:08x}-0x{:08x} {} {}",
             rel_start,
             rel_end,
@@ -506,17 +725,60 @@ impl Region {
         );
         let page_start_idx = (rel_start - self.rel_start) / 0x1000;
         let page_end_idx = (rel_end - self.rel_start) / 0x1000;
-        let mut out_mem = Vec::with_capacity((rel_end - rel_start) as usize);
+        let mut runs = Vec::new();
+        let mut current: Option<(u32, Vec<u8>)> = None;
         for i in page_start_idx..page_end_idx {
-            out_mem.extend_from_slice(&self.pages[i as usize].data);
+            let page = &self.pages[i as usize];
+            if drop_zero_pages && page.is_zero() {
+                runs.extend(current.take());
+                continue;
+            }
+            let (_, data) =
+                current.get_or_insert_with(|| (self.rel_start + i * 0x1000, Vec::new()));
+            data.extend_from_slice(page.data());
         }
+        runs.extend(current);
 
-        Some((rel_start, out_mem))
+        runs
     }
 }
 
+/// Create the error for a relocation type that cannot be applied
+fn unsupported_relocation(module: ModuleType, binary: &ModuleBinary, rela: &Rela) -> anyhow::Error {
+    let symbol = binary
+        .symbols()
+        .get(rela.r_sym as usize)
+        .ok()
+        .filter(|_| rela.r_sym != 0)
+        .and_then(|symbol| binary.strtab().get(symbol.st_name as usize).ok())
+        .unwrap_or("<none>");
+    let location = format!(
+        "{} at {module}+0x{:08x} (symbol: {symbol})",
+        relocation_type_name(rela.r_type),
+        rela.r_offset
+    );
+    match rela.r_type {
+        R_AARCH64_TLS_DTPMOD | R_AARCH64_TLS_DTPREL | R_AARCH64_TLS_TPREL | R_AARCH64_TLSDESC => {
+            // the TLS layout is only known at runtime
+            anyhow!("TLS relocation is not supported: {location}")
+        }
+        R_AARCH64_IRELATIVE => {
+            // the value is computed by calling the resolver at runtime
+            anyhow!(
+                "IRELATIVE relocation is not supported: {location}, resolver at {module}+0x{:08x}",
+                rela.r_addend
+            )
+        }
+        _ => anyhow!("unsupported relocation type {}: {location}", rela.r_type),
+    }
+}
+
+static ZERO_PAGE: [u8; 0x1000] = [0; 0x1000];
+
+/// A page of memory. All-zero pages (like .bss) are not allocated
+/// until they are written to
 pub struct Page {
-    data: [u8; 0x1000],
+    data: Option<Box<[u8; 0x1000]>>,
 }
 
 impl Page {
@@ -525,18 +787,42 @@ impl Page {
     /// Uncovered parts of the page are zeroed out
     #[inline]
     pub fn copy(data: &[u8], from: usize) -> Self {
-        let mut page = Page { data: [0; 0x1000] };
         if data.len() <= from {
             // this part of the page is not covered by the data (i.e. bss)
-            return page;
+            return Page { data: None };
         }
         let len = 0x1000.min(data.len() - from);
-        page.data[0..len].copy_from_slice(&data[from..from + len]);
-        page
+        let src = &data[from..from + len];
+        if src.iter().all(|b| *b == 0) {
+            return Page { data: None };
+        }
+        let mut page = Box::new([0; 0x1000]);
+        page[0..len].copy_from_slice(src);
+        Page { data: Some(page) }
+    }
+
+    /// Get the data of the page
+    pub fn data(&self) -> &[u8] {
+        match &self.data {
+            Some(data) => data.as_slice(),
+            None => &ZERO_PAGE,
+        }
+    }
+
+    /// Get the data of the page for writing, allocating it if needed
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        self.data
+            .get_or_insert_with(|| Box::new([0; 0x1000]))
+            .as_mut_slice()
+    }
+
+    /// Check if the page is all zero
+    pub fn is_zero(&self) -> bool {
+        self.data().iter().all(|b| *b == 0)
     }
 }
 
-fn perm_str(permission: u32) -> String {
+pub fn perm_str(permission: u32) -> String {
     let mut s = String::new();
     if permission & 4 != 0 {
         s.push('r');
@@ -1,38 +1,43 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::Path;
 
 use anyhow::{anyhow, bail};
+use elf::abi::{PF_R, PF_X};
+use serde::Serialize;
+
+use crate::binary::ModuleBinary;
+use crate::build_id::{self, Identified, KnownVersion};
+use crate::define::SymbolDefine;
+use crate::memory::align_up;
+use crate::nso::Nso;
+use crate::patch::Patch;
 
 pub struct ModuleData {
-    pub rtld: Vec<u8>,
-    pub main: Vec<u8>,
-    pub subsdk0: Vec<u8>,
-    pub sdk: Vec<u8>,
+    pub rtld: ModuleBinary,
+    pub main: ModuleBinary,
+    pub subsdk0: ModuleBinary,
+    pub sdk: ModuleBinary,
+    /// Extra modules (subsdk1 to subsdk9), like plugins in modded games
+    pub extra: BTreeMap<ModuleType, ModuleBinary>,
     pub info: Modules,
+    /// Patches applied to the modules before relocation, see add_patches
+    pub patches: Vec<Patch>,
+    /// Symbols to insert or override when linking, resolved in order
+    pub defines: Vec<SymbolDefine>,
 }
 
 impl ModuleData {
-    pub fn load(path: &str) -> anyhow::Result<Self> {
+    /// Load the modules next to the sdk module at path.
+    ///
+    /// subsdk1 to subsdk9 next to the sdk are loaded as extra modules if they exist.
+    /// extra_paths are more extra modules (NSO, NRO or ELF), see ModuleType::from_extra_path.
+    /// Modules from different versions of the game are an error, unless force is true
+    pub fn load(path: &str, extra_paths: &[String], force: bool) -> anyhow::Result<Self> {
         if !path.contains("sdk") {
             bail!("the input file must contain 'sdk' in its name")
         }
-        println!("-- loading the modules...");
-        // note that we cannot use any integrity checks here,
-        // as the ELF files could be different depending on how
-        // it is decompressed and converted from NSO
-        let sdk_data = std::fs::read(path)?;
-        let has_150 = memchr::memmem::find(&sdk_data, b"sdk_version: 4.4.0").is_some();
-        let has_160 = memchr::memmem::find(&sdk_data, b"sdk_version: 7.3.2").is_some();
-        let info = match (has_150, has_160) {
-            (true, false) => {
-                println!("sdk version matches 1.5.0");
-                Modules::new_1_5_0()
-            }
-            (false, true) => {
-                println!("sdk version matches 1.6.0");
-                Modules::new_1_6_0()
-            }
-            _ => bail!("the input files does not match a known version of the game"),
-        };
+        log::info!("-- loading the modules...");
+        let sdk_data = ModuleFile::read(path)?;
 
         let file_name = Path::new(path)
             .file_name()
@@ -46,29 +51,233 @@ impl ModuleData {
             .ok_or_else(|| anyhow!("cannot get parent directory"))?;
 
         let rtld_path = directory.join(file_name.replace("sdk", "rtld"));
-        println!("rtld    : {}", rtld_path.display());
-        let rtld_data = std::fs::read(&rtld_path)?;
+        log::info!("rtld    : {}", rtld_path.display());
+        let rtld_data = ModuleFile::read(&rtld_path)?;
 
         let main_path = directory.join(file_name.replace("sdk", "main"));
-        println!("main    : {}", main_path.display());
-        let main_data = std::fs::read(&main_path)?;
+        log::info!("main    : {}", main_path.display());
+        let main_data = ModuleFile::read(&main_path)?;
 
         let subsdk0_path = directory.join(file_name.replace("sdk", "subsdk0"));
-        println!("subsdk0 : {}", subsdk0_path.display());
-        let subsdk0_data = std::fs::read(&subsdk0_path)?;
+        log::info!("subsdk0 : {}", subsdk0_path.display());
+        let subsdk0_data = ModuleFile::read(&subsdk0_path)?;
+
+        log::info!("sdk     : {path}");
 
-        println!("sdk     : {path}");
+        let mut extra = BTreeMap::new();
+        for n in 1..=9 {
+            let module = ModuleType::Subsdk(n);
+            let extra_path = directory.join(file_name.replace("sdk", &module.to_string()));
+            if extra_path.exists() {
+                log::info!("{:8}: {}", module.to_string(), extra_path.display());
+                extra.insert(module, ModuleFile::read(&extra_path)?);
+            }
+        }
+        for extra_path in extra_paths {
+            let module = ModuleType::from_extra_path(Path::new(extra_path), &extra)?;
+            log::info!("{:8}: {extra_path}", module.to_string());
+            extra.insert(module, ModuleFile::read(extra_path)?);
+        }
+
+        Self::from_files(rtld_data, main_data, subsdk0_data, sdk_data, extra, force)
+    }
+
+    /// Link the modules and compute the layout from the module files,
+    /// which can be read with ModuleFile::read or ModuleFile::from_bytes
+    ///
+    /// extra are the extra modules (keyed by ModuleType::Subsdk), which can be empty.
+    /// Modules from different versions of the game are an error, unless force is true
+    pub fn from_files(
+        rtld_data: ModuleFile,
+        main_data: ModuleFile,
+        subsdk0_data: ModuleFile,
+        sdk_data: ModuleFile,
+        extra: BTreeMap<ModuleType, ModuleFile>,
+        force: bool,
+    ) -> anyhow::Result<Self> {
+        let rtld = ModuleBinary::parse(rtld_data)?;
+        let main = ModuleBinary::parse(main_data)?;
+        let subsdk0 = ModuleBinary::parse(subsdk0_data)?;
+        let sdk = ModuleBinary::parse(sdk_data)?;
+        let mut extra_binaries = BTreeMap::new();
+        for (module, file) in extra {
+            if !matches!(module, ModuleType::Subsdk(1..=9)) {
+                bail!("{module} cannot be loaded as an extra module");
+            }
+            extra_binaries.insert(module, ModuleBinary::parse(file)?);
+        }
+
+        // the version is identified by the build ID of each module, or the size
+        // of the module if the build ID is unknown. Note that the bytes cannot be used
+        // for integrity checks, as the ELF files could be different depending on how
+        // it is decompressed and converted from NSO
+        log::info!("-- identifying the modules...");
+        let mut identified = Vec::with_capacity(4);
+        for (module, binary) in [
+            (ModuleType::None, &rtld),
+            (ModuleType::Main, &main),
+            (ModuleType::Subsdk0, &subsdk0),
+            (ModuleType::Sdk, &sdk),
+        ] {
+            let size = ModuleInfo::from_binary(module, 0, binary)?;
+            identified.push(Identified::new(
+                module,
+                binary.build_id,
+                binary.bytes(),
+                &size,
+            ));
+        }
+        // extra modules are not part of the game, so they are not identified
+        let version = build_id::check_modules(&identified, force)?;
+        let is_1_6_0 = match version {
+            Some(version) => version.is_1_6_0(),
+            None if force => {
+                log::warn!("cannot determine the version of the game, assuming 1.5.0 (--force)");
+                false
+            }
+            None => {
+                bail!("cannot determine the version of the game, use --force to load the modules as 1.5.0");
+            }
+        };
+
+        log::info!("-- computing module layout...");
+        let info = Modules::from_binaries(is_1_6_0, &rtld, &main, &subsdk0, &extra_binaries, &sdk)?;
+        log::info!("");
+        log::info!("MODULE   START      TEXT_END   END");
+        for (module, module_info) in info.iter() {
+            log::info!(
+                "{:8} 0x{:08x} 0x{:08x} 0x{:08x}",
+                module.to_string(),
+                module_info.start,
+                module_info.text_end,
+                module_info.end
+            );
+        }
+        if !info.extra.is_empty() {
+            log::info!(
+                "extra modules are loaded, the layout is not compared with the known layout"
+            );
+        } else if let Some(known) = version.map(KnownVersion::layout) {
+            let mut matches = true;
+            for ((module, module_info), (_, known_info)) in info.iter().zip(known.iter()) {
+                if module_info != known_info {
+                    log::warn!("layout of {module} does not match the known layout");
+                    matches = false;
+                }
+            }
+            if matches {
+                log::info!("module layout matches the known layout");
+            } else {
+                log::info!("the modules might be modified or from a different version of the game");
+            }
+        }
 
         let data = Self {
-            rtld: rtld_data,
-            main: main_data,
-            subsdk0: subsdk0_data,
-            sdk: sdk_data,
+            rtld,
+            main,
+            subsdk0,
+            sdk,
+            extra: extra_binaries,
             info,
+            patches: Vec::new(),
+            defines: Vec::new(),
         };
 
         Ok(data)
     }
+
+    /// Get the parsed binary of a module
+    ///
+    /// Panics if the module is an extra module that is not loaded
+    pub fn get_binary(&self, module: ModuleType) -> &ModuleBinary {
+        match module {
+            ModuleType::None => &self.rtld,
+            ModuleType::Main => &self.main,
+            ModuleType::Subsdk0 => &self.subsdk0,
+            ModuleType::Subsdk(_) => &self.extra[&module],
+            ModuleType::Sdk => &self.sdk,
+        }
+    }
+
+    /// Iterate over the parsed binaries of the loaded modules, in load order
+    pub fn iter_binaries(&self) -> impl Iterator<Item = (ModuleType, &ModuleBinary)> {
+        self.info
+            .modules()
+            .map(|module| (module, self.get_binary(module)))
+    }
+
+    /// Add patches to apply to the modules before relocation
+    ///
+    /// Each patch must be inside the module, and must not overwrite
+    /// the target of a relocation, since the relocation would overwrite the patch
+    pub fn add_patches(&mut self, patches: Vec<Patch>) -> anyhow::Result<()> {
+        for patch in &patches {
+            if !self.info.contains(patch.module) {
+                bail!("{}: {} is not loaded", patch.source, patch.module);
+            }
+        }
+        for module in self.info.modules().collect::<Vec<_>>() {
+            let module_patches = patches.iter().filter(|p| p.module == module);
+            if module_patches.clone().next().is_none() {
+                continue;
+            }
+            let binary = self.get_binary(module);
+            let mut targets = BTreeSet::new();
+            for rela in binary.rela_dyn().chain(binary.rela_plt()) {
+                targets.insert(rela.r_offset as u32);
+            }
+            targets.extend(binary.relr()?);
+            let info = self.info.get(module);
+            let size = info.end - info.start;
+            for patch in module_patches {
+                let end = patch.offset as u64 + patch.bytes.len() as u64;
+                if end > size as u64 {
+                    bail!(
+                        "{}: 0x{:08x}-0x{:08x} is outside of {module} (size 0x{size:08x})",
+                        patch.source,
+                        patch.offset,
+                        end
+                    );
+                }
+                // a relocation writes 8 bytes at the target
+                let end = end as u32;
+                if let Some(target) = targets.range(patch.offset.saturating_sub(7)..end).next() {
+                    bail!(
+                        "{}: 0x{:08x}-0x{:08x} overwrites the relocation at 0x{target:08x} in {module}",
+                        patch.source,
+                        patch.offset,
+                        end
+                    );
+                }
+            }
+        }
+        self.patches.extend(patches);
+        Ok(())
+    }
+}
+
+/// Content of a module file, either an ELF or a decompressed NSO (or NRO)
+pub enum ModuleFile {
+    Elf(Vec<u8>),
+    Nso(Nso),
+}
+
+impl ModuleFile {
+    /// Read the module file, detecting the format from the magic
+    pub fn read(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_bytes(std::fs::read(path)?)
+    }
+
+    /// Create the module from the bytes of an NSO, NRO or ELF, detecting the format from the magic
+    pub fn from_bytes(data: Vec<u8>) -> anyhow::Result<Self> {
+        if Nso::is_nso(&data) {
+            Ok(Self::Nso(Nso::decompress(&data)?))
+        } else if Nso::is_nro(&data) {
+            Ok(Self::Nso(Nso::from_nro(&data)?))
+        } else {
+            Ok(Self::Elf(data))
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -77,10 +286,48 @@ pub struct Modules {
     pub rtld: ModuleInfo,
     pub main: ModuleInfo,
     pub subsdk0: ModuleInfo,
+    /// Layout of the extra modules, loaded between subsdk0 and sdk
+    pub extra: BTreeMap<ModuleType, ModuleInfo>,
     pub sdk: ModuleInfo,
 }
 
 impl Modules {
+    /// Compute the layout from the loadable segments of each module.
+    ///
+    /// Modules are placed one after another in the order rtld loads
+    /// them, each starting at a page boundary
+    pub fn from_binaries(
+        is_1_6_0: bool,
+        rtld: &ModuleBinary,
+        main: &ModuleBinary,
+        subsdk0: &ModuleBinary,
+        extra: &BTreeMap<ModuleType, ModuleBinary>,
+        sdk: &ModuleBinary,
+    ) -> anyhow::Result<Self> {
+        let rtld = ModuleInfo::from_binary(ModuleType::None, 0, rtld)?;
+        let main = ModuleInfo::from_binary(ModuleType::Main, rtld.end, main)?;
+        let subsdk0 = ModuleInfo::from_binary(ModuleType::Subsdk0, main.end, subsdk0)?;
+        // rtld loads subsdk0 to subsdk9 in order
+        let mut end = subsdk0.end;
+        let mut extra_info = BTreeMap::new();
+        for (module, binary) in extra {
+            let info = ModuleInfo::from_binary(*module, end, binary)?;
+            end = info.end;
+            extra_info.insert(*module, info);
+        }
+        let sdk = ModuleInfo::from_binary(ModuleType::Sdk, end, sdk)?;
+        Ok(Self {
+            is_1_6_0,
+            rtld,
+            main,
+            subsdk0,
+            extra: extra_info,
+            sdk,
+        })
+    }
+
+    // known layouts, only used to check the computed layout
+
     pub fn new_1_5_0() -> Self {
         Self {
             is_1_6_0: false,
@@ -99,6 +346,7 @@ impl Modules {
                 text_end: 0x29ba000,
                 end: 0x2d95000,
             },
+            extra: BTreeMap::new(),
             sdk: ModuleInfo {
                 start: 0x2d95000,
                 text_end: 0x31a4000,
@@ -124,6 +372,7 @@ impl Modules {
                 text_end: 0x30de000,
                 end: 0x3487000,
             },
+            extra: BTreeMap::new(),
             sdk: ModuleInfo {
                 start: 0x3487000,
                 text_end: 0x39b5000,
@@ -137,14 +386,43 @@ impl Modules {
     ///
     /// If the offset is after the end of the module, return the end of the module,
     pub fn to_program_offset(&self, module: ModuleType, offset: u32) -> u32 {
-        let info = match module {
+        let info = self.get(module);
+        let addr = info.start + offset;
+        addr.min(info.end)
+    }
+
+    /// Get the layout of a module
+    ///
+    /// Panics if the module is an extra module that is not loaded, see contains
+    pub fn get(&self, module: ModuleType) -> &ModuleInfo {
+        match module {
             ModuleType::None => &self.rtld,
             ModuleType::Main => &self.main,
             ModuleType::Subsdk0 => &self.subsdk0,
+            ModuleType::Subsdk(_) => &self.extra[&module],
             ModuleType::Sdk => &self.sdk,
-        };
-        let addr = info.start + offset;
-        addr.min(info.end)
+        }
+    }
+
+    /// Check if the module is loaded
+    pub fn contains(&self, module: ModuleType) -> bool {
+        match module {
+            ModuleType::Subsdk(_) => self.extra.contains_key(&module),
+            _ => true,
+        }
+    }
+
+    /// Iterate over the loaded modules in load order
+    pub fn modules(&self) -> impl Iterator<Item = ModuleType> + '_ {
+        [ModuleType::None, ModuleType::Main, ModuleType::Subsdk0]
+            .into_iter()
+            .chain(self.extra.keys().copied())
+            .chain(std::iter::once(ModuleType::Sdk))
+    }
+
+    /// Iterate over the layout of the loaded modules in load order
+    pub fn iter(&self) -> impl Iterator<Item = (ModuleType, &ModuleInfo)> {
+        self.modules().map(|module| (module, self.get(module)))
     }
 }
 
@@ -160,7 +438,36 @@ pub struct ModuleInfo {
     pub end: u32,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+impl ModuleInfo {
+    /// Compute the layout of a module loaded at start (relative to program start)
+    pub fn from_binary(
+        module: ModuleType,
+        start: u32,
+        binary: &ModuleBinary,
+    ) -> anyhow::Result<Self> {
+        let mut text_end = None;
+        let mut end = 0;
+        for segment in binary.segments() {
+            let segment_end = align_up!(segment.vaddr + segment.mem_size);
+            if segment.permissions == PF_R | PF_X {
+                if text_end.is_some() {
+                    bail!("unexpected multiple executable segments in {module}");
+                }
+                text_end = Some(start + segment_end);
+            }
+            end = end.max(segment_end);
+        }
+        let text_end = text_end.ok_or_else(|| anyhow!("missing executable segment in {module}"))?;
+        Ok(Self {
+            start,
+            text_end,
+            end: start + end,
+        })
+    }
+}
+
+/// A module loaded by rtld. The order is the load order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ModuleType {
     /// program start (i.e. rtld)
     None,
@@ -168,17 +475,148 @@ pub enum ModuleType {
     Main,
     /// subsdk0 aka multimedia
     Subsdk0,
+    /// subsdk1 to subsdk9, extra modules like exlaunch plugins
+    Subsdk(u8),
     /// sdk aka nnSdk
     Sdk,
 }
 
+impl ModuleType {
+    /// The modules that are always loaded, in the order they are loaded by rtld.
+    /// Extra modules are loaded between subsdk0 and sdk, see [`Modules::modules`]
+    pub const LOAD_ORDER: [ModuleType; 4] = [
+        ModuleType::None,
+        ModuleType::Main,
+        ModuleType::Subsdk0,
+        ModuleType::Sdk,
+    ];
+
+    /// Get the module from its name or alias, ignoring case and .nss postfix
+    ///
+    /// rtld (alias: nnrtld), main (alias: uking, u-king), subsdk0 (alias: multimedia),
+    /// subsdk1 to subsdk9, sdk (alias: nnsdk)
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim();
+        let name = name
+            .strip_suffix(".nss")
+            .unwrap_or(name)
+            .to_ascii_lowercase();
+        match name.as_str() {
+            "rtld" | "nnrtld" => Some(ModuleType::None),
+            "main" | "uking" | "u-king" => Some(ModuleType::Main),
+            "subsdk0" | "multimedia" => Some(ModuleType::Subsdk0),
+            "sdk" | "nnsdk" => Some(ModuleType::Sdk),
+            _ => match name.strip_prefix("subsdk")?.as_bytes() {
+                [n @ b'1'..=b'9'] => Some(ModuleType::Subsdk(n - b'0')),
+                _ => None,
+            },
+        }
+    }
+
+    /// Pick the slot for an extra module. The slot is from the file name if it's subsdk1
+    /// to subsdk9, otherwise it's the first free slot
+    pub fn from_extra_path(
+        path: &Path,
+        extra: &BTreeMap<ModuleType, ModuleFile>,
+    ) -> anyhow::Result<Self> {
+        let stem = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or_default();
+        let module = match ModuleType::from_name(stem) {
+            Some(module @ ModuleType::Subsdk(_)) => module,
+            _ => (1..=9)
+                .map(ModuleType::Subsdk)
+                .find(|module| !extra.contains_key(module))
+                .ok_or_else(|| anyhow!("too many extra modules, at most 9 are supported"))?,
+        };
+        if extra.contains_key(&module) {
+            bail!(
+                "{} is loaded as {module}, which is already used",
+                path.display()
+            );
+        }
+        Ok(module)
+    }
+}
+
 impl std::fmt::Display for ModuleType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ModuleType::None => write!(f, "rtld"),
             ModuleType::Main => write!(f, "main"),
             ModuleType::Subsdk0 => write!(f, "subsdk0"),
+            ModuleType::Subsdk(n) => write!(f, "subsdk{n}"),
             ModuleType::Sdk => write!(f, "sdk"),
         }
     }
 }
+
+impl Serialize for ModuleType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{module_data, test_modules};
+
+    fn parse_test_modules() -> [ModuleBinary; 4] {
+        test_modules().map(|m| ModuleBinary::parse(ModuleFile::Elf(m.build_elf())).unwrap())
+    }
+
+    #[test]
+    fn from_binaries_layout() {
+        let [rtld, main, subsdk0, sdk] = parse_test_modules();
+        let modules = test_modules();
+        let info =
+            Modules::from_binaries(false, &rtld, &main, &subsdk0, &BTreeMap::new(), &sdk).unwrap();
+        // modules are placed one after another, from .text to the end of .bss
+        let mut start = 0;
+        for ((module, info), test_module) in info.iter().zip(&modules) {
+            assert_eq!(info.start, start, "{module}");
+            assert_eq!(
+                info.text_end,
+                start + test_module.text_pages as u32 * 0x1000
+            );
+            assert_eq!(info.end, start + test_module.data_start() + 0x3000);
+            start = info.end;
+        }
+        assert_eq!(info.get(ModuleType::Sdk).end, start);
+    }
+
+    #[test]
+    fn from_binaries_extra_before_sdk() {
+        let [rtld, main, subsdk0, sdk] = parse_test_modules();
+        let [_, _, plugin, _] = parse_test_modules();
+        let extra = BTreeMap::from([(ModuleType::Subsdk(1), plugin)]);
+        let info = Modules::from_binaries(true, &rtld, &main, &subsdk0, &extra, &sdk).unwrap();
+        let plugin = info.get(ModuleType::Subsdk(1));
+        assert_eq!(plugin.start, info.subsdk0.end);
+        assert_eq!(
+            plugin.end - plugin.start,
+            info.subsdk0.end - info.subsdk0.start
+        );
+        assert_eq!(info.sdk.start, plugin.end);
+        assert_eq!(
+            info.modules().collect::<Vec<_>>(),
+            [
+                ModuleType::None,
+                ModuleType::Main,
+                ModuleType::Subsdk0,
+                ModuleType::Subsdk(1),
+                ModuleType::Sdk
+            ]
+        );
+    }
+
+    #[test]
+    fn from_files_unknown_version_needs_force() {
+        let data = module_data();
+        assert!(!data.info.is_1_6_0);
+        let [rtld, main, subsdk0, sdk] = test_modules().map(|m| ModuleFile::Elf(m.build_elf()));
+        assert!(ModuleData::from_files(rtld, main, subsdk0, sdk, BTreeMap::new(), false).is_err());
+    }
+}
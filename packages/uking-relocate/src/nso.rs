@@ -0,0 +1,289 @@
+use std::ops::Range;
+
+use anyhow::{anyhow, bail};
+
+/// A decompressed NSO module (the executable format in exefs).
+/// NRO modules (like homebrew plugins) are loaded into the same structure
+///
+/// See https://switchbrew.org/wiki/NSO and https://switchbrew.org/wiki/NRO
+pub struct Nso {
+    pub text: NsoSegment,
+    pub rodata: NsoSegment,
+    pub data: NsoSegment,
+    /// Size of .bss, which follows .data in memory
+    pub bss_size: u32,
+    /// Module ID (i.e. the build ID of the ELF the NSO is made from)
+    pub module_id: [u8; 0x20],
+    /// The decompressed segments, placed at their memory offsets
+    pub image: Vec<u8>,
+}
+
+/// Location of a segment in the decompressed module
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NsoSegment {
+    /// Offset of the segment relative to the module start
+    pub mem_offset: u32,
+    /// Decompressed size of the segment
+    pub size: u32,
+}
+
+impl NsoSegment {
+    /// Get the range of the segment in the decompressed image
+    pub fn range(&self) -> Range<usize> {
+        let start = self.mem_offset as usize;
+        start..start + self.size as usize
+    }
+}
+
+impl Nso {
+    /// Check if the data starts with the NSO magic
+    pub fn is_nso(data: &[u8]) -> bool {
+        data.starts_with(b"NSO0")
+    }
+
+    /// Parse the header and decompress the segments
+    pub fn decompress(data: &[u8]) -> anyhow::Result<Self> {
+        if !Self::is_nso(data) {
+            bail!("invalid NSO magic");
+        }
+        if data.len() < 0x100 {
+            bail!("NSO header is truncated");
+        }
+        let flags = read_u32(data, 0xC)?;
+
+        let mut image = Vec::new();
+        let mut segments = Vec::with_capacity(3);
+        // (segment header offset, compressed size offset), the index is the bit in flags
+        // that marks the segment as compressed
+        for (i, (header, file_size_offset)) in [(0x10, 0x60), (0x20, 0x64), (0x30, 0x68)]
+            .into_iter()
+            .enumerate()
+        {
+            let file_offset = read_u32(data, header)? as usize;
+            let mem_offset = read_u32(data, header + 4)?;
+            let size = read_u32(data, header + 8)?;
+            let file_size = read_u32(data, file_size_offset)? as usize;
+            let compressed = flags & (1 << i) != 0;
+
+            if (mem_offset as usize) < image.len() {
+                bail!("unexpected overlapping segments in NSO");
+            }
+            let file_data = data
+                .get(file_offset..file_offset + file_size)
+                .ok_or_else(|| anyhow!("NSO segment {i} is out of bounds"))?;
+            let segment_data = if compressed {
+                lz4_flex::block::decompress(file_data, size as usize)?
+            } else {
+                file_data.to_vec()
+            };
+            if segment_data.len() != size as usize {
+                bail!(
+                    "NSO segment {i} has unexpected size after decompression: 0x{:x} != 0x{:x}",
+                    segment_data.len(),
+                    size
+                );
+            }
+            image.resize(mem_offset as usize, 0);
+            image.extend_from_slice(&segment_data);
+            segments.push(NsoSegment { mem_offset, size });
+        }
+
+        Ok(Self {
+            text: segments[0],
+            rodata: segments[1],
+            data: segments[2],
+            bss_size: read_u32(data, 0x3C)?,
+            module_id: data[0x40..0x60].try_into()?,
+            image,
+        })
+    }
+
+    /// Check if the data has the NRO magic
+    pub fn is_nro(data: &[u8]) -> bool {
+        data.get(0x10..0x14) == Some(b"NRO0")
+    }
+
+    /// Parse the header of an NRO. NROs are not compressed, and
+    /// the segments are already at their memory offsets in the file
+    pub fn from_nro(data: &[u8]) -> anyhow::Result<Self> {
+        if !Self::is_nro(data) {
+            bail!("invalid NRO magic");
+        }
+        if data.len() < 0x80 {
+            bail!("NRO header is truncated");
+        }
+        let size = read_u32(data, 0x18)? as usize;
+        let image = data
+            .get(..size)
+            .ok_or_else(|| anyhow!("NRO is truncated"))?
+            .to_vec();
+        let mut segments = Vec::with_capacity(3);
+        for (i, header) in [0x20, 0x28, 0x30].into_iter().enumerate() {
+            let mem_offset = read_u32(data, header)?;
+            let size = read_u32(data, header + 4)?;
+            if mem_offset as usize + size as usize > image.len() {
+                bail!("NRO segment {i} is out of bounds");
+            }
+            segments.push(NsoSegment { mem_offset, size });
+        }
+
+        Ok(Self {
+            text: segments[0],
+            rodata: segments[1],
+            data: segments[2],
+            bss_size: read_u32(data, 0x38)?,
+            module_id: data[0x40..0x60].try_into()?,
+            image,
+        })
+    }
+}
+
+pub fn read_u32(data: &[u8], offset: usize) -> anyhow::Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .ok_or_else(|| anyhow!("unexpected out of bounds read at 0x{offset:08x}"))?;
+    Ok(u32::from_le_bytes(bytes.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Build an NSO with the segments at 0x0, 0x1000 and 0x2000. Only .text is compressed
+    fn make_nso(text: &[u8], rodata: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut nso = vec![0; 0x100];
+        nso[0..4].copy_from_slice(b"NSO0");
+        write_u32(&mut nso, 0xC, 1);
+        let compressed_text = lz4_flex::block::compress(text);
+        for (i, (size, file_data)) in [
+            (text.len(), compressed_text.as_slice()),
+            (rodata.len(), rodata),
+            (data.len(), data),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let file_offset = nso.len() as u32;
+            write_u32(&mut nso, 0x10 + i * 0x10, file_offset);
+            write_u32(&mut nso, 0x14 + i * 0x10, i as u32 * 0x1000);
+            write_u32(&mut nso, 0x18 + i * 0x10, size as u32);
+            write_u32(&mut nso, 0x60 + i * 4, file_data.len() as u32);
+            nso.extend_from_slice(file_data);
+        }
+        write_u32(&mut nso, 0x3C, 0x500);
+        nso[0x40..0x44].copy_from_slice(&[0xAB, 0xCD, 0xEF, 0x01]);
+        nso
+    }
+
+    #[test]
+    fn decompress_places_segments_at_mem_offset() {
+        let text = [0x1F, 0x20, 0x03, 0xD5].repeat(0x100);
+        let nso = Nso::decompress(&make_nso(&text, b"rodata", b"data")).unwrap();
+        assert_eq!(
+            nso.text,
+            NsoSegment {
+                mem_offset: 0,
+                size: 0x400
+            }
+        );
+        assert_eq!(
+            nso.rodata,
+            NsoSegment {
+                mem_offset: 0x1000,
+                size: 6
+            }
+        );
+        assert_eq!(
+            nso.data,
+            NsoSegment {
+                mem_offset: 0x2000,
+                size: 4
+            }
+        );
+        assert_eq!(&nso.image[nso.text.range()], text.as_slice());
+        assert_eq!(&nso.image[nso.rodata.range()], b"rodata");
+        assert_eq!(&nso.image[nso.data.range()], b"data");
+        // the gaps between the segments are zero
+        assert!(nso.image[0x400..0x1000].iter().all(|b| *b == 0));
+        assert_eq!(nso.bss_size, 0x500);
+        assert_eq!(nso.module_id[..5], [0xAB, 0xCD, 0xEF, 0x01, 0]);
+    }
+
+    #[test]
+    fn decompress_rejects_bad_input() {
+        let nso = make_nso(b"text", b"rodata", b"data");
+        assert!(Nso::decompress(&nso[..0x80]).is_err());
+        assert!(Nso::decompress(&nso[..nso.len() - 1]).is_err());
+        let mut bad_magic = nso.clone();
+        bad_magic[0..4].copy_from_slice(b"NRO0");
+        assert!(Nso::decompress(&bad_magic).is_err());
+        // .text is smaller than the size in the header after decompression
+        let mut bad_size = nso;
+        write_u32(&mut bad_size, 0x18, 5);
+        assert!(Nso::decompress(&bad_size).is_err());
+    }
+
+    /// Build an NRO with .text at 0x0, .rodata at 0x1000 and .data at 0x2000
+    fn make_nro() -> Vec<u8> {
+        let mut nro = vec![0; 0x3000];
+        nro[0x10..0x14].copy_from_slice(b"NRO0");
+        write_u32(&mut nro, 0x18, 0x3000);
+        for (i, size) in [0x1000, 0x800, 0x1000].into_iter().enumerate() {
+            write_u32(&mut nro, 0x20 + i * 8, i as u32 * 0x1000);
+            write_u32(&mut nro, 0x24 + i * 8, size);
+        }
+        write_u32(&mut nro, 0x38, 0x2000);
+        nro[0x40..0x42].copy_from_slice(&[0x12, 0x34]);
+        nro[0x1000..0x1006].copy_from_slice(b"rodata");
+        nro
+    }
+
+    #[test]
+    fn from_nro() {
+        let nro = Nso::from_nro(&make_nro()).unwrap();
+        assert_eq!(
+            nro.text,
+            NsoSegment {
+                mem_offset: 0,
+                size: 0x1000
+            }
+        );
+        assert_eq!(
+            nro.rodata,
+            NsoSegment {
+                mem_offset: 0x1000,
+                size: 0x800
+            }
+        );
+        assert_eq!(
+            nro.data,
+            NsoSegment {
+                mem_offset: 0x2000,
+                size: 0x1000
+            }
+        );
+        assert_eq!(nro.bss_size, 0x2000);
+        assert_eq!(nro.module_id[..3], [0x12, 0x34, 0]);
+        assert_eq!(&nro.image[nro.rodata.range()][..6], b"rodata");
+        assert_eq!(nro.image.len(), 0x3000);
+    }
+
+    #[test]
+    fn from_nro_rejects_bad_input() {
+        let nro = make_nro();
+        assert!(Nso::from_nro(&nro[..0x40]).is_err());
+        // the size in the header is larger than the file
+        assert!(Nso::from_nro(&nro[..0x2000]).is_err());
+        // .data ends after the image
+        let mut bad_segment = nro.clone();
+        write_u32(&mut bad_segment, 0x34, 0x1001);
+        assert!(Nso::from_nro(&bad_segment).is_err());
+        let mut bad_magic = nro;
+        bad_magic[0x10..0x14].copy_from_slice(b"NSO0");
+        assert!(Nso::from_nro(&bad_magic).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+
+use crate::module::{ModuleData, ModuleType};
+
+/// Size of the NSO header. Offsets in IPS patches include the header
+const NSO_HEADER_SIZE: u32 = 0x100;
+
+/// A patch to the bytes of a module, applied before relocation
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patch {
+    /// Where the patch is from, for printing
+    pub source: String,
+    pub module: ModuleType,
+    /// Offset relative to the module start
+    pub offset: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// A patch in the YAML patch format
+#[derive(Debug, Deserialize)]
+struct YamlPatch {
+    #[serde(default)]
+    name: Option<String>,
+    module: String,
+    offset: u32,
+    /// Bytes in hex, spaces are allowed
+    bytes: String,
+}
+
+/// Read patches from path, which can be:
+/// - an IPS or IPS32 file, named by the build ID of the module to patch
+/// - a directory of IPS files (like an Atmosphere exefs_patches directory).
+///   Files that do not match any module are skipped, since they are usually for other versions
+/// - a YAML file with a list of patches (module, offset and bytes)
+pub fn read_patches(path: &str, data: &ModuleData) -> anyhow::Result<Vec<Patch>> {
+    let path = Path::new(path);
+    if path.is_dir() {
+        let mut files = std::fs::read_dir(path)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        files.retain(|file| file.extension().is_some_and(|ext| ext == "ips"));
+        files.sort();
+        let mut patches = Vec::new();
+        for file in files {
+            match find_module(&file, data)? {
+                Some(module) => patches.extend(read_ips_file(&file, module)?),
+                None => log::info!(
+                    "skipping {}: does not match the build ID of any module",
+                    file.display()
+                ),
+            }
+        }
+        return Ok(patches);
+    }
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("ips") => {
+            let module = find_module(path, data)?.ok_or_else(|| {
+                anyhow!(
+                    "{} does not match the build ID of any module",
+                    path.display()
+                )
+            })?;
+            read_ips_file(path, module)
+        }
+        Some("yml" | "yaml") => read_yaml_file(path),
+        _ => bail!("unknown patch format: {}", path.display()),
+    }
+}
+
+/// Find the module to patch by the build ID in the file name of the IPS file
+fn find_module(path: &Path, data: &ModuleData) -> anyhow::Result<Option<ModuleType>> {
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| anyhow!("invalid patch file name: {}", path.display()))?
+        .to_ascii_uppercase();
+    for (module, binary) in data.iter_binaries() {
+        // the name can be shorter than the build ID, like on Atmosphere
+        if let Some(build_id) = binary.build_id {
+            if !stem.is_empty() && build_id.to_string().starts_with(&stem) {
+                return Ok(Some(module));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Read an IPS file that patches the module
+fn read_ips_file(path: &Path, module: ModuleType) -> anyhow::Result<Vec<Patch>> {
+    let source = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    parse_ips(&std::fs::read(path)?, module, &source)
+}
+
+/// Parse an IPS or IPS32 patch
+pub fn parse_ips(data: &[u8], module: ModuleType, source: &str) -> anyhow::Result<Vec<Patch>> {
+    let (offset_size, eof): (usize, &[u8]) = if data.starts_with(b"IPS32") {
+        (4, b"EEOF")
+    } else if data.starts_with(b"PATCH") {
+        (3, b"EOF")
+    } else {
+        bail!("{source} is not an IPS or IPS32 patch");
+    };
+    let mut patches = Vec::new();
+    let mut pos = 5;
+    loop {
+        if data.get(pos..).is_some_and(|rest| rest.starts_with(eof)) {
+            break;
+        }
+        let offset = read_be(data, pos, offset_size, source)?;
+        let size = read_be(data, pos + offset_size, 2, source)?;
+        pos += offset_size + 2;
+        let bytes = if size == 0 {
+            // RLE record
+            let count = read_be(data, pos, 2, source)?;
+            let value = read_be(data, pos + 2, 1, source)?;
+            pos += 3;
+            vec![value as u8; count as usize]
+        } else {
+            let bytes = data
+                .get(pos..pos + size as usize)
+                .ok_or_else(|| anyhow!("{source} is truncated"))?;
+            pos += size as usize;
+            bytes.to_vec()
+        };
+        let offset = offset.checked_sub(NSO_HEADER_SIZE).ok_or_else(|| {
+            anyhow!("{source}: offset 0x{offset:x} patches the NSO header, which is not loaded")
+        })?;
+        patches.push(Patch {
+            source: source.to_string(),
+            module,
+            offset,
+            bytes,
+        });
+    }
+    Ok(patches)
+}
+
+/// Read a big endian number of size bytes
+fn read_be(data: &[u8], pos: usize, size: usize, source: &str) -> anyhow::Result<u32> {
+    let bytes = data
+        .get(pos..pos + size)
+        .ok_or_else(|| anyhow!("{source} is truncated"))?;
+    Ok(bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u32))
+}
+
+/// Read patches in the YAML format:
+/// ```yaml
+/// - name: skip the check # optional
+///   module: main
+///   offset: 0x123456     # relative to the module start
+///   bytes: 1F 20 03 D5
+/// ```
+fn read_yaml_file(path: &Path) -> anyhow::Result<Vec<Patch>> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let yaml: Vec<YamlPatch> = serde_yaml::from_str(&std::fs::read_to_string(path)?)
+        .map_err(|e| anyhow!("failed to parse {}: {e}", path.display()))?;
+    let mut patches = Vec::with_capacity(yaml.len());
+    for (i, patch) in yaml.into_iter().enumerate() {
+        let source = match patch.name {
+            Some(name) => format!("{file_name}: {name}"),
+            None => format!("{file_name}[{i}]"),
+        };
+        let module = ModuleType::from_name(&patch.module)
+            .ok_or_else(|| anyhow!("{source}: invalid module: {}", patch.module))?;
+        let hex = patch
+            .bytes
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+        if hex.is_empty() || hex.len() % 2 != 0 || !hex.is_ascii() {
+            bail!("{source}: invalid bytes");
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| anyhow!("{source}: invalid bytes"))?;
+        patches.push(Patch {
+            source,
+            module,
+            offset: patch.offset,
+            bytes,
+        });
+    }
+    Ok(patches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ips() {
+        let mut data = b"PATCH".to_vec();
+        // 2 bytes at 0x1100
+        data.extend_from_slice(&[0x00, 0x11, 0x00, 0x00, 0x02, 0xAA, 0xBB]);
+        // RLE record: 3 bytes of 0xCC at 0x1200
+        data.extend_from_slice(&[0x00, 0x12, 0x00, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+        data.extend_from_slice(b"EOF");
+        let patches = parse_ips(&data, ModuleType::Main, "test.ips").unwrap();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].module, ModuleType::Main);
+        assert_eq!(patches[0].source, "test.ips");
+        assert_eq!(patches[0].offset, 0x1000);
+        assert_eq!(patches[0].bytes, [0xAA, 0xBB]);
+        assert_eq!(patches[1].offset, 0x1100);
+        assert_eq!(patches[1].bytes, [0xCC; 3]);
+    }
+
+    #[test]
+    fn ips32() {
+        let mut data = b"IPS32".to_vec();
+        data.extend_from_slice(&[0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0xDD]);
+        data.extend_from_slice(b"EEOF");
+        let patches = parse_ips(&data, ModuleType::Sdk, "test.ips").unwrap();
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0].offset, 0x01000000);
+        assert_eq!(patches[0].bytes, [0xDD]);
+    }
+
+    #[test]
+    fn ips_invalid() {
+        // bad magic
+        assert!(parse_ips(b"PATCX", ModuleType::Main, "test.ips").is_err());
+        // missing EOF
+        assert!(parse_ips(b"PATCH", ModuleType::Main, "test.ips").is_err());
+        // truncated data
+        let data = b"PATCH\x00\x11\x00\x00\x04\xAA\xBBEOF";
+        assert!(parse_ips(data, ModuleType::Main, "test.ips").is_err());
+        // patches the NSO header
+        let data = b"PATCH\x00\x00\x10\x00\x01\xAAEOF";
+        assert!(parse_ips(data, ModuleType::Main, "test.ips").is_err());
+    }
+}
@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail};
+use elf::abi::{PF_X, R_AARCH64_RELATIVE};
+use serde::Serialize;
+
+use crate::aslr::ASLR_REGION_START;
+use crate::binary::ModuleBinary;
+use crate::memory::{perm_str, Memory};
+use crate::module::{ModuleData, ModuleType};
+use crate::reloc_report::relocation_type_name;
+
+/// Minimum fraction of .text bytes that must match to accept the program start in the dump
+const MIN_TEXT_MATCH: f64 = 0.9;
+
+/// A program found in a RAM dump, with the relocations undone
+pub struct Derelocated {
+    pub report: DerelocateReport,
+    /// The program from the dump, relative to the program start
+    pub dump: Vec<u8>,
+    /// The program with the values written by relocations replaced with the
+    /// bytes before relocation, except the ones modified at runtime
+    pub image: Vec<u8>,
+}
+
+/// What is found when derelocating a dump
+#[derive(Debug, Serialize)]
+pub struct DerelocateReport {
+    /// Offset of the program start in the dump file
+    pub dump_offset: u64,
+    /// The physical start address of the program when the dump is taken
+    pub start: u64,
+    /// Fraction of the .text bytes in the dump that match the modules
+    pub text_match: f64,
+    /// Number of relocations undone
+    pub restored: usize,
+    /// Relocation targets with a different value than what the loader writes
+    pub mismatched: Vec<MismatchedRelocation>,
+    /// Pages with different contents than a fresh load at the same start
+    pub modified_pages: Vec<ModifiedPage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MismatchedRelocation {
+    pub module: ModuleType,
+    /// Offset of the relocation target, relative to the module start
+    pub offset: u32,
+    #[serde(rename = "type")]
+    pub r_type: &'static str,
+    pub symbol: Option<String>,
+    /// The value written by the loader
+    pub expected: u64,
+    /// The value in the dump
+    pub actual: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModifiedPage {
+    pub module: ModuleType,
+    /// Start of the page, relative to the program start
+    pub rel_start: u32,
+    pub permissions: String,
+    /// Number of bytes that are different
+    pub bytes: u32,
+}
+
+/// A relocation target
+struct Target {
+    module: ModuleType,
+    /// Offset relative to the module start
+    offset: u32,
+    r_type: &'static str,
+    symbol: Option<String>,
+}
+
+impl Target {
+    /// Get the offset relative to the program start
+    fn rel_offset(&self, memory: &Memory) -> u32 {
+        memory.get_modules().get(self.module).start + self.offset
+    }
+}
+
+/// Find the program in the dump and undo the relocations.
+///
+/// The program start in the dump is found by matching the .text of the modules, which
+/// is not changed by relocation. It must be at a page-aligned offset in the dump. The physical start is found from the values of the
+/// RELATIVE relocations, unless start is specified
+pub fn derelocate(
+    data: &ModuleData,
+    dump: &[u8],
+    start: Option<u64>,
+) -> anyhow::Result<Derelocated> {
+    log::info!("-- [derelocate] loading the modules at a reference start...");
+    let reference = Memory::load(ASLR_REGION_START, data)?;
+    let size = reference.get_program_size() as usize;
+
+    log::info!("-- [derelocate] searching for the program in the dump...");
+    let (dump_offset, text_match) = find_program(&reference, dump)?;
+    log::info!(
+        "-- [derelocate] found the program at 0x{dump_offset:08x} in the dump ({:.2}% of .text matches)",
+        text_match * 100.0
+    );
+    let program = &dump[dump_offset..dump_offset + size];
+
+    let start = match start {
+        Some(start) => start,
+        None => find_start(&reference, program)?,
+    };
+    crate::check_start(start)?;
+    log::info!("-- [derelocate] the program start is 0x{start:016x}");
+
+    log::info!("-- [derelocate] loading the modules at the program start...");
+    let fresh = Memory::load(start, data)?;
+
+    let mut image = program.to_vec();
+    let mut restored = 0;
+    let mut mismatched = Vec::new();
+    for target in relocation_targets(&fresh) {
+        let rel_offset = target.rel_offset(&fresh) as usize;
+        let expected = fresh.read_u64(rel_offset as u32)?;
+        let actual = read_u64(program, rel_offset)?;
+        if expected != actual {
+            mismatched.push(MismatchedRelocation {
+                module: target.module,
+                offset: target.offset,
+                r_type: target.r_type,
+                symbol: target.symbol,
+                expected,
+                actual,
+            });
+            continue;
+        }
+        let original = original_u64(data.get_binary(target.module), target.offset);
+        image[rel_offset..rel_offset + 8].copy_from_slice(&original.to_le_bytes());
+        restored += 1;
+    }
+    log::info!(
+        "-- [derelocate] undone {restored} relocations, {} are modified at runtime",
+        mismatched.len()
+    );
+
+    let mut modified_pages = Vec::new();
+    for region in &fresh.regions {
+        for (i, page) in region.iter_pages().enumerate() {
+            let rel_start = region.rel_start + i as u32 * 0x1000;
+            let dump_page = &program[rel_start as usize..rel_start as usize + 0x1000];
+            let bytes = page.iter().zip(dump_page).filter(|(a, b)| a != b).count() as u32;
+            if bytes > 0 {
+                modified_pages.push(ModifiedPage {
+                    module: region.module,
+                    rel_start,
+                    permissions: perm_str(region.permissions),
+                    bytes,
+                });
+            }
+        }
+    }
+    log::info!(
+        "-- [derelocate] {} pages are modified at runtime",
+        modified_pages.len()
+    );
+
+    Ok(Derelocated {
+        report: DerelocateReport {
+            dump_offset: dump_offset as u64,
+            start,
+            text_match,
+            restored,
+            mismatched,
+            modified_pages,
+        },
+        dump: program.to_vec(),
+        image,
+    })
+}
+
+impl Derelocated {
+    /// Load the program from the dump at a new start. The relocation targets that are not
+    /// modified at runtime are relocated like a fresh load. The ones modified at runtime are
+    /// moved to the new start if they point inside the program, and kept as-is otherwise.
+    ///
+    /// Other pointers written at runtime (which cannot be told apart from other data) are not moved
+    pub fn rebase(&self, data: &ModuleData, new_start: u64) -> anyhow::Result<Memory> {
+        crate::check_start(new_start)?;
+        let mut memory = Memory::load(new_start, data)?;
+        let mut values = Vec::new();
+        for target in relocation_targets(&memory) {
+            let rel_offset = target.rel_offset(&memory);
+            values.push((rel_offset, memory.read_u64(rel_offset)?));
+        }
+        memory.replace_pages(&self.dump);
+        for (rel_offset, value) in values {
+            memory.write_u64(rel_offset, value)?;
+        }
+        let old_start = self.report.start;
+        let old_end = old_start + memory.get_program_size() as u64;
+        for mismatched in &self.report.mismatched {
+            let value = if (old_start..old_end).contains(&mismatched.actual) {
+                mismatched.actual - old_start + new_start
+            } else {
+                mismatched.actual
+            };
+            let rel_offset = memory.get_modules().get(mismatched.module).start + mismatched.offset;
+            memory.write_u64(rel_offset, value)?;
+        }
+        Ok(memory)
+    }
+
+    /// Get the image of a module, with the relocations undone
+    pub fn module_image(&self, data: &ModuleData, module: ModuleType) -> &[u8] {
+        let info = data.info.get(module);
+        &self.image[info.start as usize..info.end as usize]
+    }
+}
+
+/// Find the offset of the program start in the dump, by matching the .text of the modules.
+/// Returns the offset and the fraction of the .text bytes that match.
+///
+/// Only page-aligned offsets are checked, since memory is mapped by pages
+fn find_program(reference: &Memory, dump: &[u8]) -> anyhow::Result<(usize, f64)> {
+    let size = reference.get_program_size() as usize;
+    let texts = reference
+        .regions
+        .iter()
+        .filter(|region| region.permissions & PF_X != 0)
+        .collect::<Vec<_>>();
+    // the first page of rtld is matched exactly to find the candidates
+    let first_page = texts
+        .first()
+        .and_then(|region| region.iter_pages().next())
+        .ok_or_else(|| anyhow!("unexpected missing .text"))?;
+    let total = texts.iter().map(|r| r.get_byte_len() as u64).sum::<u64>();
+
+    let mut best = None;
+    for offset in (0..dump.len().saturating_sub(size - 1)).step_by(0x1000) {
+        if &dump[offset..offset + 0x1000] != first_page {
+            continue;
+        }
+        let mut matched = 0;
+        for region in &texts {
+            for (i, page) in region.iter_pages().enumerate() {
+                let page_start = offset + region.rel_start as usize + i * 0x1000;
+                let dump_page = &dump[page_start..page_start + 0x1000];
+                matched += page.iter().zip(dump_page).filter(|(a, b)| a == b).count() as u64;
+            }
+        }
+        let text_match = matched as f64 / total as f64;
+        log::info!(
+            "candidate at 0x{offset:08x}: {:.2}% of .text matches",
+            text_match * 100.0
+        );
+        if best.is_none_or(|(_, best_match)| text_match > best_match) {
+            best = Some((offset, text_match));
+        }
+    }
+    let Some((offset, text_match)) = best else {
+        bail!("cannot find the program in the dump");
+    };
+    if text_match < MIN_TEXT_MATCH {
+        bail!(
+            "cannot find the program in the dump: only {:.2}% of .text matches at 0x{offset:08x}",
+            text_match * 100.0
+        );
+    }
+    Ok((offset, text_match))
+}
+
+/// Find the physical start of the program from the values of the RELATIVE relocations,
+/// which are the program start plus a known offset
+fn find_start(reference: &Memory, program: &[u8]) -> anyhow::Result<u64> {
+    let relative = relocation_type_name(R_AARCH64_RELATIVE);
+    let mut deltas = HashMap::<u64, usize>::new();
+    let mut total = 0;
+    for relocation in &reference.relocations.applied {
+        if relocation.r_type != relative {
+            continue;
+        }
+        let rel_offset = reference.get_modules().get(relocation.module).start + relocation.offset;
+        let actual = read_u64(program, rel_offset as usize)?;
+        *deltas
+            .entry(actual.wrapping_sub(relocation.value))
+            .or_default() += 1;
+        total += 1;
+    }
+    let (delta, count) = deltas
+        .into_iter()
+        .max_by_key(|(_, count)| *count)
+        .ok_or_else(|| anyhow!("cannot find the program start: no RELATIVE relocations"))?;
+    log::info!("{count} of {total} RELATIVE relocations agree on the program start");
+    if count * 2 < total {
+        bail!("cannot find the program start: the RELATIVE relocations do not agree, specify the start");
+    }
+    Ok(reference.get_program_start().wrapping_add(delta))
+}
+
+/// Get the targets of the relocations applied (or written as 0 if unresolved) when loading
+fn relocation_targets(memory: &Memory) -> Vec<Target> {
+    let report = &memory.relocations;
+    let applied = report.applied.iter().map(|r| Target {
+        module: r.module,
+        offset: r.offset,
+        r_type: r.r_type,
+        symbol: r.symbol.clone(),
+    });
+    let unresolved = report.unresolved.iter().map(|r| Target {
+        module: r.module,
+        offset: r.offset,
+        r_type: r.r_type,
+        symbol: Some(r.symbol.clone()),
+    });
+    applied.chain(unresolved).collect()
+}
+
+/// Get the value at the relocation target before relocation. Targets in .bss are 0
+fn original_u64(binary: &ModuleBinary, offset: u32) -> u64 {
+    binary.read_u64(offset).unwrap_or_default()
+}
+
+fn read_u64(data: &[u8], offset: usize) -> anyhow::Result<u64> {
+    let bytes = data
+        .get(offset..offset + 8)
+        .ok_or_else(|| anyhow!("unexpected out of bounds read at 0x{offset:08x}"))?;
+    Ok(u64::from_le_bytes(bytes.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use elf::abi::R_AARCH64_GLOB_DAT;
+
+    use super::*;
+    use crate::test_util::{module_data, START};
+
+    const DUMP_OFFSET: usize = 0x3000;
+    const NEW_START: u64 = 0x0000002345600000;
+
+    /// The program memory, as it is in RAM
+    fn program_bytes(memory: &Memory) -> Vec<u8> {
+        let mut bytes = vec![0; memory.get_program_size() as usize];
+        for region in &memory.regions {
+            let start = region.rel_start as usize;
+            let pages = region.iter_pages().collect::<Vec<_>>().concat();
+            bytes[start..start + pages.len()].copy_from_slice(&pages);
+        }
+        bytes
+    }
+
+    /// The program at DUMP_OFFSET, with other memory around it
+    fn make_dump(program: &[u8]) -> Vec<u8> {
+        let mut dump = vec![0xcc; DUMP_OFFSET];
+        dump.extend_from_slice(program);
+        dump.extend_from_slice(&[0xcc; 0x2000]);
+        dump
+    }
+
+    /// Offset relative to the program start of the GLOB_DAT relocation of nn_os_fn in main
+    fn glob_dat_offset(memory: &Memory) -> u32 {
+        let relocation = memory
+            .relocations
+            .applied
+            .iter()
+            .find(|r| {
+                r.module == ModuleType::Main
+                    && r.r_type == relocation_type_name(R_AARCH64_GLOB_DAT)
+                    && r.symbol.as_deref() == Some("nn_os_fn")
+            })
+            .unwrap();
+        memory.get_modules().get(ModuleType::Main).start + relocation.offset
+    }
+
+    #[test]
+    fn derelocate_dump() {
+        let data = module_data();
+        let memory = Memory::load(START, &data).unwrap();
+        let program = program_bytes(&memory);
+        let derelocated = derelocate(&data, &make_dump(&program), None).unwrap();
+
+        let report = &derelocated.report;
+        assert_eq!(report.dump_offset, DUMP_OFFSET as u64);
+        // found from the RELATIVE relocations
+        assert_eq!(report.start, START);
+        assert_eq!(report.text_match, 1.0);
+        let relocations = &memory.relocations;
+        assert_eq!(
+            report.restored,
+            relocations.applied.len() + relocations.unresolved.len()
+        );
+        assert!(report.mismatched.is_empty());
+        assert!(report.modified_pages.is_empty());
+        assert_eq!(derelocated.dump, program);
+
+        // the targets have the values before relocation
+        for target in relocation_targets(&memory) {
+            let rel_offset = target.rel_offset(&memory) as usize;
+            let original = original_u64(data.get_binary(target.module), target.offset);
+            assert_eq!(read_u64(&derelocated.image, rel_offset).unwrap(), original);
+        }
+        let main = data.info.get(ModuleType::Main);
+        assert_eq!(
+            derelocated.module_image(&data, ModuleType::Main),
+            &derelocated.image[main.start as usize..main.end as usize]
+        );
+    }
+
+    #[test]
+    fn derelocate_modified_at_runtime() {
+        let data = module_data();
+        let memory = Memory::load(START, &data).unwrap();
+        let mut program = program_bytes(&memory);
+        let offset = glob_dat_offset(&memory) as usize;
+        let expected = read_u64(&program, offset).unwrap();
+        program[offset..offset + 8].copy_from_slice(&0x1234u64.to_le_bytes());
+        let derelocated = derelocate(&data, &make_dump(&program), Some(START)).unwrap();
+
+        let report = &derelocated.report;
+        assert_eq!(report.mismatched.len(), 1);
+        let mismatched = &report.mismatched[0];
+        assert_eq!(mismatched.module, ModuleType::Main);
+        assert_eq!(mismatched.symbol.as_deref(), Some("nn_os_fn"));
+        assert_eq!(mismatched.expected, expected);
+        assert_eq!(mismatched.actual, 0x1234);
+        // not restored
+        assert_eq!(read_u64(&derelocated.image, offset).unwrap(), 0x1234);
+        let relocations = &memory.relocations;
+        assert_eq!(
+            report.restored,
+            relocations.applied.len() + relocations.unresolved.len() - 1
+        );
+        let pages = report
+            .modified_pages
+            .iter()
+            .map(|p| (p.rel_start, p.bytes))
+            .collect::<Vec<_>>();
+        let page = offset as u32 & !0xfff;
+        let bytes = (expected ^ 0x1234)
+            .to_le_bytes()
+            .iter()
+            .filter(|b| **b != 0)
+            .count();
+        assert_eq!(pages, [(page, bytes as u32)]);
+    }
+
+    #[test]
+    fn rebase_dump() {
+        let data = module_data();
+        let memory = Memory::load(START, &data).unwrap();
+        let mut program = program_bytes(&memory);
+        // pointers written at runtime to relocation targets, inside and outside of the program
+        let inside = glob_dat_offset(&memory) as usize;
+        let outside = inside + 8;
+        program[inside..inside + 8].copy_from_slice(&(START + 0x100).to_le_bytes());
+        program[outside..outside + 8].copy_from_slice(&0x1234u64.to_le_bytes());
+        let derelocated = derelocate(&data, &make_dump(&program), Some(START)).unwrap();
+        assert_eq!(derelocated.report.mismatched.len(), 2);
+
+        let rebased = derelocated.rebase(&data, NEW_START).unwrap();
+        assert_eq!(rebased.get_program_start(), NEW_START);
+        assert_eq!(rebased.read_u64(inside as u32).unwrap(), NEW_START + 0x100);
+        assert_eq!(rebased.read_u64(outside as u32).unwrap(), 0x1234);
+        // the other targets are relocated like a fresh load
+        let fresh = Memory::load(NEW_START, &data).unwrap();
+        for target in relocation_targets(&fresh) {
+            let rel_offset = target.rel_offset(&fresh);
+            if rel_offset as usize == inside || rel_offset as usize == outside {
+                continue;
+            }
+            assert_eq!(
+                rebased.read_u64(rel_offset).unwrap(),
+                fresh.read_u64(rel_offset).unwrap()
+            );
+        }
+        assert!(derelocated.rebase(&data, NEW_START + 0x1000).is_err());
+    }
+
+    #[test]
+    fn find_program_in_dump() {
+        let data = module_data();
+        let reference = Memory::load(ASLR_REGION_START, &data).unwrap();
+        let program = program_bytes(&Memory::load(START, &data).unwrap());
+        let dump = make_dump(&program);
+        assert_eq!(find_program(&reference, &dump).unwrap(), (DUMP_OFFSET, 1.0));
+
+        // the dump is smaller than the program
+        assert!(find_program(&reference, &program[..program.len() - 0x1000]).is_err());
+        assert!(find_program(&reference, &[]).is_err());
+        // the program is not at a page-aligned offset
+        let mut unaligned = vec![0xcc; 0x800];
+        unaligned.extend_from_slice(&dump);
+        assert!(find_program(&reference, &unaligned).is_err());
+        // the .text does not match enough
+        let mut modified = dump.clone();
+        let main_text = reference
+            .regions
+            .iter()
+            .find(|r| r.module == ModuleType::Main && r.permissions & PF_X != 0)
+            .unwrap();
+        let start = DUMP_OFFSET + main_text.rel_start as usize;
+        modified[start..start + main_text.get_byte_len() as usize].fill(0);
+        assert!(find_program(&reference, &modified).is_err());
+    }
+}
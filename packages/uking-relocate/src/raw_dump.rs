@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::init::ModuleInit;
+use crate::memory::{perm_str, Memory};
+
+/// Layout of the dumped memory, written to layout.json
+#[derive(Debug, Serialize)]
+pub struct Layout {
+    /// Physical address of the program start
+    pub program_start: u64,
+    /// Size of the program region
+    pub program_size: u32,
+    pub files: Vec<LayoutFile>,
+    /// Entry points and static initializers of the modules, in load order
+    pub modules: Vec<ModuleInit>,
+}
+
+/// A dumped memory file
+#[derive(Debug, Serialize)]
+pub struct LayoutFile {
+    /// Name of the file, relative to layout.json. Segments with
+    /// identical data share the same file
+    pub file: String,
+    /// Module the memory is in
+    pub module: String,
+    /// Start of the memory relative to the program start
+    pub rel_start: u32,
+    /// Physical address of the start of the memory
+    pub address: u64,
+    /// Size of the memory in bytes
+    pub size: u32,
+    /// Permissions of the memory, like "r-x"
+    pub permissions: String,
+}
+
+/// Write each kept segment of the memory to its own file in the output directory,
+/// along with layout.json
+///
+/// All-zero pages are not written if drop_zero_pages is true
+pub fn write(
+    output: impl AsRef<Path>,
+    memory: &Memory,
+    regions: &[Range<u32>],
+    drop_zero_pages: bool,
+) -> anyhow::Result<()> {
+    let output = output.as_ref();
+    std::fs::create_dir_all(output)?;
+
+    log::info!("-- [exefs] copying program memory...");
+    let mut files = Vec::new();
+    let mut written: BTreeMap<&[u8], String> = BTreeMap::new();
+    let segments = memory.get_kept_segments(regions, drop_zero_pages);
+    for segment in &segments {
+        let module = segment.region.module.to_string();
+        let file = match written.get(segment.data.as_slice()) {
+            Some(file) => file.clone(),
+            None => {
+                let file = format!("{}_{:08x}.bin", module, segment.rel_start);
+                std::fs::write(output.join(&file), &segment.data)?;
+                written.insert(&segment.data, file.clone());
+                file
+            }
+        };
+        files.push(LayoutFile {
+            file,
+            module,
+            rel_start: segment.rel_start,
+            address: memory.get_program_start() + segment.rel_start as u64,
+            size: segment.data.len() as u32,
+            permissions: perm_str(segment.region.permissions),
+        });
+    }
+    log::info!(
+        "-- [exefs] wrote {} files for {} segments",
+        written.len(),
+        files.len()
+    );
+
+    let layout = Layout {
+        program_start: memory.get_program_start(),
+        program_size: memory.get_program_size(),
+        files,
+        modules: memory.init.clone(),
+    };
+    std::fs::write(
+        output.join("layout.json"),
+        serde_json::to_string_pretty(&layout)?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use elf::abi::PF_W;
+
+    use super::*;
+    use crate::module::ModuleType;
+    use crate::test_util::{module_data, TempDir, START};
+
+    fn read_layout(dir: &TempDir) -> serde_json::Value {
+        let text = std::fs::read_to_string(dir.path().join("layout.json")).unwrap();
+        serde_json::from_str(&text).unwrap()
+    }
+
+    /// The last page of the .data segment of the module, which is only .bss
+    fn bss_page(memory: &Memory, module: ModuleType) -> Range<u32> {
+        let region = memory
+            .regions
+            .iter()
+            .find(|r| r.module == module && r.permissions & PF_W != 0)
+            .unwrap();
+        let start = region.rel_start + region.get_byte_len() - 0x1000;
+        start..start + 0x1000
+    }
+
+    #[test]
+    fn write_all() {
+        let memory = Memory::load(START, &module_data()).unwrap();
+        let dir = TempDir::new();
+        write(dir.path(), &memory, &[], false).unwrap();
+
+        let layout = read_layout(&dir);
+        assert_eq!(layout["program_start"], START);
+        assert_eq!(layout["program_size"], memory.get_program_size());
+        let files = layout["files"].as_array().unwrap();
+        assert_eq!(files.len(), memory.regions.len());
+        for (file, region) in files.iter().zip(&memory.regions) {
+            assert_eq!(file["module"], region.module.to_string());
+            assert_eq!(file["rel_start"], region.rel_start);
+            assert_eq!(file["address"], START + region.rel_start as u64);
+            assert_eq!(file["size"], region.get_byte_len());
+            assert_eq!(file["permissions"], perm_str(region.permissions));
+            let data = std::fs::read(dir.path().join(file["file"].as_str().unwrap())).unwrap();
+            assert_eq!(data, region.iter_pages().collect::<Vec<_>>().concat());
+        }
+        assert_eq!(
+            layout["modules"].as_array().unwrap().len(),
+            memory.init.len()
+        );
+    }
+
+    #[test]
+    fn write_shares_identical_segments() {
+        let memory = Memory::load(START, &module_data()).unwrap();
+        let regions = [
+            bss_page(&memory, ModuleType::Main),
+            bss_page(&memory, ModuleType::Sdk),
+        ];
+        let dir = TempDir::new();
+        write(dir.path(), &memory, &regions, false).unwrap();
+
+        let layout = read_layout(&dir);
+        let files = layout["files"].as_array().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0]["module"], "main");
+        assert_eq!(files[1]["module"], "sdk");
+        assert_eq!(files[0]["file"], files[1]["file"]);
+        // the shared file and layout.json
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn write_drops_zero_pages() {
+        let memory = Memory::load(START, &module_data()).unwrap();
+        let regions = [bss_page(&memory, ModuleType::Main)];
+        let dir = TempDir::new();
+        write(dir.path(), &memory, &regions, true).unwrap();
+
+        let layout = read_layout(&dir);
+        assert!(layout["files"].as_array().unwrap().is_empty());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use anyhow::{anyhow, bail};
+
+use crate::decomp::{to_main_offset, DecompFunction, DECOMP_MAIN_START};
+use crate::memory::Memory;
+use crate::module::ModuleType;
+use crate::{is_hex_number, parse_hex_number, parse_u64};
+
+/// Region to keep in the output
+#[derive(Debug, Clone)]
+pub struct RegionArg {
+    /// The region as specified on the command line
+    pub text: String,
+    /// The module that the start and end are relative to
+    pub module: ModuleType,
+    /// The start of the region
+    pub start: RegionBound,
+    /// The end of the region (exclusive)
+    pub end: RegionEnd,
+}
+
+/// Start or end of a region
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegionBound {
+    /// Offset relative to the module
+    Offset(u32),
+    /// Address in the decomp (0x71xxxxxxxx), which is in the main module
+    Decomp(u64),
+    /// Address of a symbol, plus an offset
+    Symbol(String, u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegionEnd {
+    Bound(RegionBound),
+    /// The start is a function, and the region is the whole function,
+    /// with the size from uking_functions.csv
+    FunctionSize,
+}
+
+pub fn parse_region(arg: &str) -> anyhow::Result<RegionArg> {
+    let text = arg.to_string();
+    let (module, arg) = match arg.strip_prefix("[") {
+        None => (ModuleType::None, arg),
+        Some(rest) => {
+            let rest = rest.trim_start();
+            let mut parts = rest.splitn(2, "]:");
+            let module_str = parts
+                .next()
+                .ok_or_else(|| anyhow!("invalid region syntax: cannot parse module"))?
+                .trim();
+            let rest = parts.next().ok_or_else(|| {
+                anyhow!("invalid region syntax: missing address range after module")
+            })?;
+            if parts.next().is_some() {
+                bail!("invalid region syntax: too many colons")
+            }
+            let module = ModuleType::from_name(module_str)
+                .ok_or_else(|| anyhow!("invalid module: {}", module_str))?;
+            (module, rest)
+        }
+    };
+    let (start, end) = match arg.split_once('-') {
+        Some((start, end)) => {
+            if end.contains('-') {
+                bail!("invalid region syntax: too many dashes")
+            }
+            let start = parse_bound(start)?;
+            let end = RegionEnd::Bound(parse_bound(end)?);
+            (start, end)
+        }
+        None => {
+            // SYMBOL or SYMBOL+SIZE
+            let arg = arg.trim();
+            let (symbol, size) = match arg.rsplit_once('+') {
+                Some((symbol, size)) => (symbol.trim(), Some(parse_offset(size)?)),
+                None => (arg, None),
+            };
+            if symbol.is_empty() || is_hex_number(symbol) {
+                bail!("invalid region syntax: missing end address");
+            }
+            let start = RegionBound::Symbol(symbol.to_string(), 0);
+            let end = match size {
+                Some(size) => RegionEnd::Bound(RegionBound::Symbol(symbol.to_string(), size)),
+                None => RegionEnd::FunctionSize,
+            };
+            (start, end)
+        }
+    };
+
+    Ok(RegionArg {
+        text,
+        module,
+        start,
+        end,
+    })
+}
+
+/// Parse a number, a decomp address, or a symbol with an optional +offset
+fn parse_bound(arg: &str) -> anyhow::Result<RegionBound> {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        bail!("invalid region syntax: missing address");
+    }
+    if let Some((symbol, offset)) = arg.rsplit_once('+') {
+        let symbol = symbol.trim();
+        if symbol.is_empty() || is_hex_number(symbol) {
+            bail!("invalid region syntax: offset can only be added to a symbol");
+        }
+        return Ok(RegionBound::Symbol(
+            symbol.to_string(),
+            parse_offset(offset)?,
+        ));
+    }
+    if !is_hex_number(arg) {
+        if arg.starts_with(|c: char| c.is_ascii_digit()) {
+            bail!("invalid region: {arg} is not a symbol, numbers need the 0x prefix");
+        }
+        return Ok(RegionBound::Symbol(arg.to_string(), 0));
+    }
+    let value = parse_u64(arg)?;
+    if let Ok(value) = u32::try_from(value) {
+        return Ok(RegionBound::Offset(value));
+    }
+    if value < DECOMP_MAIN_START {
+        bail!("invalid region: 0x{value:x} is too big for an offset, and is not a decomp address");
+    }
+    Ok(RegionBound::Decomp(value))
+}
+
+/// Parse the offset or size after a symbol, which needs the 0x prefix like the bounds
+fn parse_offset(arg: &str) -> anyhow::Result<u32> {
+    let value = parse_hex_number(arg.trim()).map_err(|e| anyhow!("invalid region: {e}"))?;
+    u32::try_from(value)
+        .map_err(|_| anyhow!("invalid region: 0x{value:x} is too big for an offset"))
+}
+
+impl RegionArg {
+    /// Resolve the region to a range relative to the program start
+    ///
+    /// functions are the functions from uking_functions.csv, if provided
+    pub fn resolve(
+        &self,
+        memory: &Memory,
+        functions: Option<&BTreeMap<String, DecompFunction>>,
+    ) -> anyhow::Result<Range<u32>> {
+        let start = self.resolve_bound(&self.start, memory, functions)?;
+        let end = match &self.end {
+            RegionEnd::Bound(bound) => self.resolve_bound(bound, memory, functions)?,
+            RegionEnd::FunctionSize => {
+                let RegionBound::Symbol(name, _) = &self.start else {
+                    unreachable!("FunctionSize is only used with a symbol start");
+                };
+                let function = functions.and_then(|f| f.get(name)).ok_or_else(|| {
+                    anyhow!(
+                        "size of {name} is unknown, use {name}+SIZE or provide uking_functions.csv"
+                    )
+                })?;
+                start.checked_add(function.size).ok_or_else(|| {
+                    anyhow!("invalid region {}: the end of {name} overflows", self.text)
+                })?
+            }
+        };
+        if start >= end {
+            bail!("invalid region {}: start must be less than end", self.text);
+        }
+        Ok(start..end)
+    }
+
+    fn resolve_bound(
+        &self,
+        bound: &RegionBound,
+        memory: &Memory,
+        functions: Option<&BTreeMap<String, DecompFunction>>,
+    ) -> anyhow::Result<u32> {
+        let modules = memory.get_modules();
+        if !modules.contains(self.module) {
+            bail!(
+                "invalid region {}: {} is not loaded",
+                self.text,
+                self.module
+            );
+        }
+        match bound {
+            RegionBound::Offset(offset) => Ok(modules.to_program_offset(self.module, *offset)),
+            RegionBound::Decomp(address) => {
+                if !matches!(self.module, ModuleType::None | ModuleType::Main) {
+                    bail!(
+                        "invalid region {}: decomp addresses are in the main module",
+                        self.text
+                    );
+                }
+                let offset = to_main_offset(*address)?;
+                Ok(modules.to_program_offset(ModuleType::Main, offset))
+            }
+            RegionBound::Symbol(name, offset) => {
+                let address = self.find_symbol(name, memory, functions)?;
+                address.checked_add(*offset).ok_or_else(|| {
+                    anyhow!(
+                        "invalid region {}: {name}+0x{offset:x} overflows",
+                        self.text
+                    )
+                })
+            }
+        }
+    }
+
+    /// Find the symbol in the dynamic symbols, then in uking_functions.csv.
+    ///
+    /// If the module is not specified, all modules are searched in load order.
+    /// Returns the address relative to the program start
+    fn find_symbol(
+        &self,
+        name: &str,
+        memory: &Memory,
+        functions: Option<&BTreeMap<String, DecompFunction>>,
+    ) -> anyhow::Result<u32> {
+        let search = match self.module {
+            ModuleType::None => memory.dynamic_symbols.modules().collect(),
+            module => vec![module],
+        };
+        for module in search {
+            if let Some(symbol) = memory.dynamic_symbols.get(module).get(name) {
+                return Ok((symbol.address - memory.get_program_start()) as u32);
+            }
+        }
+        if matches!(self.module, ModuleType::None | ModuleType::Main) {
+            if let Some(function) = functions.and_then(|f| f.get(name)) {
+                let offset = to_main_offset(function.address)?;
+                return Ok(memory
+                    .get_modules()
+                    .to_program_offset(ModuleType::Main, offset));
+            }
+        }
+        bail!("invalid region {}: cannot find symbol {name}", self.text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str, offset: u32) -> RegionBound {
+        RegionBound::Symbol(name.to_string(), offset)
+    }
+
+    #[test]
+    fn parse_bound_numbers() {
+        assert_eq!(parse_bound("0x1000").unwrap(), RegionBound::Offset(0x1000));
+        assert_eq!(parse_bound(" 0X1a ").unwrap(), RegionBound::Offset(0x1a));
+        assert_eq!(
+            parse_bound("0x71000007a0").unwrap(),
+            RegionBound::Decomp(0x71000007a0)
+        );
+        // too big for an offset, but not in main
+        assert!(parse_bound("0x100000000").is_err());
+        // numbers need the 0x prefix
+        assert!(parse_bound("1000").is_err());
+    }
+
+    #[test]
+    fn parse_bound_symbols() {
+        // hex digits without 0x are symbols
+        for name in ["add", "cafe", "beef", "nnMain"] {
+            assert_eq!(parse_bound(name).unwrap(), symbol(name, 0));
+        }
+        assert_eq!(
+            parse_bound("nnMain + 0x10").unwrap(),
+            symbol("nnMain", 0x10)
+        );
+        assert_eq!(parse_bound("cafe+0x10").unwrap(), symbol("cafe", 0x10));
+        // offsets need the 0x prefix too
+        assert!(parse_bound("cafe+10").is_err());
+        assert!(parse_bound("cafe+0x100000000").is_err());
+        assert!(parse_bound("0x1000+0x10").is_err());
+        assert!(parse_bound("+0x10").is_err());
+        assert!(parse_bound("").is_err());
+    }
+
+    #[test]
+    fn parse_region_range() {
+        let region = parse_region("[main]:0x1000-0x2000").unwrap();
+        assert_eq!(region.text, "[main]:0x1000-0x2000");
+        assert_eq!(region.module, ModuleType::Main);
+        assert_eq!(region.start, RegionBound::Offset(0x1000));
+        assert_eq!(region.end, RegionEnd::Bound(RegionBound::Offset(0x2000)));
+
+        let region = parse_region("[ nnsdk.nss ]:add-add+0x20").unwrap();
+        assert_eq!(region.module, ModuleType::Sdk);
+        assert_eq!(region.start, symbol("add", 0));
+        assert_eq!(region.end, RegionEnd::Bound(symbol("add", 0x20)));
+
+        let region = parse_region("0x71000007a0-0x7100001000").unwrap();
+        assert_eq!(region.module, ModuleType::None);
+        assert_eq!(region.start, RegionBound::Decomp(0x71000007a0));
+        assert_eq!(
+            region.end,
+            RegionEnd::Bound(RegionBound::Decomp(0x7100001000))
+        );
+    }
+
+    #[test]
+    fn parse_region_symbol() {
+        let region = parse_region("[main]:nnMain").unwrap();
+        assert_eq!(region.start, symbol("nnMain", 0));
+        assert_eq!(region.end, RegionEnd::FunctionSize);
+
+        let region = parse_region("beef+0x200").unwrap();
+        assert_eq!(region.start, symbol("beef", 0));
+        assert_eq!(region.end, RegionEnd::Bound(symbol("beef", 0x200)));
+    }
+
+    #[test]
+    fn parse_region_invalid() {
+        for arg in [
+            "0x1000",
+            "[main]:0x1000",
+            "[main]0x1000-0x2000",
+            "[foo]:0x1000-0x2000",
+            "[main]:0x1000-0x2000-0x3000",
+            "[main]:1000-2000",
+            "[main]:nnMain+200",
+            "[main]:nnMain-nnMain+10",
+        ] {
+            assert!(parse_region(arg).is_err(), "{arg}");
+        }
+    }
+}
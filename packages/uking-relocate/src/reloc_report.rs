@@ -0,0 +1,209 @@
+use std::collections::BTreeSet;
+
+use anyhow::bail;
+use elf::abi::{
+    R_AARCH64_ABS64, R_AARCH64_COPY, R_AARCH64_GLOB_DAT, R_AARCH64_IRELATIVE, R_AARCH64_JUMP_SLOT,
+    R_AARCH64_NONE, R_AARCH64_RELATIVE, R_AARCH64_TLSDESC, R_AARCH64_TLS_DTPMOD,
+    R_AARCH64_TLS_DTPREL, R_AARCH64_TLS_TPREL,
+};
+use elf::relocation::Rela;
+use serde::Serialize;
+
+use crate::elf::SymbolConflict;
+use crate::module::ModuleType;
+
+/// Record of the relocations applied when loading the program
+#[derive(Debug, Default, Serialize)]
+pub struct RelocationReport {
+    /// Relocations that are applied with a resolved value
+    pub applied: Vec<AppliedRelocation>,
+    /// Relocations with symbols that cannot be resolved, including weak references.
+    /// 0 (plus the addend for ABS64) is written for these
+    pub unresolved: Vec<UnresolvedRelocation>,
+    /// Symbols that are defined in more than one module
+    pub conflicts: Vec<SymbolConflict>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AppliedRelocation {
+    pub module: ModuleType,
+    /// Offset of the relocation target, relative to the module start
+    pub offset: u32,
+    #[serde(rename = "type")]
+    pub r_type: &'static str,
+    /// The symbol name, if the relocation references a symbol
+    pub symbol: Option<String>,
+    pub addend: i64,
+    /// The value written to the target
+    pub value: u64,
+    /// The module that defines the symbol. None for relative relocations,
+    /// magic symbols (including --define) and weak references resolved to 0
+    pub provider: Option<ModuleType>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UnresolvedRelocation {
+    pub module: ModuleType,
+    /// Offset of the relocation target, relative to the module start
+    pub offset: u32,
+    #[serde(rename = "type")]
+    pub r_type: &'static str,
+    pub symbol: String,
+}
+
+impl RelocationReport {
+    /// Record a relocation that references a symbol.
+    /// value is the value written, or None if the symbol cannot be resolved.
+    /// provider is the module that defines the symbol
+    pub fn add(
+        &mut self,
+        module: ModuleType,
+        rela: &Rela,
+        symbol: &str,
+        value: Option<u64>,
+        provider: Option<ModuleType>,
+    ) {
+        let offset = rela.r_offset as u32;
+        let r_type = relocation_type_name(rela.r_type);
+        match value {
+            Some(value) => self.applied.push(AppliedRelocation {
+                module,
+                offset,
+                r_type,
+                symbol: Some(symbol.to_string()),
+                addend: rela.r_addend,
+                value,
+                provider,
+            }),
+            None => self.unresolved.push(UnresolvedRelocation {
+                module,
+                offset,
+                r_type,
+                symbol: symbol.to_string(),
+            }),
+        }
+    }
+
+    /// Check that all unresolved symbols are in the allowlist
+    pub fn check_allowlist(&self, allowlist: &BTreeSet<String>) -> anyhow::Result<()> {
+        let not_allowed = self
+            .unresolved
+            .iter()
+            .filter(|r| !allowlist.contains(&r.symbol))
+            .map(|r| (r.module, r.symbol.as_str()))
+            .collect::<BTreeSet<_>>();
+        if not_allowed.is_empty() {
+            return Ok(());
+        }
+        log::info!("the following unresolved symbols are not in the allowlist:");
+        for (module, symbol) in &not_allowed {
+            log::info!("  {:8} {symbol}", module.to_string());
+        }
+        bail!(
+            "{} unresolved symbols are not in the allowlist",
+            not_allowed.len()
+        );
+    }
+}
+
+/// Read the allowlist file, which has one symbol name per line.
+///
+/// Empty lines and lines starting with # are ignored
+pub fn read_allowlist(path: &str) -> anyhow::Result<BTreeSet<String>> {
+    let content = std::fs::read_to_string(path)?;
+    let allowlist = content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect();
+    Ok(allowlist)
+}
+
+pub fn relocation_type_name(r_type: u32) -> &'static str {
+    match r_type {
+        R_AARCH64_ABS64 => "R_AARCH64_ABS64",
+        R_AARCH64_GLOB_DAT => "R_AARCH64_GLOB_DAT",
+        R_AARCH64_JUMP_SLOT => "R_AARCH64_JUMP_SLOT",
+        R_AARCH64_RELATIVE => "R_AARCH64_RELATIVE",
+        R_AARCH64_NONE => "R_AARCH64_NONE",
+        R_AARCH64_COPY => "R_AARCH64_COPY",
+        R_AARCH64_TLS_DTPMOD => "R_AARCH64_TLS_DTPMOD64",
+        R_AARCH64_TLS_DTPREL => "R_AARCH64_TLS_DTPREL64",
+        R_AARCH64_TLS_TPREL => "R_AARCH64_TLS_TPREL64",
+        R_AARCH64_TLSDESC => "R_AARCH64_TLSDESC",
+        R_AARCH64_IRELATIVE => "R_AARCH64_IRELATIVE",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+    use crate::test_util::{module_data, TempDir, START};
+
+    #[test]
+    fn report_from_load() {
+        let memory = Memory::load(START, &module_data()).unwrap();
+        let report = &memory.relocations;
+
+        // the weak reference to missing_fn, by ABS64, GLOB_DAT and JUMP_SLOT
+        let mut unresolved = report
+            .unresolved
+            .iter()
+            .map(|r| (r.module, r.symbol.as_str(), r.r_type))
+            .collect::<Vec<_>>();
+        unresolved.sort();
+        assert_eq!(
+            unresolved,
+            [
+                (ModuleType::Subsdk0, "missing_fn", "R_AARCH64_ABS64"),
+                (ModuleType::Subsdk0, "missing_fn", "R_AARCH64_GLOB_DAT"),
+                (ModuleType::Subsdk0, "missing_fn", "R_AARCH64_JUMP_SLOT"),
+            ]
+        );
+
+        let nn_os_fn = memory
+            .dynamic_symbols
+            .get(ModuleType::Sdk)
+            .get("nn_os_fn")
+            .unwrap()
+            .address;
+        let applied = report
+            .applied
+            .iter()
+            .filter(|r| r.symbol.as_deref() == Some("nn_os_fn"))
+            .collect::<Vec<_>>();
+        // 3 relocations each in main and subsdk0
+        assert_eq!(applied.len(), 6);
+        for r in applied {
+            assert_eq!(r.provider, Some(ModuleType::Sdk));
+            assert_eq!(r.value, nn_os_fn.wrapping_add_signed(r.addend));
+        }
+        // relative relocations have no symbol or provider
+        assert!(report
+            .applied
+            .iter()
+            .any(|r| r.r_type == "R_AARCH64_RELATIVE" && r.symbol.is_none()));
+    }
+
+    #[test]
+    fn allowlist() {
+        let memory = Memory::load(START, &module_data()).unwrap();
+        let report = &memory.relocations;
+        assert!(report.check_allowlist(&BTreeSet::new()).is_err());
+        assert!(report
+            .check_allowlist(&BTreeSet::from(["nn_os_fn".to_string()]))
+            .is_err());
+
+        let dir = TempDir::new();
+        let path = dir.write(
+            "allowlist.txt",
+            "# weak references\n\n  missing_fn  \n#nn_os_fn\n",
+        );
+        let allowlist = read_allowlist(path.to_str().unwrap()).unwrap();
+        assert_eq!(allowlist, BTreeSet::from(["missing_fn".to_string()]));
+        report.check_allowlist(&allowlist).unwrap();
+    }
+}
@@ -1,56 +1,126 @@
 use std::path::{Path, PathBuf};
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
+use blueflame::env::DataId;
+use roead::byml::Byml;
+use roead::sarc::Sarc;
+use roead::Endian;
+
+/// The data loaded into the program, and where to find it in romfs.
+///
+/// Paths can go into archives (.pack, .sarc, etc), for example
+/// `Pack/Bootup.pack/Actor/GeneralParamList/Dummy.bgparamlist`
+pub const DATA_MANIFEST: [(DataId, &str); 1] =
+    [(DataId::ActorInfoByml, "Actor/ActorInfo.product.sbyml")];
+
+/// The romfs, with one or more layers like LayeredFS
 pub struct Romfs {
-    /// Path to Actor/ActorInfo.product.sbyml (or .byml)
-    pub actor_info: PathBuf,
+    /// The romfs roots, from the base (lowest priority) to the top (highest priority)
+    layers: Vec<PathBuf>,
 }
 
 impl Romfs {
-    pub fn find_paths(
-        sdk_path: impl AsRef<Path>,
-        romfs_path: Option<&Path>,
-    ) -> anyhow::Result<Self> {
-        let sdk_path = sdk_path.as_ref();
-        let Some(exefs_dir) = sdk_path.parent() else {
+    /// Find the romfs layers to search for files
+    ///
+    /// If romfs_paths is empty, the layers are the "romfs" directories in the parent of the exefs,
+    /// then in the exefs, if found. Otherwise, only the romfs_paths are used, with later paths
+    /// having higher priority
+    pub fn find_layers(sdk_path: impl AsRef<Path>, romfs_paths: &[String]) -> anyhow::Result<Self> {
+        let mut layers = Vec::new();
+        if romfs_paths.is_empty() {
+            if let Some(exefs_dir) = sdk_path.as_ref().parent() {
+                // try parent of exefs, then the one in exefs
+                if let Some(parent) = exefs_dir.parent() {
+                    layers.push(parent.join("romfs"));
+                }
+                layers.push(exefs_dir.join("romfs"));
+                layers.retain(|layer| layer.is_dir());
+            }
+        }
+        for path in romfs_paths {
+            let path = PathBuf::from(path);
+            if !path.is_dir() {
+                bail!("romfs directory does not exist: {}", path.display());
+            }
+            layers.push(path);
+        }
+        if layers.is_empty() {
             bail!("failed to find romfs directory");
-        };
-        let Some(actor_info) =
-            find_romfs_file(exefs_dir, romfs_path, "Actor/ActorInfo.product.sbyml")
-        else {
-            bail!("failed to find Actor/ActorInfo.product.sbyml in romfs");
-        };
-        println!("-- [romfs] found ActorInfo.product.sbyml");
-        Ok(Self { actor_info })
+        }
+        log::info!("-- [romfs] layers (highest priority first):");
+        for (i, layer) in layers.iter().enumerate().rev() {
+            log::info!("  {i}: {}", layer.display());
+        }
+        Ok(Self { layers })
+    }
+
+    /// Load the file at the romfs path, which can be a path into archives.
+    ///
+    /// The file (or the archive on disk containing it) is taken from the highest layer
+    /// that has it. Yaz0 compressed archives and files are decompressed
+    pub fn load(&self, path: &str) -> anyhow::Result<Vec<u8>> {
+        for (i, layer) in self.layers.iter().enumerate().rev() {
+            if let Some(data) = load_in_root(layer, path)? {
+                log::info!(
+                    "-- [romfs] loaded {path} from layer {i} ({})",
+                    layer.display()
+                );
+                return Ok(data);
+            }
+        }
+        bail!("failed to find {path} in romfs");
     }
 
-    pub fn load_actor_info_data(&self) -> anyhow::Result<Vec<u8>> {
-        println!("-- [romfs] loading ActorInfo.product.sbyml");
-        let bytes = std::fs::read(&self.actor_info)?;
-        let decompressed_bytes = roead::yaz0::decompress_if(&bytes);
-        Ok(decompressed_bytes.to_vec())
+    /// Load every file in DATA_MANIFEST
+    pub fn load_manifest(&self) -> anyhow::Result<Vec<(DataId, Vec<u8>)>> {
+        DATA_MANIFEST
+            .into_iter()
+            .map(|(id, path)| Ok((id, self.load(path)?)))
+            .collect()
     }
 }
 
-fn find_romfs_file(base: &Path, romfs_base: Option<&Path>, file: &str) -> Option<PathBuf> {
-    if let Some(romfs_base) = romfs_base {
-        return find_file_in_romfs_root(romfs_base, file);
+/// Load the file from a romfs root. Returns None if the file (or archive) is not found
+fn load_in_root(root: &Path, path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    if let Some(file) = find_file_in_romfs_root(root, path) {
+        if file.extension().is_some_and(|ext| ext == "yml") {
+            let text = std::fs::read_to_string(&file)?;
+            let data = byml_from_text(&text)
+                .map_err(|e| anyhow!("failed to convert {}: {e}", file.display()))?;
+            return Ok(Some(data));
+        }
+        let bytes = std::fs::read(file)?;
+        return Ok(Some(roead::yaz0::decompress_if(&bytes).to_vec()));
     }
-    let base_romfs = base.join("romfs");
-    if base_romfs.is_dir() {
-        if let Some(path) = find_file_in_romfs_root(&base_romfs, file) {
-            return Some(path);
+    // find the archive on disk that contains the file, starting from the innermost
+    for (i, _) in path.rmatch_indices('/') {
+        let archive = root.join(&path[..i]);
+        if archive.is_file() {
+            let bytes = std::fs::read(archive)?;
+            return load_in_archive(&bytes, &path[..i], &path[i + 1..]).map(Some);
         }
     }
-    // try parent of base
-    let parent = base.parent()?;
-    let parent_romfs = parent.join("romfs");
-    if parent_romfs.is_dir() {
-        if let Some(path) = find_file_in_romfs_root(&parent_romfs, file) {
-            return Some(path);
+    Ok(None)
+}
+
+/// Load the file from a (possibly Yaz0 compressed) SARC archive.
+///
+/// Files in SARC have full paths as their names, so nested archives are found
+/// by trying the longest prefix of the path first
+fn load_in_archive(bytes: &[u8], archive: &str, path: &str) -> anyhow::Result<Vec<u8>> {
+    let bytes = roead::yaz0::decompress_if(bytes);
+    let sarc =
+        Sarc::new(bytes.as_ref()).map_err(|e| anyhow!("failed to open archive {archive}: {e}"))?;
+    if let Some(data) = sarc.get_data(path) {
+        return Ok(roead::yaz0::decompress_if(data).to_vec());
+    }
+    for (i, _) in path.rmatch_indices('/') {
+        if let Some(nested) = sarc.get_data(&path[..i]) {
+            let nested_archive = format!("{archive}/{}", &path[..i]);
+            return load_in_archive(nested, &nested_archive, &path[i + 1..]);
         }
     }
-    None
+    bail!("failed to find {path} in archive {archive}");
 }
 
 fn find_file_in_romfs_root(root: &Path, file: &str) -> Option<PathBuf> {
@@ -69,7 +139,199 @@ fn find_file_in_romfs_root(root: &Path, file: &str) -> Option<PathBuf> {
         if path.is_file() {
             return Some(path);
         }
+    } else {
+        return None;
+    }
+    // try the YAML text form of the BYML
+    path.set_extension("yml");
+    if path.is_file() {
+        return Some(path);
     }
 
     None
 }
+
+/// Convert the YAML text form of a BYML (as produced by roead) to binary,
+/// in the version and endianness used by the game on Switch
+pub fn byml_from_text(text: &str) -> anyhow::Result<Vec<u8>> {
+    let byml = Byml::from_text(text)?;
+    if !matches!(byml, Byml::Map(_) | Byml::Array(_)) {
+        bail!("the root node must be a map or an array");
+    }
+    Ok(byml.to_binary_with_version(Endian::Little, 2))
+}
+
+/// Check the structure of ActorInfo.product.byml
+///
+/// The game binary searches the actors by the hash of the name,
+/// so the hashes must be sorted and match the actors one-to-one
+pub fn validate_actor_info(data: &[u8]) -> anyhow::Result<()> {
+    check_actor_info(&Byml::from_binary(data)?)
+}
+
+fn check_actor_info(byml: &Byml) -> anyhow::Result<()> {
+    let Byml::Map(root) = byml else {
+        bail!("ActorInfo root must be a map");
+    };
+    let Some(Byml::Array(actors)) = root.get("Actors") else {
+        bail!("ActorInfo must have an Actors array");
+    };
+    let Some(Byml::Array(hashes)) = root.get("Hashes") else {
+        bail!("ActorInfo must have a Hashes array");
+    };
+    if actors.len() != hashes.len() {
+        bail!(
+            "ActorInfo has {} actors but {} hashes",
+            actors.len(),
+            hashes.len()
+        );
+    }
+    let mut last_hash = None;
+    for (i, (actor, hash)) in actors.iter().zip(hashes).enumerate() {
+        let Byml::Map(actor) = actor else {
+            bail!("ActorInfo.Actors[{i}] must be a map");
+        };
+        if !matches!(actor.get("name"), Some(Byml::String(_))) {
+            bail!("ActorInfo.Actors[{i}] must have a name");
+        }
+        // hashes that fit are stored as signed integers
+        let hash = match hash {
+            Byml::U32(hash) => *hash,
+            Byml::I32(hash) if *hash >= 0 => *hash as u32,
+            _ => bail!("ActorInfo.Hashes[{i}] must be an unsigned integer"),
+        };
+        if last_hash.is_some_and(|last| last >= hash) {
+            bail!("ActorInfo.Hashes must be sorted, but [{i}] is out of order: 0x{hash:08x}");
+        }
+        last_hash = Some(hash);
+    }
+    log::info!("-- [romfs] ActorInfo has {} actors", actors.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use roead::sarc::SarcWriter;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn sarc(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = SarcWriter::new(Endian::Little);
+        for (name, data) in files {
+            writer = writer.with_file(*name, *data);
+        }
+        writer.to_binary()
+    }
+
+    #[test]
+    fn load_from_archive() {
+        let nested = sarc(&[("Actor/Foo.bxml", b"foo")]);
+        let pack = sarc(&[
+            ("Actor/ActorInfo.product.sbyml", b"info"),
+            ("Actor/Pack/Foo.sbactorpack", &nested),
+        ]);
+        let load = |path| load_in_archive(&pack, "Pack/Bootup.pack", path);
+        assert_eq!(load("Actor/ActorInfo.product.sbyml").unwrap(), b"info");
+        assert_eq!(
+            load("Actor/Pack/Foo.sbactorpack/Actor/Foo.bxml").unwrap(),
+            b"foo"
+        );
+        assert!(load("Actor/Bar.bxml").is_err());
+        assert!(load("Actor/Pack/Foo.sbactorpack/Actor/Bar.bxml").is_err());
+    }
+
+    #[test]
+    fn load_from_archive_on_disk() {
+        let dir = TempDir::new();
+        dir.write(
+            "romfs/Pack/Bootup.pack",
+            sarc(&[("Actor/Foo.byml", b"foo")]),
+        );
+        dir.write("romfs/Actor/Bar.sbyml", b"bar");
+        let root = dir.path().join("romfs");
+        let load = |path| load_in_root(&root, path).unwrap();
+        assert_eq!(load("Pack/Bootup.pack/Actor/Foo.byml").unwrap(), b"foo");
+        // sbyml and byml are interchangeable for loose files
+        assert_eq!(load("Actor/Bar.byml").unwrap(), b"bar");
+        assert_eq!(load("Actor/Baz.byml"), None);
+        assert!(load_in_root(&root, "Pack/Bootup.pack/Actor/Baz.byml").is_err());
+    }
+
+    #[test]
+    fn find_layers_next_to_exefs() {
+        let dir = TempDir::new();
+        let sdk = dir.write("dump/exefs/sdk", b"");
+        assert!(Romfs::find_layers(&sdk, &[]).is_err());
+        dir.write("dump/romfs/Actor/Foo.byml", b"");
+        dir.write("dump/exefs/romfs/Actor/Foo.byml", b"");
+        let romfs = Romfs::find_layers(&sdk, &[]).unwrap();
+        assert_eq!(
+            romfs.layers,
+            [
+                dir.path().join("dump/romfs"),
+                dir.path().join("dump/exefs/romfs")
+            ]
+        );
+        // --romfs replaces the discovered layers
+        let layer = dir.path().join("mod").to_str().unwrap().to_string();
+        assert!(Romfs::find_layers(&sdk, std::slice::from_ref(&layer)).is_err());
+        dir.write("mod/Actor/Foo.byml", b"");
+        let romfs = Romfs::find_layers(&sdk, &[layer]).unwrap();
+        assert_eq!(romfs.layers, [dir.path().join("mod")]);
+    }
+
+    #[test]
+    fn load_from_top_layer() {
+        let dir = TempDir::new();
+        dir.write("base/Actor/Foo.byml", b"base foo");
+        dir.write("base/Actor/Bar.byml", b"base bar");
+        dir.write(
+            "base/Pack/Bootup.pack",
+            sarc(&[("Actor/Baz.byml", b"base baz")]),
+        );
+        // files in archives are overridden by replacing the whole archive
+        dir.write(
+            "mod/Pack/Bootup.pack",
+            sarc(&[("Actor/Baz.byml", b"mod baz")]),
+        );
+        // and byml is interchangeable with sbyml across layers
+        dir.write("mod/Actor/Foo.sbyml", b"mod foo");
+        let layers = ["base", "mod"].map(|l| dir.path().join(l).to_str().unwrap().to_string());
+        let romfs = Romfs::find_layers("", &layers).unwrap();
+        assert_eq!(romfs.load("Actor/Foo.byml").unwrap(), b"mod foo");
+        assert_eq!(romfs.load("Actor/Bar.byml").unwrap(), b"base bar");
+        assert_eq!(
+            romfs.load("Pack/Bootup.pack/Actor/Baz.byml").unwrap(),
+            b"mod baz"
+        );
+        assert!(romfs.load("Actor/Qux.byml").is_err());
+    }
+
+    fn check(text: &str) -> anyhow::Result<()> {
+        check_actor_info(&Byml::from_text(text).unwrap())
+    }
+
+    #[test]
+    fn actor_info_valid() {
+        check("{Actors: [{name: Foo}, {name: Bar}], Hashes: [1, 2]}").unwrap();
+        check("{Actors: [], Hashes: []}").unwrap();
+    }
+
+    #[test]
+    fn actor_info_invalid() {
+        // not a map, or missing arrays
+        assert!(check("[1, 2]").is_err());
+        assert!(check("{Actors: [{name: Foo}]}").is_err());
+        assert!(check("{Hashes: [1]}").is_err());
+        // the actors and hashes must match one-to-one
+        assert!(check("{Actors: [{name: Foo}], Hashes: [1, 2]}").is_err());
+        // each actor must have a name
+        assert!(check("{Actors: [{notname: Foo}], Hashes: [1]}").is_err());
+        assert!(check("{Actors: [Foo], Hashes: [1]}").is_err());
+        // the hashes must be unsigned and strictly increasing
+        assert!(check("{Actors: [{name: Foo}], Hashes: [-1]}").is_err());
+        assert!(check("{Actors: [{name: Foo}, {name: Bar}], Hashes: [2, 1]}").is_err());
+        assert!(check("{Actors: [{name: Foo}, {name: Bar}], Hashes: [1, 1]}").is_err());
+    }
+}
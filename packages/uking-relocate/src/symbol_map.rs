@@ -0,0 +1,110 @@
+use std::fmt::Write;
+
+use crate::memory::{perm_str, Memory};
+
+/// Write a linker-map style listing of the module and segment boundaries,
+/// followed by the dynamic symbols of all modules sorted by address
+pub fn write(path: &str, memory: &Memory) -> anyhow::Result<()> {
+    let start = memory.get_program_start();
+    let modules = memory.get_modules();
+    let mut out = String::new();
+
+    writeln!(out, "# program start: 0x{start:016x}")?;
+    writeln!(out)?;
+    writeln!(out, "MODULE   START              END")?;
+    for (module, info) in modules.iter() {
+        writeln!(
+            out,
+            "{:8} 0x{:016x} 0x{:016x}",
+            module.to_string(),
+            start + info.start as u64,
+            start + info.end as u64
+        )?;
+    }
+    writeln!(out)?;
+    writeln!(out, "SEGMENT  START              END                PERM")?;
+    for region in &memory.regions {
+        let region_start = start + region.rel_start as u64;
+        writeln!(
+            out,
+            "{:8} 0x{:016x} 0x{:016x} {}",
+            region.module.to_string(),
+            region_start,
+            region_start + region.get_byte_len() as u64,
+            perm_str(region.permissions)
+        )?;
+    }
+    writeln!(out)?;
+
+    let mut symbols = memory.dynamic_symbols.iter().collect::<Vec<_>>();
+    symbols.sort_by_key(|(module, name, value)| (value.address, *module, *name));
+    // flags: w = weak, p = protected
+    writeln!(out, "ADDRESS            MODULE   OFFSET     FLAGS NAME")?;
+    for (module, name, value) in symbols {
+        let module_start = start + modules.get(module).start as u64;
+        writeln!(
+            out,
+            "0x{:016x} {:8} 0x{:08x} {}{}    {}",
+            value.address,
+            module.to_string(),
+            value.address - module_start,
+            if value.weak { 'w' } else { '-' },
+            if value.protected { 'p' } else { '-' },
+            name
+        )?;
+    }
+
+    std::fs::write(path, out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::ModuleType;
+    use crate::test_util::{module_data, test_modules, TempDir, START};
+
+    #[test]
+    fn write_map() {
+        let memory = Memory::load(START, &module_data()).unwrap();
+        let dir = TempDir::new();
+        let path = dir.path().join("map.txt");
+        write(path.to_str().unwrap(), &memory).unwrap();
+        let map = std::fs::read_to_string(path).unwrap();
+        let lines = map.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines[0], format!("# program start: 0x{START:016x}"));
+        let main = memory.get_modules().get(ModuleType::Main);
+        let main_start = START + main.start as u64;
+        let main_line = format!(
+            "main     0x{main_start:016x} 0x{:016x}",
+            START + main.end as u64
+        );
+        assert!(lines.contains(&main_line.as_str()));
+        for region in &memory.regions {
+            let line = format!(
+                "{:8} 0x{:016x} 0x{:016x} {}",
+                region.module.to_string(),
+                START + region.rel_start as u64,
+                START + (region.rel_start + region.get_byte_len()) as u64,
+                perm_str(region.permissions)
+            );
+            assert!(lines.contains(&line.as_str()), "{line}");
+        }
+
+        let offset = test_modules()[1].export_offset("main_fn");
+        let line = format!(
+            "0x{:016x} main     0x{offset:08x} --    main_fn",
+            main_start + offset as u64
+        );
+        assert!(lines.contains(&line.as_str()), "{line}");
+        // symbols are sorted by address
+        let symbols = lines
+            .iter()
+            .skip_while(|line| !line.starts_with("ADDRESS"))
+            .skip(1)
+            .collect::<Vec<_>>();
+        assert_eq!(symbols.len(), memory.dynamic_symbols.iter().count());
+        assert!(symbols.is_sorted());
+    }
+}
@@ -0,0 +1,467 @@
+//! Helpers for the unit tests: tiny AArch64 modules and temporary directories
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use elf::abi::{
+    DT_FINI, DT_FINI_ARRAY, DT_FINI_ARRAYSZ, DT_HASH, DT_INIT, DT_INIT_ARRAY, DT_INIT_ARRAYSZ,
+    DT_JMPREL, DT_PLTREL, DT_PLTRELSZ, DT_RELA, DT_RELAENT, DT_RELASZ, DT_STRSZ, DT_STRTAB,
+    DT_SYMENT, DT_SYMTAB, PF_R, PF_W, PF_X, PT_DYNAMIC, PT_LOAD, R_AARCH64_ABS64,
+    R_AARCH64_GLOB_DAT, R_AARCH64_JUMP_SLOT, R_AARCH64_RELATIVE,
+};
+
+use crate::module::{ModuleData, ModuleFile};
+
+/// A valid program start for the tests
+pub const START: u64 = 0x0000001234500000;
+
+const PAGE: usize = 0x1000;
+/// stp x29, x30, [sp, #-0x10]!
+const PROLOGUE: u32 = 0xA9BF7BFD;
+const NOP: u32 = 0xD503201F;
+const RET: u32 = 0xD65F03C0;
+/// Size of each exported function
+const FUNCTION_SIZE: usize = 0x20;
+/// Size of the data segment in the file, the rest of it is .bss
+const DATA_SIZE: usize = 0x400;
+/// Size of the data segment in memory
+const DATA_MEM_SIZE: usize = 0x3000;
+
+/// (offset in .data, type, symbol, addend) of a relocation
+type TestRela = (usize, u32, u64, u64);
+
+/// An entry of .init_array or .fini_array
+#[derive(Clone, Copy)]
+pub enum ArrayEntry {
+    /// Pointer to an exported function, with a RELATIVE relocation
+    Function(&'static str),
+    /// A value that is not relocated, like 0 or -1
+    Raw(u64),
+}
+
+/// A tiny module, with a page-aligned .text, .rodata (with the dynamic section)
+/// and .data (with .bss) segment, like the modules of the game
+pub struct TestModule {
+    pub text_pages: usize,
+    /// Exported functions, at the start of .text
+    pub exports: &'static [&'static str],
+    /// Imported symbols, referenced by ABS64, GLOB_DAT and JUMP_SLOT relocations
+    pub imports: &'static [&'static str],
+    /// Imports that are weak references, which are allowed to be unresolved
+    pub weak_imports: &'static [&'static str],
+    /// Export for DT_INIT
+    pub init: Option<&'static str>,
+    /// Export for DT_FINI
+    pub fini: Option<&'static str>,
+    pub init_array: &'static [ArrayEntry],
+    pub fini_array: &'static [ArrayEntry],
+    /// Seed of the random bytes that fill the rest of .text
+    pub seed: u32,
+}
+
+/// rtld, main, subsdk0 and sdk. Each module imports some exports of the others,
+/// and subsdk0 has a weak reference to missing_fn, which is not defined
+pub fn test_modules() -> [TestModule; 4] {
+    use ArrayEntry::*;
+    [
+        TestModule {
+            text_pages: 2,
+            exports: &["__rtld_start", "rtld_fn"],
+            imports: &[],
+            weak_imports: &[],
+            init: None,
+            fini: None,
+            init_array: &[],
+            fini_array: &[],
+            seed: 1,
+        },
+        TestModule {
+            text_pages: 8,
+            exports: &["nnMain", "main_fn", "main_vt"],
+            imports: &["nn_os_fn", "nn_fs_fn", "mm_fn"],
+            weak_imports: &[],
+            init: Some("main_fn"),
+            fini: Some("main_vt"),
+            init_array: &[
+                Function("main_fn"),
+                Raw(0),
+                Raw(u64::MAX),
+                Function("nnMain"),
+            ],
+            fini_array: &[Function("main_fn"), Function("main_vt")],
+            seed: 2,
+        },
+        TestModule {
+            text_pages: 3,
+            exports: &["mm_fn", "mm_unused"],
+            imports: &["nn_os_fn", "missing_fn"],
+            weak_imports: &["missing_fn"],
+            init: None,
+            fini: None,
+            init_array: &[],
+            fini_array: &[],
+            seed: 3,
+        },
+        TestModule {
+            text_pages: 4,
+            exports: &["nn_os_fn", "nn_fs_fn", "sdk_unused", "nnsdkInit"],
+            imports: &["nnMain"],
+            weak_imports: &[],
+            init: Some("nnsdkInit"),
+            fini: None,
+            init_array: &[Function("nnsdkInit")],
+            fini_array: &[],
+            seed: 4,
+        },
+    ]
+}
+
+/// Load the test modules (which are not a known version, so force is used)
+pub fn module_data() -> ModuleData {
+    let [rtld, main, subsdk0, sdk] = test_modules().map(|m| ModuleFile::Elf(m.build_elf()));
+    ModuleData::from_files(rtld, main, subsdk0, sdk, BTreeMap::new(), true).unwrap()
+}
+
+impl TestModule {
+    /// Get the offset of an export relative to the module start
+    pub fn export_offset(&self, name: &str) -> u32 {
+        let i = self.exports.iter().position(|e| *e == name).unwrap();
+        (i * FUNCTION_SIZE) as u32
+    }
+
+    /// Get the start of .data relative to the module start
+    pub fn data_start(&self) -> u32 {
+        let ro_start = self.text_pages * PAGE;
+        (ro_start + self.build_rodata(0).len()).next_multiple_of(PAGE) as u32
+    }
+
+    /// Build the module as an ET_DYN ELF
+    pub fn build_elf(&self) -> Vec<u8> {
+        let text_end = self.text_pages * PAGE;
+        let data_start = self.data_start() as usize;
+        let rodata = self.build_rodata(data_start);
+        let dynamic_offset = rodata.len() - self.dynamic(0, 0, 0, 0).len() * 0x10;
+        let segments = [
+            (0, self.build_text(), text_end, PF_R | PF_X),
+            (text_end, rodata.clone(), rodata.len(), PF_R),
+            (data_start, self.build_data(), DATA_MEM_SIZE, PF_R | PF_W),
+        ];
+
+        let mut out = Vec::new();
+        out.extend_from_slice(b"\x7fELF\x02\x01\x01");
+        out.resize(16, 0);
+        push_u16(&mut out, 3); // ET_DYN
+        push_u16(&mut out, 183); // EM_AARCH64
+        push_u32(&mut out, 1);
+        push_u64(&mut out, 0); // entry
+        push_u64(&mut out, 0x40); // phoff
+        push_u64(&mut out, 0); // shoff
+        push_u32(&mut out, 0); // flags
+        push_u16(&mut out, 0x40);
+        push_u16(&mut out, 0x38);
+        push_u16(&mut out, segments.len() as u16 + 1);
+        push_u16(&mut out, 0x40);
+        push_u16(&mut out, 0);
+        push_u16(&mut out, 0);
+        // segments are at PAGE + vaddr in the file
+        for (vaddr, data, mem_size, flags) in &segments {
+            let vaddr = *vaddr as u64;
+            push_phdr(
+                &mut out,
+                PT_LOAD,
+                *flags,
+                PAGE as u64 + vaddr,
+                vaddr,
+                data.len(),
+                *mem_size,
+            );
+        }
+        let dynamic = (text_end + dynamic_offset) as u64;
+        let dynamic_size = rodata.len() - dynamic_offset;
+        push_phdr(
+            &mut out,
+            PT_DYNAMIC,
+            PF_R,
+            PAGE as u64 + dynamic,
+            dynamic,
+            dynamic_size,
+            dynamic_size,
+        );
+        for (vaddr, data, _, _) in &segments {
+            out.resize(PAGE + vaddr, 0);
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    fn build_text(&self) -> Vec<u8> {
+        let mut text = Vec::new();
+        for _ in self.exports {
+            push_u32(&mut text, PROLOGUE);
+            for _ in 0..(FUNCTION_SIZE / 4 - 2) {
+                push_u32(&mut text, NOP);
+            }
+            push_u32(&mut text, RET);
+        }
+        // xorshift
+        let mut x = self.seed.wrapping_mul(0x9E3779B9) | 1;
+        while text.len() < self.text_pages * PAGE {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            push_u32(&mut text, x);
+        }
+        text
+    }
+
+    /// Symbols: the null symbol, the imports, then the exports
+    fn symbol_index(&self, name: &str) -> u64 {
+        let i = self.imports.iter().position(|i| *i == name).unwrap();
+        i as u64 + 1
+    }
+
+    /// The relocations in .rela.dyn, and the (offset, value) written to .data
+    /// that are not relocated
+    fn relocations(&self) -> (Vec<TestRela>, Vec<(usize, u64)>) {
+        let mut relas = Vec::new();
+        let mut raw = Vec::new();
+        let mut offset = 0;
+        for export in self.exports {
+            let addend = self.export_offset(export) as u64;
+            relas.push((offset, R_AARCH64_RELATIVE, 0, addend));
+            offset += 8;
+        }
+        for r_type in [R_AARCH64_ABS64, R_AARCH64_GLOB_DAT] {
+            for import in self.imports {
+                relas.push((offset, r_type, self.symbol_index(import), 0));
+                offset += 8;
+            }
+        }
+        // .got.plt is after these, then the arrays
+        offset += 8 * self.imports.len();
+        for entry in self.init_array.iter().chain(self.fini_array) {
+            match entry {
+                ArrayEntry::Function(name) => {
+                    let addend = self.export_offset(name) as u64;
+                    relas.push((offset, R_AARCH64_RELATIVE, 0, addend));
+                }
+                ArrayEntry::Raw(value) => raw.push((offset, *value)),
+            }
+            offset += 8;
+        }
+        assert!(offset <= 0x200, "too many relocations");
+        (relas, raw)
+    }
+
+    /// Offset of .got.plt in .data
+    fn got_plt(&self) -> usize {
+        8 * (self.exports.len() + 2 * self.imports.len())
+    }
+
+    fn build_data(&self) -> Vec<u8> {
+        let mut data = vec![0; DATA_SIZE];
+        for (offset, value) in self.relocations().1 {
+            data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        // some data that is not relocated
+        for (i, b) in data.iter_mut().enumerate().skip(0x200) {
+            *b = (i as u32).wrapping_mul(self.seed) as u8;
+        }
+        data
+    }
+
+    /// The dynamic section entries, for the tables at the offsets in .rodata
+    fn dynamic(
+        &self,
+        ro_start: usize,
+        data_start: usize,
+        strsz: usize,
+        relasz: usize,
+    ) -> Vec<(i64, u64)> {
+        let (dynsym, dynstr, hash, rela, jmprel) = self.rodata_offsets();
+        let ro = |offset: usize| (ro_start + offset) as u64;
+        let mut dynamic = vec![
+            (DT_SYMTAB, ro(dynsym)),
+            (DT_SYMENT, 0x18),
+            (DT_STRTAB, ro(dynstr)),
+            (DT_STRSZ, strsz as u64),
+            (DT_HASH, ro(hash)),
+            (DT_RELA, ro(rela)),
+            (DT_RELASZ, relasz as u64),
+            (DT_RELAENT, 0x18),
+            (DT_JMPREL, ro(jmprel)),
+            (DT_PLTRELSZ, 0x18 * self.imports.len() as u64),
+            (DT_PLTREL, DT_RELA as u64),
+        ];
+        if let Some(init) = self.init {
+            dynamic.push((DT_INIT, self.export_offset(init) as u64));
+        }
+        if let Some(fini) = self.fini {
+            dynamic.push((DT_FINI, self.export_offset(fini) as u64));
+        }
+        let arrays = (data_start + self.got_plt() + 8 * self.imports.len()) as u64;
+        let init_size = 8 * self.init_array.len() as u64;
+        if init_size > 0 {
+            dynamic.push((DT_INIT_ARRAY, arrays));
+            dynamic.push((DT_INIT_ARRAYSZ, init_size));
+        }
+        if !self.fini_array.is_empty() {
+            dynamic.push((DT_FINI_ARRAY, arrays + init_size));
+            dynamic.push((DT_FINI_ARRAYSZ, 8 * self.fini_array.len() as u64));
+        }
+        dynamic.push((0, 0));
+        dynamic
+    }
+
+    /// Offsets of .dynsym, .dynstr, .hash, .rela.dyn and .rela.plt in .rodata
+    fn rodata_offsets(&self) -> (usize, usize, usize, usize, usize) {
+        let symbols = 1 + self.imports.len() + self.exports.len();
+        let strsz = 1 + self
+            .imports
+            .iter()
+            .chain(self.exports)
+            .map(|name| name.len() + 1)
+            .sum::<usize>();
+        let dynstr = 0x18 * symbols;
+        let hash = (dynstr + strsz).next_multiple_of(8);
+        let rela = (hash + 4 * (2 + 1 + symbols)).next_multiple_of(8);
+        let jmprel = rela + 0x18 * self.relocations().0.len();
+        (0, dynstr, hash, rela, jmprel)
+    }
+
+    fn build_rodata(&self, data_start: usize) -> Vec<u8> {
+        let ro_start = self.text_pages * PAGE;
+        let (_, _, hash_offset, rela_offset, _) = self.rodata_offsets();
+        let mut dynstr = vec![0];
+        let mut dynsym = vec![0; 0x18];
+        for (i, name) in self.imports.iter().chain(self.exports).enumerate() {
+            push_u32(&mut dynsym, dynstr.len() as u32);
+            dynstr.extend_from_slice(name.as_bytes());
+            dynstr.push(0);
+            // STB_GLOBAL or STB_WEAK, STT_FUNC
+            let bind = if self.weak_imports.contains(name) {
+                2
+            } else {
+                1
+            };
+            dynsym.push((bind << 4) | 2);
+            dynsym.push(0);
+            if i < self.imports.len() {
+                push_u16(&mut dynsym, 0);
+                push_u64(&mut dynsym, 0);
+                push_u64(&mut dynsym, 0);
+            } else {
+                push_u16(&mut dynsym, 1);
+                push_u64(&mut dynsym, self.export_offset(name) as u64);
+                push_u64(&mut dynsym, FUNCTION_SIZE as u64);
+            }
+        }
+        let symbols = dynsym.len() / 0x18;
+        let mut out = dynsym;
+        out.extend_from_slice(&dynstr);
+        out.resize(hash_offset, 0);
+        // one bucket, the chains are not used when loading
+        push_u32(&mut out, 1);
+        push_u32(&mut out, symbols as u32);
+        out.resize(out.len() + 4 * (1 + symbols), 0);
+        out.resize(rela_offset, 0);
+        let (relas, _) = self.relocations();
+        for (offset, r_type, symbol, addend) in &relas {
+            push_rela(&mut out, data_start + offset, *r_type, *symbol, *addend);
+        }
+        for (i, import) in self.imports.iter().enumerate() {
+            let offset = data_start + self.got_plt() + 8 * i;
+            push_rela(
+                &mut out,
+                offset,
+                R_AARCH64_JUMP_SLOT,
+                self.symbol_index(import),
+                0,
+            );
+        }
+        out.resize(out.len().next_multiple_of(0x10), 0);
+        for (tag, value) in self.dynamic(ro_start, data_start, dynstr.len(), 0x18 * relas.len()) {
+            push_u64(&mut out, tag as u64);
+            push_u64(&mut out, value);
+        }
+        out
+    }
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_rela(out: &mut Vec<u8>, offset: usize, r_type: u32, symbol: u64, addend: u64) {
+    push_u64(out, offset as u64);
+    push_u64(out, (symbol << 32) | r_type as u64);
+    push_u64(out, addend);
+}
+
+fn push_phdr(
+    out: &mut Vec<u8>,
+    p_type: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    file_size: usize,
+    mem_size: usize,
+) {
+    push_u32(out, p_type);
+    push_u32(out, flags);
+    push_u64(out, offset);
+    push_u64(out, vaddr);
+    push_u64(out, vaddr);
+    push_u64(out, file_size as u64);
+    push_u64(out, mem_size as u64);
+    push_u64(out, PAGE as u64);
+}
+
+/// A directory in the system temp directory, unique to each test, which is removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "uking-relocate-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Write a file in the directory, creating the parent directories, and return its path
+    pub fn write(&self, name: &str, content: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+}
+
+impl Default for TempDir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,468 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::BufWriter;
+
+use elf::abi::PF_X;
+use serde::Serialize;
+
+use crate::decomp::{to_main_offset, DecompFunction};
+use crate::memory::Memory;
+use crate::module::ModuleType;
+use crate::reloc_report::AppliedRelocation;
+
+/// Max number of failures to print in the summary
+const MAX_LOGGED_FAILURES: usize = 20;
+
+/// Result of the sanity checks on the relocated program
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    /// Number of relocated pointers checked
+    pub pointers: usize,
+    /// Number of vtables checked
+    pub vtables: usize,
+    /// Number of functions checked
+    pub functions: usize,
+    /// Number of functions that start with a stack frame prologue.
+    /// The others are usually leaf functions or thunks
+    pub prologues: usize,
+    pub failures: Vec<VerifyFailure>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VerifyFailure {
+    pub check: VerifyCheck,
+    pub module: ModuleType,
+    /// Offset of what is checked, relative to the module start.
+    /// None if the decomp address is outside of main
+    pub offset: Option<u32>,
+    /// Physical address of what is checked
+    pub address: Option<u64>,
+    /// The relocation symbol, vtable or function checked
+    pub symbol: Option<String>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyCheck {
+    /// A relocated pointer lands inside a mapped region of the right module
+    Pointer,
+    /// A vtable's entries point into the executable segment of the right module
+    Vtable,
+    /// A function starts with a valid instruction in an executable segment
+    Function,
+}
+
+/// Check the relocated program:
+/// - Every relocated pointer lands inside a mapped region (and inside the module
+///   that defines the symbol)
+/// - Every vtable (_ZTV* in data_symbols) points into the executable segment of the
+///   module that defines the virtual functions
+/// - Every function in functions starts with a valid instruction
+///
+/// data_symbols and functions are from the decomp and are in main
+pub fn verify(
+    memory: &Memory,
+    functions: Option<&BTreeMap<String, DecompFunction>>,
+    data_symbols: Option<&[(u64, String)]>,
+) -> VerifyReport {
+    let mut verifier = Verifier {
+        memory,
+        report: VerifyReport::default(),
+    };
+    log::info!("-- [verify] checking relocated pointers...");
+    verifier.check_pointers();
+    if let Some(data_symbols) = data_symbols {
+        log::info!("-- [verify] checking vtables...");
+        verifier.check_vtables(data_symbols);
+    }
+    if let Some(functions) = functions {
+        log::info!("-- [verify] checking functions...");
+        verifier.check_functions(functions);
+    }
+    verifier.report
+}
+
+impl VerifyReport {
+    /// Print the number of checks and the first failures
+    pub fn log_summary(&self) {
+        log::info!(
+            "-- [verify] checked {} pointers, {} vtables, {} functions ({} with a prologue)",
+            self.pointers,
+            self.vtables,
+            self.functions,
+            self.prologues
+        );
+        if self.failures.is_empty() {
+            return;
+        }
+        log::info!("");
+        log::info!("CHECK    MODULE   ADDRESS            MESSAGE");
+        for failure in self.failures.iter().take(MAX_LOGGED_FAILURES) {
+            let check = match failure.check {
+                VerifyCheck::Pointer => "pointer",
+                VerifyCheck::Vtable => "vtable",
+                VerifyCheck::Function => "function",
+            };
+            log::info!(
+                "{check:8} {:8} {:18} {}{}",
+                failure.module.to_string(),
+                failure
+                    .address
+                    .map(|a| format!("0x{a:016x}"))
+                    .unwrap_or_default(),
+                failure
+                    .symbol
+                    .as_ref()
+                    .map(|s| format!("{s}: "))
+                    .unwrap_or_default(),
+                failure.message
+            );
+        }
+        if self.failures.len() > MAX_LOGGED_FAILURES {
+            log::info!("... and {} more", self.failures.len() - MAX_LOGGED_FAILURES);
+        }
+        log::info!("");
+    }
+
+    /// Write the report to path in JSON
+    pub fn write(&self, path: &str) -> anyhow::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+}
+
+struct Verifier<'a> {
+    memory: &'a Memory,
+    report: VerifyReport,
+}
+
+impl Verifier<'_> {
+    fn check_pointers(&mut self) {
+        let start = self.memory.get_program_start();
+        let modules = self.memory.get_modules();
+        for relocation in &self.memory.relocations.applied {
+            // magic symbols (including --define) can be anywhere
+            if relocation.symbol.is_some() && relocation.provider.is_none() {
+                continue;
+            }
+            self.report.pointers += 1;
+            let Some(offset) = self.program_offset(relocation.value) else {
+                self.fail_relocation(relocation, "points outside of the program");
+                continue;
+            };
+            // pointers to the end of a segment are not in a region
+            if self.memory.find_region(offset).is_none()
+                && self.memory.find_region(offset.wrapping_sub(1)).is_none()
+            {
+                self.fail_relocation(relocation, "does not point to a mapped region");
+                continue;
+            }
+            let expected = relocation.provider.unwrap_or(relocation.module);
+            let info = modules.get(expected);
+            if offset < info.start || offset > info.end {
+                self.fail_relocation(
+                    relocation,
+                    &format!(
+                        "points to 0x{:016x}, which is outside of {expected}",
+                        start + offset as u64
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_vtables(&mut self, data_symbols: &[(u64, String)]) {
+        let main = self.memory.get_modules().get(ModuleType::Main);
+        let relocations = self
+            .memory
+            .relocations
+            .applied
+            .iter()
+            .map(|r| ((r.module, r.offset), r))
+            .collect::<BTreeMap<_, _>>();
+        let unresolved = self
+            .memory
+            .relocations
+            .unresolved
+            .iter()
+            .map(|r| ((r.module, r.offset), r.symbol.as_str()))
+            .collect::<BTreeMap<_, _>>();
+        // the vtable ends at the next symbol, which is usually the typeinfo (_ZTI)
+        // or typeinfo name (_ZTS) that are also relocated
+        let addresses = data_symbols
+            .iter()
+            .map(|(address, _)| *address)
+            .collect::<BTreeSet<_>>();
+
+        for (address, name) in data_symbols {
+            if !name.starts_with("_ZTV") {
+                continue;
+            }
+            self.report.vtables += 1;
+            let offset = match to_main_offset(*address) {
+                Ok(offset) if offset < main.end - main.start => offset,
+                _ => {
+                    self.fail_decomp(VerifyCheck::Vtable, *address, name, "is outside of main");
+                    continue;
+                }
+            };
+            if self.is_executable(ModuleType::Main, offset) {
+                self.fail(
+                    VerifyCheck::Vtable,
+                    ModuleType::Main,
+                    offset,
+                    Some(name),
+                    "is in an executable segment",
+                );
+                continue;
+            }
+            // the entries start after the offset-to-top and the typeinfo, and
+            // end at the first value that is not relocated, or the next symbol
+            let end = addresses
+                .range(address + 1..)
+                .next()
+                .and_then(|next| to_main_offset(*next).ok());
+            let mut entry = offset + 0x10;
+            let mut count = 0;
+            while end.is_none_or(|end| entry < end) {
+                let key = (ModuleType::Main, entry);
+                if let Some(symbol) = unresolved.get(&key) {
+                    self.fail(
+                        VerifyCheck::Vtable,
+                        ModuleType::Main,
+                        entry,
+                        Some(name),
+                        &format!("entry {count} references unresolved symbol {symbol}"),
+                    );
+                } else if let Some(relocation) = relocations.get(&key) {
+                    self.check_vtable_entry(name, count, relocation);
+                } else {
+                    break;
+                }
+                entry += 8;
+                count += 1;
+            }
+            if count == 0 {
+                self.fail(
+                    VerifyCheck::Vtable,
+                    ModuleType::Main,
+                    offset,
+                    Some(name),
+                    "the first entry is not relocated",
+                );
+            }
+        }
+    }
+
+    fn check_vtable_entry(&mut self, name: &str, index: usize, relocation: &AppliedRelocation) {
+        let expected = match (&relocation.symbol, relocation.provider) {
+            (None, _) => relocation.module,
+            (Some(_), Some(provider)) => provider,
+            // magic symbols (including --define) can be anywhere
+            (Some(_), None) => return,
+        };
+        let info = self.memory.get_modules().get(expected);
+        let is_valid = self
+            .program_offset(relocation.value)
+            .filter(|offset| info.start <= *offset && *offset < info.end)
+            .is_some_and(|offset| self.is_executable(expected, offset - info.start));
+        if !is_valid {
+            self.fail(
+                VerifyCheck::Vtable,
+                relocation.module,
+                relocation.offset,
+                Some(name),
+                &format!(
+                    "entry {index} points to 0x{:016x}, which is not in the executable segment of {expected}",
+                    relocation.value
+                ),
+            );
+        }
+    }
+
+    fn check_functions(&mut self, functions: &BTreeMap<String, DecompFunction>) {
+        let main = self.memory.get_modules().get(ModuleType::Main);
+        let main_start = main.start;
+        for (name, function) in functions {
+            self.report.functions += 1;
+            let offset = match to_main_offset(function.address) {
+                Ok(offset) if offset < main.end - main.start => offset,
+                _ => {
+                    let address = function.address;
+                    self.fail_decomp(VerifyCheck::Function, address, name, "is outside of main");
+                    continue;
+                }
+            };
+            if offset % 4 != 0 {
+                self.fail(
+                    VerifyCheck::Function,
+                    ModuleType::Main,
+                    offset,
+                    Some(name),
+                    "is not aligned to 4 bytes",
+                );
+                continue;
+            }
+            if !self.is_executable(ModuleType::Main, offset) {
+                self.fail(
+                    VerifyCheck::Function,
+                    ModuleType::Main,
+                    offset,
+                    Some(name),
+                    "is not in an executable segment",
+                );
+                continue;
+            }
+            let Ok(insn) = self.memory.read_u32(main_start + offset) else {
+                continue;
+            };
+            if is_prologue(insn) {
+                self.report.prologues += 1;
+            } else if !is_valid_insn(insn) {
+                self.fail(
+                    VerifyCheck::Function,
+                    ModuleType::Main,
+                    offset,
+                    Some(name),
+                    &format!("starts with 0x{insn:08x}, which is not a valid instruction"),
+                );
+            }
+        }
+    }
+
+    /// Convert a physical address to an offset relative to the program start
+    fn program_offset(&self, address: u64) -> Option<u32> {
+        let offset = address.checked_sub(self.memory.get_program_start())?;
+        let offset = u32::try_from(offset).ok()?;
+        (offset <= self.memory.get_program_size()).then_some(offset)
+    }
+
+    /// Check if offset (relative to the module start) is in an executable region
+    fn is_executable(&self, module: ModuleType, offset: u32) -> bool {
+        let offset = self.memory.get_modules().get(module).start + offset;
+        self.memory
+            .find_region(offset)
+            .is_some_and(|region| region.permissions & PF_X != 0)
+    }
+
+    fn fail_relocation(&mut self, relocation: &AppliedRelocation, message: &str) {
+        let message = format!(
+            "{} {message} (0x{:016x})",
+            relocation.r_type, relocation.value
+        );
+        self.fail(
+            VerifyCheck::Pointer,
+            relocation.module,
+            relocation.offset,
+            relocation.symbol.as_deref(),
+            &message,
+        );
+    }
+
+    fn fail_decomp(&mut self, check: VerifyCheck, address: u64, name: &str, message: &str) {
+        self.report.failures.push(VerifyFailure {
+            check,
+            module: ModuleType::Main,
+            offset: None,
+            address: None,
+            symbol: Some(name.to_string()),
+            message: format!("0x{address:x} {message}"),
+        });
+    }
+
+    fn fail(
+        &mut self,
+        check: VerifyCheck,
+        module: ModuleType,
+        offset: u32,
+        symbol: Option<&str>,
+        message: &str,
+    ) {
+        let info = self.memory.get_modules().get(module);
+        let address = self.memory.get_program_start() + (info.start + offset) as u64;
+        self.report.failures.push(VerifyFailure {
+            check,
+            module,
+            offset: Some(offset),
+            address: Some(address),
+            symbol: symbol.map(|s| s.to_string()),
+            message: message.to_string(),
+        });
+    }
+}
+
+/// Check if the instruction sets up a stack frame, saves registers
+/// or signs the return address, like at the start of most functions
+fn is_prologue(insn: u32) -> bool {
+    // stp Xt, Xt2, [sp, #imm]! (including stp x29, x30)
+    insn & 0xFFC0_03E0 == 0xA980_03E0
+        // stp Dt, Dt2, [sp, #imm]!
+        || insn & 0xFFC0_03E0 == 0x6D80_03E0
+        // sub sp, sp, #imm
+        || insn & 0xFF80_03FF == 0xD100_03FF
+        // str Xt, [sp, #imm]!
+        || insn & 0xFFE0_0FE0 == 0xF800_0FE0
+        // paciasp
+        || insn == 0xD503_233F
+        // bti c
+        || insn == 0xD503_245F
+}
+
+/// Check if the instruction is not in the reserved, unallocated or SVE encoding
+/// groups. Functions without a prologue (leaf functions and thunks) can start with
+/// anything else, so this mostly catches zeroes and data
+fn is_valid_insn(insn: u32) -> bool {
+    let op0 = (insn >> 25) & 0xF;
+    op0 > 0b0011
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prologue() {
+        for insn in [
+            0xA9BF7BFD, // stp x29, x30, [sp, #-0x10]!
+            0xA9BA6FFC, // stp x28, x27, [sp, #-0x60]!
+            0x6DBE27E9, // stp d9, d8, [sp, #-0x20]!
+            0xD10103FF, // sub sp, sp, #0x40
+            0xF81E0FF3, // str x19, [sp, #-0x20]!
+            0xD503233F, // paciasp
+            0xD503245F, // bti c
+        ] {
+            assert!(is_prologue(insn), "0x{insn:08x}");
+        }
+        for insn in [
+            0xA8C17BFD, // ldp x29, x30, [sp], #0x10
+            0xA9017BFD, // stp x29, x30, [sp, #0x10]
+            0xD10103E0, // sub x0, sp, #0x40
+            0xD65F03C0, // ret
+            0xD503201F, // nop
+            0x00000000,
+        ] {
+            assert!(!is_prologue(insn), "0x{insn:08x}");
+        }
+    }
+
+    #[test]
+    fn valid_insn() {
+        for insn in [
+            0xD65F03C0, // ret
+            0xD2800000, // mov x0, #0
+            0x14000000, // b .
+            0xF9400000, // ldr x0, [x0]
+        ] {
+            assert!(is_valid_insn(insn), "0x{insn:08x}");
+        }
+        for insn in [
+            0x00000000, // udf #0
+            0x00000001, 0x04000000, // SVE
+            0x06000000, // unallocated
+        ] {
+            assert!(!is_valid_insn(insn), "0x{insn:08x}");
+        }
+    }
+}